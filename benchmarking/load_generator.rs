@@ -16,7 +16,7 @@ async fn main() {
         let tx = tx.clone();
         tokio::spawn(async move {
             let ws_connection = connect_async(url).await.unwrap();
-            let (mut read, mut write) = ws_connection.split();
+            let (mut read, write) = ws_connection.split();
 
             let payload = vec![b'a'; message_size];
             let start = Instant::now();
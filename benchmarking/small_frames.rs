@@ -0,0 +1,101 @@
+// Reads 10k small masked frames from a client, counting the heap allocations made by the
+// server while receiving them, which for small frames are mostly the payload buffers
+use futures::StreamExt;
+use socket_flow::handshake::accept_async;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+const FRAME_COUNT: usize = 10_000;
+const PAYLOAD_SIZE: usize = 32;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// Single frame binary messages, masked like a client would send them
+fn frames(count: usize, fragmented: bool) -> Vec<u8> {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frames = Vec::new();
+    for i in 0..count {
+        let first_byte = match (fragmented, i % 4) {
+            (false, _) => 0x82,
+            (true, 0) => 0x02,
+            (true, 3) => 0x80,
+            (true, _) => 0x00,
+        };
+        frames.extend_from_slice(&[first_byte, 0x80 | PAYLOAD_SIZE as u8]);
+        frames.extend_from_slice(&mask);
+        frames.extend((0..PAYLOAD_SIZE).map(|j| b'a' ^ mask[j % 4]));
+    }
+    frames
+}
+
+async fn receive(fragmented: bool) {
+    let (mut client, server) = duplex(1024 * 1024);
+    let request = "GET / HTTP/1.1\r\n\
+        Host: 127.0.0.1\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n";
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut connection = accept_async(server).await.unwrap();
+    let mut response = [0u8; 1024];
+    let _ = client.read(&mut response).await.unwrap();
+
+    let frames = frames(FRAME_COUNT, fragmented);
+    let messages = if fragmented {
+        FRAME_COUNT / 4
+    } else {
+        FRAME_COUNT
+    };
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    let writer = tokio::spawn(async move {
+        client.write_all(&frames).await.unwrap();
+        client
+    });
+    for _ in 0..messages {
+        connection.next().await.unwrap().unwrap();
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated_bytes;
+    drop(writer.await.unwrap());
+
+    println!(
+        "{} frames{}: {} messages in {:?}, {} allocations, {} KiB allocated",
+        FRAME_COUNT,
+        if fragmented { " (4 per message)" } else { "" },
+        messages,
+        elapsed,
+        allocations,
+        allocated_bytes / 1024,
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    receive(false).await;
+    receive(true).await;
+}
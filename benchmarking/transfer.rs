@@ -0,0 +1,86 @@
+// Transfers 1 GB from a server to a client over a local connection, counting the heap
+// allocations made by the whole process, for comparing a payload copied for each message,
+// against a shared `Bytes` payload, which is sent without copying it
+use bytes::Bytes;
+use futures::StreamExt;
+use socket_flow::handshake::{accept_async, connect_async};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tokio::net::TcpListener;
+
+const TOTAL_SIZE: usize = 1024 * 1024 * 1024;
+const MESSAGE_SIZE: usize = 1024 * 1024;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+async fn transfer(shared: bool) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let payload = vec![b'a'; MESSAGE_SIZE];
+    let shared_payload = Bytes::from(payload.clone());
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut connection = accept_async(stream).await.unwrap();
+        for _ in 0..TOTAL_SIZE / MESSAGE_SIZE {
+            if shared {
+                connection
+                    .send_as_binary(shared_payload.clone())
+                    .await
+                    .unwrap();
+            } else {
+                connection.send_as_binary(payload.clone()).await.unwrap();
+            }
+        }
+        connection.close_connection().await.unwrap();
+    });
+
+    let mut connection = connect_async(&format!("ws://127.0.0.1:{}", port))
+        .await
+        .unwrap();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    let mut received = 0;
+    while let Some(Ok(message)) = connection.next().await {
+        if let Some(data) = message.as_binary() {
+            received += data.len();
+        }
+    }
+    server.await.unwrap();
+
+    println!(
+        "{}: received {} MiB in {:?}, {} allocations, {} MiB allocated",
+        if shared { "Bytes" } else { "Vec<u8>" },
+        received / (1024 * 1024),
+        start.elapsed(),
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        (ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated_bytes) / (1024 * 1024),
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    transfer(false).await;
+    transfer(true).await;
+}
@@ -8,17 +8,22 @@ use socket_flow::handshake::{connect_async_with_config};
 const AGENT: &str = "socket-flow";
 
 fn get_config() -> ClientConfig {
-    let mut websocket_config = WebSocketConfig::default();
-    websocket_config.extensions = Some(Extensions {
-        permessage_deflate: true,
-        client_no_context_takeover: Some(true),
-        server_no_context_takeover: Some(true),
-        client_max_window_bits: None,
-        server_max_window_bits: None,
-    });
-    let mut client_config = ClientConfig::default();
-    client_config.web_socket_config = websocket_config;
-    client_config
+    let websocket_config = WebSocketConfig {
+        extensions: Some(Extensions {
+            permessage_deflate: true,
+            client_no_context_takeover: Some(true),
+            server_no_context_takeover: Some(true),
+            client_max_window_bits: None,
+            server_max_window_bits: None,
+        }),
+        // The test suite expects the checks clients relax by default
+        strict: Some(true),
+        ..Default::default()
+    };
+    ClientConfig {
+        web_socket_config: websocket_config,
+        ..Default::default()
+    }
 }
 
 async fn run_test(case: u32) -> Result<(), Error> {
@@ -58,7 +63,7 @@ async fn get_case_count() -> Result<u32, Error> {
     let msg = connection.next().await.unwrap()?;
     connection.close_connection().await?;
 
-    let text_message = msg.as_text()?;
+    let text_message = msg.as_text().expect("case count should be a text message");
     Ok(text_message
         .parse::<u32>()
         .expect("couldn't convert test case to number"))
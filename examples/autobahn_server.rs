@@ -8,14 +8,16 @@ use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream};
 
 async fn handle_connection(_: SocketAddr, stream: TcpStream) {
-    let mut config = WebSocketConfig::default();
-    config.extensions = Some(Extensions {
-        permessage_deflate: true,
-        client_no_context_takeover: Some(true),
-        server_no_context_takeover: Some(true),
-        client_max_window_bits: None,
-        server_max_window_bits: None,
-    });
+    let config = WebSocketConfig {
+        extensions: Some(Extensions {
+            permessage_deflate: true,
+            client_no_context_takeover: Some(true),
+            server_no_context_takeover: Some(true),
+            client_max_window_bits: None,
+            server_max_window_bits: None,
+        }),
+        ..Default::default()
+    };
 
     match accept_async_with_config(SocketFlowStream::Plain(stream), Some(config)).await {
         Ok(mut ws_connection) => {
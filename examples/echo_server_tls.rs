@@ -38,12 +38,9 @@ fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
 }
 
 fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
-    Ok(private_key(&mut BufReader::new(File::open(path)?))
+    private_key(&mut BufReader::new(File::open(path)?))
         .unwrap()
-        .ok_or(io::Error::new(
-            ErrorKind::Other,
-            "no private key found".to_string(),
-        ))?)
+        .ok_or(io::Error::other("no private key found".to_string()))
 }
 
 #[tokio::main]
@@ -0,0 +1,50 @@
+use log::*;
+use serde::{Deserialize, Serialize};
+use socket_flow::handshake::{accept_async, connect_async};
+use tokio::net::TcpListener;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Greeting {
+    name: String,
+    times: u32,
+}
+
+// Replies every greeting, incrementing its counter
+async fn run_server(listener: TcpListener) -> Result<(), Box<dyn std::error::Error>> {
+    let (stream, _) = listener.accept().await?;
+    let mut ws_connection = accept_async(stream).await?;
+    while let Some(result) = ws_connection.recv_json::<Greeting>().await {
+        let mut greeting = result?;
+        greeting.times += 1;
+        ws_connection.send_json(&greeting).await?;
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = format!("ws://{}", listener.local_addr()?);
+    let server = tokio::spawn(async move {
+        if let Err(err) = run_server(listener).await {
+            error!("Server error: {}", err);
+        }
+    });
+
+    let mut ws_connection = connect_async(&addr).await?;
+    let greeting = Greeting {
+        name: String::from("socket-flow"),
+        times: 0,
+    };
+    ws_connection.send_json(&greeting).await?;
+
+    if let Some(reply) = ws_connection.recv_json::<Greeting>().await {
+        info!("Received reply: {:?}", reply?);
+    }
+
+    ws_connection.close_connection().await?;
+    server.await?;
+    Ok(())
+}
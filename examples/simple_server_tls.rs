@@ -10,7 +10,7 @@ use socket_flow::split::WSWriter;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io;
-use std::io::{BufReader, ErrorKind};
+use std::io::BufReader;
 use std::path::Path;
 use std::sync::Arc;
 
@@ -19,17 +19,16 @@ fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
 }
 
 fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
-    Ok(private_key(&mut BufReader::new(File::open(path)?))
+    private_key(&mut BufReader::new(File::open(path)?))
         .unwrap()
-        .ok_or(io::Error::new(
-            ErrorKind::Other,
-            "no private key found".to_string(),
-        ))?)
+        .ok_or(io::Error::other("no private key found".to_string()))
 }
 
 async fn run_server(port: u16, tls_config: Arc<RustlsConfig>) {
-    let mut server_config = ServerConfig::default();
-    server_config.tls_config = Option::from(tls_config);
+    let server_config = ServerConfig {
+        tls_config: Option::from(tls_config),
+        ..Default::default()
+    };
 
     match start_server_with_config(8080, Some(server_config)).await {
         Ok(mut event_receiver) => {
@@ -0,0 +1,55 @@
+use log::*;
+use socket_flow::handshake::connect_async;
+use std::env;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// Reads from the file until the chunk is full, or the file ends
+async fn read_chunk(file: &mut File) -> std::io::Result<Vec<u8>> {
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut filled = 0;
+    while filled < CHUNK_SIZE {
+        let read = file.read(&mut chunk[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    chunk.truncate(filled);
+    Ok(chunk)
+}
+
+// Streams the file in 16 KB chunks, as a single binary message,
+// without loading the entire file in memory
+async fn stream_file(addr: &str, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ws_connection = connect_async(addr).await?;
+    let mut file = File::open(path).await?;
+
+    let mut fragmented = ws_connection.fragmented_binary();
+    let mut chunk = read_chunk(&mut file).await?;
+    loop {
+        // Reading one chunk ahead, so we know which one is the last fragment
+        let next_chunk = read_chunk(&mut file).await?;
+        if next_chunk.is_empty() {
+            fragmented.finish(chunk).await?;
+            break;
+        }
+        fragmented.send(chunk).await?;
+        chunk = next_chunk;
+    }
+
+    info!("File {} sent", path);
+    ws_connection.close_connection().await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let path = env::args().nth(1).unwrap_or(String::from("Cargo.toml"));
+    if let Err(err) = stream_file("ws://127.0.0.1:9002", &path).await {
+        error!("Error when streaming file: {}", err);
+    }
+}
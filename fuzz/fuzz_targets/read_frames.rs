@@ -0,0 +1,80 @@
+#![no_main]
+// Feeds arbitrary bytes into a server connection, as the frames sent by the client after the
+// handshake. The connection must either deliver messages, or fail with an error, without
+// panicking, hanging, or allocating more than its frame and message size limits allow.
+// The first byte selects whether permessage-deflate is negotiated, and with which context
+// takeover, so decompressing payloads is also covered
+use futures::StreamExt;
+use libfuzzer_sys::fuzz_target;
+use socket_flow::config::WebSocketConfig;
+use socket_flow::extensions::Extensions;
+use socket_flow::handshake::accept_async_with_config;
+use std::sync::OnceLock;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+
+// Kept small, so an input can't make the connection allocate much, and libFuzzer's
+// memory limit catches unbounded allocations
+const MAX_SIZE: usize = 1024 * 1024;
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| Runtime::new().unwrap())
+}
+
+fn config(selector: u8) -> WebSocketConfig {
+    let extensions = (selector & 0b1 != 0).then(|| Extensions {
+        permessage_deflate: true,
+        client_no_context_takeover: Some(selector & 0b10 != 0),
+        server_no_context_takeover: Some(selector & 0b100 != 0),
+        client_max_window_bits: None,
+        server_max_window_bits: None,
+    });
+    WebSocketConfig {
+        max_frame_size: Some(MAX_SIZE),
+        max_message_size: Some(MAX_SIZE),
+        extensions,
+        ..Default::default()
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, frames)) = data.split_first() else {
+        return;
+    };
+    let frames = frames.to_vec();
+
+    runtime().block_on(async move {
+        let (client, server) = duplex(64 * 1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+
+        let request = "GET / HTTP/1.1\r\n\
+            Host: 127.0.0.1\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Extensions: permessage-deflate\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n";
+        let writer = tokio::spawn(async move {
+            let _ = client_write.write_all(request.as_bytes()).await;
+            let _ = client_write.write_all(&frames).await;
+            let _ = client_write.shutdown().await;
+        });
+        // Whatever the server writes, like the handshake response, pongs, and close frames,
+        // is drained, so it never blocks on a full stream
+        let drain = tokio::spawn(async move {
+            let mut buffer = vec![0u8; 8 * 1024];
+            while matches!(client_read.read(&mut buffer).await, Ok(n) if n > 0) {}
+        });
+
+        let mut connection = accept_async_with_config(server, Some(config(selector)))
+            .await
+            .unwrap();
+        // The stream finishes after the first error, or once the input is consumed
+        while connection.next().await.is_some() {}
+        drop(connection);
+
+        writer.await.unwrap();
+        drain.await.unwrap();
+    });
+});
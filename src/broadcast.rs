@@ -0,0 +1,92 @@
+use crate::error::Error;
+use crate::frame::Frame;
+use crate::message::Message;
+use crate::split::WSWriter;
+use futures::future::join_all;
+
+// The frame and message size limits of a connection, which are the only settings
+// its uncompressed frames depend on
+type FrameLimits = (usize, Option<usize>);
+
+/// Sends the same message to many connections, like the members of a chat room,
+/// or the subscribers of a topic.
+///
+/// It holds the writers of the connections, obtained by splitting them, and removes the ones
+/// whose connection was closed, or failed while broadcasting.
+/// The frames of a message are encoded once, and shared by every connection that doesn't
+/// compress it, since frames sent by servers aren't masked. Compressed messages are encoded
+/// for each connection, since they depend on its compression context.
+#[derive(Clone, Default)]
+pub struct Broadcaster {
+    senders: Vec<WSWriter>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the writer of a connection, which will receive the following broadcasts
+    pub fn add(&mut self, sender: WSWriter) {
+        self.senders.push(sender);
+    }
+
+    /// Number of connections receiving the broadcasts
+    pub fn len(&self) -> usize {
+        self.senders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    /// Sends the message to every connection, concurrently, returning the number of
+    /// connections it was sent to. Connections that are closed, or fail to send it, are removed.
+    /// If the message can't be sent, like a ping with a payload above 125 bytes,
+    /// the error is returned, and it isn't sent to any connection
+    pub async fn broadcast(&mut self, message: Message) -> Result<usize, Error> {
+        self.senders.retain(|sender| !sender.is_closed());
+
+        let mut encoded: Vec<(FrameLimits, Vec<Frame>)> = Vec::new();
+        let mut frames = Vec::with_capacity(self.senders.len());
+        for sender in &self.senders {
+            if sender.compresses(&message) {
+                frames.push(None);
+                continue;
+            }
+            let limits = (
+                sender.write_frame_size(),
+                sender.web_socket_config.max_message_size,
+            );
+            let shared = match encoded
+                .iter()
+                .find(|(encoded_limits, _)| *encoded_limits == limits)
+            {
+                Some((_, shared)) => shared.clone(),
+                None => {
+                    let shared = sender.convert_to_frames(None, message.clone())?;
+                    encoded.push((limits, shared.clone()));
+                    shared
+                }
+            };
+            frames.push(Some(shared));
+        }
+
+        let message = &message;
+        let sends = self
+            .senders
+            .iter()
+            .zip(frames)
+            .map(|(sender, frames)| async move {
+                match frames {
+                    Some(frames) => sender.write_frames(frames).await,
+                    None => sender.send_message(message.clone()).await,
+                }
+            });
+        let mut results = join_all(sends).await.into_iter();
+
+        self.senders
+            .retain(|_| results.next().is_some_and(|result| result.is_ok()));
+        Ok(self.senders.len())
+    }
+}
@@ -1,9 +1,16 @@
 use crate::error::Error;
-use crate::message::Message;
-use crate::split::{WSReader, WSWriter};
-use futures::Stream;
+use crate::extensions::NegotiatedExtensions;
+use crate::message::{CloseCode, CloseFrame, Message};
+use crate::split::{FragmentedWriter, TrySendError, WSReader, WSWriter};
+use crate::stats::ConnectionStats;
+use crate::stream::SocketAddrs;
+use crate::tunnel::WSTunnel;
+use bytes::Bytes;
+use futures::{Sink, Stream};
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 /// WSConnection represents the final connection of a client/server, after all the steps
 /// of establishing a connection have been properly met.
@@ -18,6 +25,12 @@ pub struct WSConnection {
     /// Implements futures::Stream,
     /// so the end-user can process all the incoming messages, using .next() method
     reader: WSReader,
+    /// The subprotocol agreed between client and server during the handshake, if any
+    protocol: Option<String>,
+    /// Addresses of the underlying TCP connection
+    addrs: SocketAddrs,
+    /// The extensions agreed between client and server during the handshake
+    extensions: NegotiatedExtensions,
 }
 
 // WSConnection has the reader attribute, which is already a ReceiverStream
@@ -37,9 +50,94 @@ impl Stream for WSConnection {
     }
 }
 
+// Delegating to the writer, so the connection can be used with the SinkExt combinators.
+// Since WSConnection has an inherent send method, for sending data as text,
+// SinkExt::send needs to be called explicitly, like SinkExt::send(&mut connection, message)
+impl Sink<Message> for WSConnection {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().writer).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Pin::new(&mut self.get_mut().writer).start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().writer).poll_close(cx)
+    }
+}
+
 impl WSConnection {
-    pub fn new(writer: WSWriter, reader: WSReader) -> Self {
-        Self { writer, reader }
+    pub fn new(writer: WSWriter, reader: WSReader, protocol: Option<String>) -> Self {
+        Self {
+            writer,
+            reader,
+            protocol,
+            addrs: SocketAddrs::default(),
+            extensions: NegotiatedExtensions::default(),
+        }
+    }
+
+    pub(crate) fn with_extensions(mut self, extensions: NegotiatedExtensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    pub(crate) fn with_addrs(mut self, addrs: SocketAddrs) -> Self {
+        self.addrs = addrs;
+        self
+    }
+
+    /// Returns the address of the remote end of the underlying TCP connection.
+    /// It's None for streams that aren't TCP based, like an in-memory duplex stream
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.addrs.peer
+    }
+
+    /// Returns the local address of the underlying TCP connection.
+    /// It's None for streams that aren't TCP based, like an in-memory duplex stream
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.addrs.local
+    }
+
+    /// Returns the extensions negotiated during the handshake, like if messages
+    /// are compressed with permessage-deflate, and its parameters
+    pub fn extensions(&self) -> &NegotiatedExtensions {
+        &self.extensions
+    }
+
+    /// Returns the subprotocol negotiated during the handshake, through
+    /// the `Sec-WebSocket-Protocol` header, if any
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Returns true once the connection is closed, either because a close frame was sent or
+    /// received, or because the connection failed.
+    /// Afterward, sending or closing returns `Error::AlreadyClosed`
+    pub fn is_closed(&self) -> bool {
+        self.writer.is_closed()
+    }
+
+    /// Returns why the connection was closed, once the stream has finished, check `CloseFrame`
+    pub fn close_reason(&self) -> Option<CloseFrame> {
+        self.reader.close_reason()
+    }
+
+    /// Returns a snapshot of the messages and bytes sent and received over the connection
+    pub fn stats(&self) -> ConnectionStats {
+        self.writer.stats()
+    }
+
+    /// Returns true while messages can still be sent over the connection
+    pub fn is_open(&self) -> bool {
+        !self.is_closed()
     }
 
     /// This function will split the connection into the `WSReader`, which is a stream of messages
@@ -49,27 +147,61 @@ impl WSConnection {
         (self.reader, self.writer)
     }
 
+    /// Turns the connection into a byte stream, implementing `AsyncRead` and `AsyncWrite`,
+    /// for tunneling other protocols over binary messages, check the `tunnel` module
+    pub fn into_io(self) -> WSTunnel {
+        WSTunnel::new(self.reader, self.writer)
+    }
+
     /// This function will be used for closing the connection between two instances, mainly it will
     /// be used by a client,
-    /// to request disconnection with a server.It first sends a close frame
-    /// through the socket, and waits until it receives the confirmation in a channel
-    /// executing it inside a timeout, to avoid a long waiting time
+    /// to request disconnection with a server. It sends a close frame with a Normal status code,
+    /// check `close` for more details
     pub async fn close_connection(&mut self) -> Result<(), Error> {
         self.writer.close_connection().await
     }
 
-    /// Send a general message, which is a good option for echoing messages
+    /// Gracefully shuts down the connection, writing the messages already submitted before
+    /// performing the close handshake with a GoingAway status code, check `WSWriter::shutdown`
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        self.writer.shutdown().await
+    }
+
+    /// Performs the close handshake, sending a close frame with the given status code and reason.
+    /// It waits until the peer replies with its own close frame, within a timeout,
+    /// and shuts down the write half of the connection afterward
+    pub async fn close(&mut self, code: CloseCode, reason: Option<String>) -> Result<(), Error> {
+        self.writer.close(code, reason).await
+    }
+
+    /// Send a general message, which is a good option for echoing messages.
+    /// For sending from multiple tasks, the connection can be split, and the writer cloned,
+    /// since each message is written entirely before the next one
     pub async fn send_message(&mut self, message: Message) -> Result<(), Error> {
         self.writer.send_message(message).await
     }
 
+    /// Sends a message without compressing it, even if permessage-deflate was negotiated,
+    /// which is useful for payloads that are already compressed
+    pub async fn send_uncompressed(&mut self, message: Message) -> Result<(), Error> {
+        self.writer.send_uncompressed(message).await
+    }
+
+    /// Sends all the messages in order, as a single batch, see `WSWriter::send_all`
+    pub async fn send_all(
+        &mut self,
+        messages: impl IntoIterator<Item = Message>,
+    ) -> Result<(), Error> {
+        self.writer.send_all(messages).await
+    }
+
     /// Send generic data, by default it considers OpCode Text
     pub async fn send(&mut self, data: Vec<u8>) -> Result<(), Error> {
         self.writer.send(data).await
     }
 
-    /// Send a message as Binary Opcode
-    pub async fn send_as_binary(&mut self, data: Vec<u8>) -> Result<(), Error> {
+    /// Send a message as Binary Opcode, from a `Vec<u8>` or `Bytes`, without copying it
+    pub async fn send_as_binary(&mut self, data: impl Into<Bytes>) -> Result<(), Error> {
         self.writer.send_as_binary(data).await
     }
 
@@ -78,11 +210,68 @@ impl WSConnection {
         self.writer.send_as_text(data).await
     }
 
+    /// Serializes the value as JSON, sending it as a text message
+    #[cfg(feature = "serde")]
+    pub async fn send_json<T: serde::Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.writer.send_json(value).await
+    }
+
+    /// Waits up to the given duration for the next message, returning ReadTimeout if none
+    /// arrives in time, and None when the connection is closed
+    pub async fn recv_timeout(&mut self, duration: Duration) -> Result<Option<Message>, Error> {
+        self.reader.recv_timeout(duration).await
+    }
+
+    /// Receives the next text or binary message, deserializing it from JSON.
+    /// Control messages are skipped, and None is returned when the connection is closed
+    #[cfg(feature = "serde")]
+    pub async fn recv_json<T: serde::de::DeserializeOwned>(&mut self) -> Option<Result<T, Error>> {
+        crate::json::next_json(self).await
+    }
+
     /// Sends a Ping OpCode to client/server
     pub async fn send_ping(&mut self) -> Result<(), Error> {
         self.writer.send_ping().await
     }
 
+    /// Sends a ping with the given payload, check `WSWriter::ping`
+    pub async fn ping(&mut self, payload: impl Into<Vec<u8>>) -> Result<(), Error> {
+        self.writer.ping(payload).await
+    }
+
+    /// Sends a message without waiting for the writer, check `WSWriter::try_send`
+    pub fn try_send(&mut self, message: Message) -> Result<(), TrySendError<Message>> {
+        self.writer.try_send(message)
+    }
+
+    /// Sends a ping and waits for its pong, returning the round-trip time,
+    /// check `WSWriter::send_ping_and_wait_pong`.
+    /// Since the stream isn't consumed meanwhile, at most `channel_capacity` messages are
+    /// buffered before the pong, so the connection can be split for reading them concurrently
+    pub async fn send_ping_and_wait_pong(&mut self, wait: Duration) -> Result<Duration, Error> {
+        self.writer.send_ping_and_wait_pong(wait).await
+    }
+
+    /// Sends an unsolicited pong with the given payload, check `WSWriter::pong`
+    pub async fn pong(&mut self, payload: impl Into<Vec<u8>>) -> Result<(), Error> {
+        self.writer.pong(payload).await
+    }
+
+    /// Starts a binary message, that will be sent as multiple fragments, which is useful for
+    /// streaming large payloads, like files, without buffering them.
+    /// Each fragment is sent with `FragmentedWriter::send`, and the last one with `FragmentedWriter::finish`
+    pub fn fragmented_binary(&mut self) -> FragmentedWriter<'_> {
+        self.writer.fragmented_binary()
+    }
+
+    /// Starts a text message, that will be sent as multiple fragments
+    pub fn fragmented_text(&mut self) -> FragmentedWriter<'_> {
+        self.writer.fragmented_text()
+    }
+
     /// Send data fragmented, where fragment_size should be a value calculated in powers of 2
     /// The payload would be divided into that size, still considering connection configurations
     /// like max_frame_size
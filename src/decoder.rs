@@ -1,3 +1,4 @@
+use crate::error::Error;
 use bytes::BytesMut;
 use flate2::{Decompress, FlushDecompress, Status};
 fn calculate_buffer_size(payload_size: usize) -> usize {
@@ -20,7 +21,9 @@ const DEFLATE_TRAILER: [u8; 4] = [0, 0, 255, 255];
 impl Decoder {
     pub fn new(reset_context: bool, window_bits: Option<u8>) -> Self {
         let decompressor = if let Some(window_bits) = window_bits {
-            Decompress::new_with_window_bits(false, window_bits)
+            // zlib doesn't support 8-bit raw deflate windows, but a larger window
+            // decompresses any data compressed with a smaller one
+            Decompress::new_with_window_bits(false, window_bits.max(9))
         } else {
             Decompress::new(false)
         };
@@ -30,7 +33,14 @@ impl Decoder {
         }
     }
 
-    pub fn decompress(&mut self, payload: &mut BytesMut) -> Result<Vec<u8>, std::io::Error> {
+    // Decompresses the payload of a message, failing with MaxMessageSize as soon as the
+    // decompressed data exceeds max_size, so a small payload can't be inflated into a huge
+    // allocation, before its size is checked
+    pub fn decompress(
+        &mut self,
+        payload: &mut BytesMut,
+        max_size: usize,
+    ) -> Result<Vec<u8>, Error> {
         payload.extend_from_slice(&DEFLATE_TRAILER);
         // adjust the buffer size, depending on the payload,
         // for balancing between CPU vs. Memory usage
@@ -48,33 +58,46 @@ impl Decoder {
 
         let before_in = self.decompressor.total_in();
 
-        // Here on the while loop, we need to use decompressor.total_in() method, because
+        // Here on the loop, we need to use decompressor.total_in() method, because
         // when we don't need to reset the context between decompression processes,
         // the decompressor will keep the number of bytes decompressed, also the client
         // responsible for compressing the payload, which is also keeping the context, will send
         // smaller payloads, hopping that the receiver also is keeping the context
         // That is why the handshake part is really important, to ensure we don't have a
         // misalignment.
-        while self.decompressor.total_in() - before_in < payload.as_ref().len() as u64 {
-            let i = (self.decompressor.total_in() - before_in) as usize;
-            let input = &payload[i..];
+        loop {
+            let total_in = self.decompressor.total_in();
+            let total_out = self.decompressor.total_out();
+            let input = &payload[(total_in - before_in) as usize..];
 
             // TODO - We are using decompress_vec, perhaps only decompress method should be
             // more performant, the only issue with that,
             // is that you need to manage the buffer manually
-            match self
+            let status = self
                 .decompressor
-                .decompress_vec(input, &mut buffer, FlushDecompress::Sync)?
+                .decompress_vec(input, &mut buffer, FlushDecompress::Sync)
+                .map_err(|_| Error::InvalidCompressedPayload)?;
+            let buffer_filled = buffer.len() == buffer.capacity();
+            decompressed_data.extend_from_slice(buffer.as_ref());
+            buffer.clear();
+            if decompressed_data.len() > max_size {
+                return Err(Error::MaxMessageSize);
+            }
+
+            let input_consumed = self.decompressor.total_in() - before_in == payload.len() as u64;
+            // A filled buffer means there may be more output pending, even if the input is
+            // entirely consumed
+            if status == Status::StreamEnd || (input_consumed && !buffer_filled) {
+                break;
+            }
+            // A truncated or corrupted payload may leave the decompressor without making
+            // any progress, which would loop forever
+            if self.decompressor.total_in() == total_in
+                && self.decompressor.total_out() == total_out
             {
-                Status::Ok => {
-                    decompressed_data.extend_from_slice(buffer.as_ref());
-                    buffer.clear();
-                }
-                Status::StreamEnd => break,
-                _ => {}
+                return Err(Error::InvalidCompressedPayload);
             }
         }
-        decompressed_data.truncate(decompressed_data.len());
 
         Ok(decompressed_data.to_vec())
     }
@@ -9,7 +9,14 @@ pub struct Encoder {
 impl Encoder {
     pub fn new(reset_context: bool, window_bits: Option<u8>) -> Self {
         let compressor = if let Some(window_bits) = window_bits {
-            Compress::new_with_window_bits(Compression::default(), false, window_bits)
+            // zlib doesn't support 8-bit raw deflate windows, and compressing with a
+            // 9-bit window could reference data the peer no longer has. Therefore, for 8 bits,
+            // data is only stored in deflate blocks, which never references previous data
+            if window_bits < 9 {
+                Compress::new_with_window_bits(Compression::none(), false, 9)
+            } else {
+                Compress::new_with_window_bits(Compression::default(), false, window_bits)
+            }
         } else {
             Compress::new(Compression::default(), false)
         };
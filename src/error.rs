@@ -10,7 +10,7 @@ use url::ParseError;
 #[derive(Error, Debug)]
 pub enum Error {
     // Sender / Receiver Errors
-    #[error("{source}")]
+    #[error("Failed to send through the channel: {source}")]
     SendError {
         #[from]
         source: SendError<Frame>,
@@ -19,8 +19,29 @@ pub enum Error {
     #[error("channel communication error")]
     CommunicationError,
 
+    #[error("Pong wasn't received within the timeout")]
+    PongTimeout,
+
+    #[error("Connection closed by the peer, without a close frame")]
+    ConnectionClosed,
+
+    #[error("Connection reset by the peer")]
+    ConnectionReset,
+
+    #[error("Connection is already closed")]
+    AlreadyClosed,
+
+    #[error("No message was received within the read timeout")]
+    ReadTimeout,
+
+    #[error("A frame couldn't be written within the write timeout")]
+    WriteTimeout,
+
+    #[error("Sending a batch failed after {written} messages were written: {source}")]
+    SendAllFailed { written: usize, source: Box<Error> },
+
     // General Errors
-    #[error("{source}")]
+    #[error("Operation timed out: {source}")]
     Timeout {
         #[from]
         source: Elapsed,
@@ -32,7 +53,7 @@ pub enum Error {
         source: io::Error,
     },
 
-    #[error("{source}")]
+    #[error("Invalid UTF-8 data: {source}")]
     FromUtf8Error {
         #[from]
         source: FromUtf8Error,
@@ -54,11 +75,68 @@ pub enum Error {
     #[error("Couldn't find Sec-WebSocket-Key header in the request")]
     NoSecWebsocketKey,
 
-    #[error("Server didn't upgrade the connection")]
-    NoUpgrade,
+    #[error("Sec-WebSocket-Key header isn't the base64 encoding of 16 bytes")]
+    InvalidSecWebsocketKey,
+
+    #[error("Unsupported Sec-WebSocket-Version, only version 13 is supported")]
+    UnsupportedWebsocketVersion,
+
+    // Both carry the status line and headers of the server response, truncated to 1 KiB,
+    // for diagnosing interop issues
+    #[error("Server didn't upgrade the connection, responding with: {response}")]
+    NoUpgrade { response: String },
+
+    #[error("Server didn't send a valid Sec-WebSocket-Accept key, responding with: {response}")]
+    InvalidAcceptKey { response: String },
+
+    #[error("Server response is missing the Upgrade: websocket or Connection: Upgrade headers, responding with: {response}")]
+    InvalidUpgradeResponse { response: String },
+
+    // Per RFC 7692, the client must fail the connection if the server accepts an extension,
+    // or a parameter, that wasn't offered
+    #[error("Server accepted extensions that weren't offered: {extensions}")]
+    InvalidExtensionResponse { extensions: String },
+
+    // Returned when redirects aren't followed, which is the default, otherwise the
+    // connection is retried against the location
+    #[error("Server redirected the handshake to {location}, responding with: {response}")]
+    Redirect { location: String, response: String },
+
+    #[error("Handshake exceeded the maximum number of redirects, last to {location}")]
+    TooManyRedirects { location: String },
+
+    #[error("Handshake was redirected back to {location}, which was already visited")]
+    RedirectLoop { location: String },
+
+    #[error("Handshake was redirected from wss to an insecure location: {location}")]
+    InsecureRedirect { location: String },
+
+    #[error("HTTP handshake exceeded the maximum allowed size")]
+    HandshakeTooLarge,
+
+    #[error("HTTP handshake exceeded the maximum allowed number of headers")]
+    TooManyHeaders,
 
-    #[error("Sever didn't send a valid Sec-WebSocket-Accept key")]
-    InvalidAcceptKey,
+    #[error("Origin isn't allowed to connect")]
+    OriginNotAllowed,
+
+    #[error("Handshake request rejected: {0}")]
+    HandshakeRejected(String),
+
+    #[error("Server reached the maximum number of concurrent connections")]
+    TooManyConnections,
+
+    #[error("Handshake wasn't completed within the handshake timeout")]
+    HandshakeTimeout,
+
+    #[error("TCP connection wasn't established within the connect timeout")]
+    ConnectTimeout,
+
+    #[error("Proxy failed to establish the tunnel, responding with status {0}")]
+    ProxyConnectFailed(u16),
+
+    #[error("Server selected a subprotocol that wasn't offered by the client")]
+    InvalidSubprotocol,
 
     // Framing Errors
     #[error("RSV not zero")]
@@ -67,18 +145,39 @@ pub enum Error {
     #[error("Control frames must not be fragmented")]
     ControlFramesFragmented,
 
-    #[error("Control frame with invalid payload size, can be greater than 125")]
+    #[error("Control frame with invalid payload size, can't be greater than 125")]
     ControlFramePayloadSize,
 
-    #[error("fragment_size: `{0}` can't be greater than max_frame_size: `{0}`")]
+    #[error("Peer sent more pings than allowed by max_pings_per_second")]
+    PingFlood,
+
+    #[error("fragment_size: `{0}` can't be greater than max_frame_size: `{1}`")]
     CustomFragmentSizeExceeded(usize, usize),
 
+    #[error("Client sent an unmasked frame")]
+    UnmaskedClientFrame,
+
+    #[error("Server sent a masked frame")]
+    MaskedServerFrame,
+
+    #[error("Frame payload length isn't minimally encoded, or has the most significant bit set")]
+    InvalidPayloadLength,
+
     #[error("Max frame size reached")]
     MaxFrameSize,
 
     #[error("Max message size reached")]
     MaxMessageSize,
 
+    #[error("Text message with invalid UTF-8 payload")]
+    InvalidUtf8,
+
+    #[error("Close frame payload can't be a single byte")]
+    InvalidClosePayload,
+
+    #[error("Close frame with an invalid or reserved status code")]
+    InvalidCloseCode,
+
     // Fragmentation Errors
     #[error("Invalid frame while there is a fragmented message in progress")]
     InvalidFrameFragmentation,
@@ -99,6 +198,9 @@ pub enum Error {
     #[error("Invalid HTTP request line")]
     InvalidHTTPRequestLine,
 
+    #[error("Invalid HTTP status line")]
+    InvalidHTTPStatusLine,
+
     #[error("Missing HTTP method")]
     MissingHTTPMethod,
 
@@ -111,7 +213,7 @@ pub enum Error {
     #[error("Invalid Content-Length")]
     InvalidContentLength,
 
-    #[error("{source}")]
+    #[error("Invalid URL: {source}")]
     URLParseError {
         #[from]
         source: ParseError,
@@ -129,17 +231,30 @@ pub enum Error {
     #[error("Incomplete HTTP request")]
     IncompleteHTTPRequest,
 
+    #[error("Header names and values can't be empty or contain CR/LF characters")]
+    InvalidHeader,
+
     // Domain addr parsing error
-    #[error("{source}")]
+    #[error("Invalid DNS name: {source}")]
     DomainError {
         #[from]
         source: InvalidDnsNameError,
     },
 
+    #[error("TLS error: {0}")]
+    Tls(#[from] rustls::Error),
+
     #[error("use_tls = `{0}` argument does not match the passed URL scheme: `{1}`")]
     SchemeAgainstTlsConfig(bool, String),
 
+    #[cfg(feature = "serde")]
+    #[error("JSON serialization error: {0}")]
+    Json(#[from] serde_json::Error),
+
     // Compression / Decompression Errors
     #[error("max_window_bits should be a value between 8 and 15")]
     InvalidMaxWindowBits,
+
+    #[error("The compressed payload is truncated or corrupted")]
+    InvalidCompressedPayload,
 }
@@ -1,8 +1,13 @@
+use crate::error::Error;
+
 const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
 const CLIENT_NO_CONTEXT_TAKEOVER: &str = "client_no_context_takeover";
 const SERVER_NO_CONTEXT_TAKEOVER: &str = "server_no_context_takeover";
 const CLIENT_MAX_WINDOW_BITS: &str = "client_max_window_bits";
 const SERVER_MAX_WINDOW_BITS: &str = "server_max_window_bits";
+// The LZ77 window sizes allowed by RFC 7692, as a base-2 logarithm
+const MIN_WINDOW_BITS: u8 = 8;
+const MAX_WINDOW_BITS: u8 = 15;
 
 /// It's important to enhance that some compression extensions,
 /// in some cases affects compression and
@@ -39,40 +44,208 @@ pub struct Extensions {
     pub server_max_window_bits: Option<u8>,
 }
 
+/// The extensions agreed between client and server during the handshake,
+/// available over `WSConnection::extensions`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NegotiatedExtensions {
+    /// The permessage-deflate parameters, if compression was negotiated
+    pub permessage_deflate: Option<DeflateParams>,
+}
+
+/// The permessage-deflate parameters agreed during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateParams {
+    /// The client resets its compression context after each message
+    pub client_no_context_takeover: bool,
+    /// The server resets its compression context after each message
+    pub server_no_context_takeover: bool,
+    /// The window size used by the client for compressing, if it was limited
+    pub client_max_window_bits: Option<u8>,
+    /// The window size used by the server for compressing, if it was limited
+    pub server_max_window_bits: Option<u8>,
+}
+
+impl NegotiatedExtensions {
+    pub(crate) fn new(extensions: &Option<Extensions>) -> Self {
+        let permessage_deflate = extensions
+            .as_ref()
+            .filter(|extensions| extensions.permessage_deflate)
+            .map(|extensions| DeflateParams {
+                client_no_context_takeover: extensions.client_no_context_takeover == Some(true),
+                server_no_context_takeover: extensions.server_no_context_takeover == Some(true),
+                client_max_window_bits: extensions.client_max_window_bits,
+                server_max_window_bits: extensions.server_max_window_bits,
+            });
+        Self { permessage_deflate }
+    }
+}
+
 // In first stage server will accept all the client extension configs, and
 // will reply the handshake request with everything that came from client
 // on a second stage, the end-user will set the default extension settings when calling
 // accept_async_with_config, and the server will read the client settings from the handshake
 // and will merge with the default settings, prioritizing what is default
 pub fn parse_extensions(extensions_header_value: String) -> Option<Extensions> {
-    let extensions_str = extensions_header_value.split(';');
-    let mut extensions = Extensions::default();
-
-    for extension_str in extensions_str.into_iter() {
-        if extension_str.trim() == PERMESSAGE_DEFLATE {
-            extensions.permessage_deflate = true;
-        } else if extension_str.trim().starts_with(CLIENT_NO_CONTEXT_TAKEOVER) {
-            extensions.client_no_context_takeover = Some(true);
-        } else if extension_str.trim().starts_with(SERVER_NO_CONTEXT_TAKEOVER) {
-            extensions.server_no_context_takeover = Some(true);
-        } else if extension_str.trim().starts_with(CLIENT_MAX_WINDOW_BITS) {
-            if !extension_str.contains('=') {
-                extensions.client_max_window_bits = Some(15);
-            } else {
-                extensions.client_max_window_bits =
-                    extension_str.trim().split('=').last()?.parse::<u8>().ok();
+    // The client may offer permessage-deflate multiple times, with different parameters,
+    // in the order of preference, where the first acceptable one is selected
+    parse_offers(&extensions_header_value)
+        .iter()
+        .filter(|offer| offer.name.eq_ignore_ascii_case(PERMESSAGE_DEFLATE))
+        .filter_map(deflate_extensions)
+        .find(|extensions| validate_window_bits(extensions).is_ok())
+}
+
+// Client will use this function for parsing the extensions accepted by the server,
+// comparing them with the ones the client offered.
+// Differently from the offers, a response can't be declined, so it fails the handshake if it
+// has window sizes out of range, or anything that wasn't offered
+pub(crate) fn parse_accepted_extensions(
+    extensions_header_value: &str,
+    offered: Option<&Extensions>,
+) -> Result<Option<Extensions>, Error> {
+    let accepted = parse_offers(extensions_header_value);
+    let Some(first) = accepted.first() else {
+        return Ok(None);
+    };
+    let not_offered = || Error::InvalidExtensionResponse {
+        extensions: extensions_header_value.to_string(),
+    };
+
+    // Only permessage-deflate is ever offered, once
+    if accepted.len() > 1 || !first.name.eq_ignore_ascii_case(PERMESSAGE_DEFLATE) {
+        return Err(not_offered());
+    }
+    let extensions = deflate_extensions(first).ok_or_else(not_offered)?;
+    validate_window_bits(&extensions)?;
+
+    let offered = offered
+        .filter(|offered| offered.permessage_deflate)
+        .ok_or_else(not_offered)?;
+    // The server may limit its own window, and ask any of the sides to reset its context,
+    // even if it wasn't offered, but it can only limit the client window if the client
+    // offered it, and no window can be larger than the offered one
+    let within_offer = |accepted: Option<u8>, offered: Option<u8>| match (accepted, offered) {
+        (Some(accepted), Some(offered)) => accepted <= offered,
+        _ => true,
+    };
+    if (extensions.client_max_window_bits.is_some() && offered.client_max_window_bits.is_none())
+        || !within_offer(
+            extensions.client_max_window_bits,
+            offered.client_max_window_bits,
+        )
+        || !within_offer(
+            extensions.server_max_window_bits,
+            offered.server_max_window_bits,
+        )
+    {
+        return Err(not_offered());
+    }
+    Ok(Some(extensions))
+}
+
+// Ensures the window sizes are within the range allowed by RFC 7692, from 8 to 15 bits
+pub(crate) fn validate_window_bits(extensions: &Extensions) -> Result<(), Error> {
+    let valid = [
+        extensions.client_max_window_bits,
+        extensions.server_max_window_bits,
+    ]
+    .into_iter()
+    .flatten()
+    .all(|bits| (MIN_WINDOW_BITS..=MAX_WINDOW_BITS).contains(&bits));
+    if !valid {
+        return Err(Error::InvalidMaxWindowBits);
+    }
+    Ok(())
+}
+
+// An extension offered through the Sec-WebSocket-Extensions header, with its parameters
+struct ExtensionOffer {
+    name: String,
+    params: Vec<(String, Option<String>)>,
+}
+
+// Parses the Sec-WebSocket-Extensions grammar, which is a comma-separated list of extensions,
+// each followed by semicolon-separated parameters, with an optional value, that may be quoted:
+// permessage-deflate; client_max_window_bits="10", permessage-deflate
+fn parse_offers(header_value: &str) -> Vec<ExtensionOffer> {
+    split_unquoted(header_value, ',')
+        .into_iter()
+        .filter_map(|element| {
+            let mut parts = split_unquoted(element, ';').into_iter();
+            let name = parts.next()?.trim();
+            if name.is_empty() {
+                return None;
             }
-        } else if extension_str.trim().starts_with(SERVER_MAX_WINDOW_BITS) {
-            if !extension_str.contains('=') {
-                extensions.server_max_window_bits = Some(15);
-            } else {
-                extensions.server_max_window_bits =
-                    extension_str.trim().split('=').last()?.parse::<u8>().ok();
+            let params = parts
+                .map(|param| match param.split_once('=') {
+                    Some((name, value)) => (
+                        name.trim().to_string(),
+                        Some(value.trim().trim_matches('"').to_string()),
+                    ),
+                    None => (param.trim().to_string(), None),
+                })
+                .collect();
+            Some(ExtensionOffer {
+                name: name.to_string(),
+                params,
+            })
+        })
+        .collect()
+}
+
+// Splits the value on the separator, unless it's inside a quoted string
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&value[start..i]);
+                start = i + c.len_utf8();
             }
+            _ => {}
         }
     }
-    if !extensions.permessage_deflate {
-        return None;
+    parts.push(&value[start..]);
+    parts
+}
+
+// Converts a permessage-deflate offer into the extensions it asks for.
+// Offers with unknown or repeated parameters, or invalid values, are declined, per RFC 7692
+fn deflate_extensions(offer: &ExtensionOffer) -> Option<Extensions> {
+    let mut extensions = Extensions {
+        permessage_deflate: true,
+        ..Default::default()
+    };
+
+    for (i, (name, value)) in offer.params.iter().enumerate() {
+        if offer.params[..i]
+            .iter()
+            .any(|(previous, _)| previous.eq_ignore_ascii_case(name))
+        {
+            return None;
+        }
+        match (name.to_ascii_lowercase().as_str(), value) {
+            (CLIENT_NO_CONTEXT_TAKEOVER, None) => {
+                extensions.client_no_context_takeover = Some(true);
+            }
+            (SERVER_NO_CONTEXT_TAKEOVER, None) => {
+                extensions.server_no_context_takeover = Some(true);
+            }
+            // Without a value, the client only tells that it supports the parameter
+            (CLIENT_MAX_WINDOW_BITS, None) => {
+                extensions.client_max_window_bits = Some(MAX_WINDOW_BITS);
+            }
+            (CLIENT_MAX_WINDOW_BITS, Some(value)) => {
+                extensions.client_max_window_bits = Some(value.parse().ok()?);
+            }
+            (SERVER_MAX_WINDOW_BITS, Some(value)) => {
+                extensions.server_max_window_bits = Some(value.parse().ok()?);
+            }
+            _ => return None,
+        }
     }
 
     Some(extensions)
@@ -88,22 +261,27 @@ pub fn merge_extensions(
 ) -> Option<Extensions> {
     let server_ext = server_extensions?;
     let client_ext = client_extensions?;
+    // Without permessage-deflate on both sides, there is nothing else to agree on
+    if !(client_ext.permessage_deflate && server_ext.permessage_deflate) {
+        return None;
+    }
     let merged_extensions = Extensions {
-        permessage_deflate: client_ext.permessage_deflate && server_ext.permessage_deflate,
+        permessage_deflate: true,
         client_no_context_takeover: server_ext
             .client_no_context_takeover
             .and(client_ext.client_no_context_takeover),
         server_no_context_takeover: server_ext
             .server_no_context_takeover
             .and(client_ext.server_no_context_takeover),
+        // Per RFC 7692, the client window can only be limited if the client offered the
+        // parameter, otherwise the client must fail the connection
         client_max_window_bits: match (
             server_ext.client_max_window_bits,
             client_ext.client_max_window_bits,
         ) {
             (Some(server_bits), Some(client_bits)) => Some(std::cmp::min(server_bits, client_bits)),
-            (Some(server_bits), None) => Some(server_bits),
             (None, Some(client_bits)) => Some(client_bits),
-            (None, None) => None,
+            (_, None) => None,
         },
         server_max_window_bits: match (
             server_ext.server_max_window_bits,
@@ -118,29 +296,24 @@ pub fn merge_extensions(
     Some(merged_extensions)
 }
 
-// Function used for constructing the HTTP request headers for extensions
+// Function used for constructing the HTTP request headers for extensions.
+// It also ends the head with the blank line, so nothing else can follow it
 pub fn add_extension_headers(request: &mut String, extensions: Option<Extensions>) {
-    match extensions {
-        None => {
-            request.push_str("\r\n");
+    if let Some(extensions) = extensions.filter(|extensions| extensions.permessage_deflate) {
+        request.push_str(&format!("Sec-WebSocket-Extensions: {}", PERMESSAGE_DEFLATE));
+        if let Some(true) = extensions.client_no_context_takeover {
+            request.push_str(&format!("; {}", CLIENT_NO_CONTEXT_TAKEOVER))
         }
-        Some(extensions) => {
-            if extensions.permessage_deflate {
-                request.push_str(&format!("Sec-WebSocket-Extensions: {}", PERMESSAGE_DEFLATE));
-                if let Some(true) = extensions.client_no_context_takeover {
-                    request.push_str(&format!("; {}", CLIENT_NO_CONTEXT_TAKEOVER))
-                }
-                if let Some(true) = extensions.server_no_context_takeover {
-                    request.push_str(&format!("; {}", SERVER_NO_CONTEXT_TAKEOVER))
-                }
-                if let Some(bits) = extensions.client_max_window_bits {
-                    request.push_str(&format!("; {}={}", CLIENT_MAX_WINDOW_BITS, bits))
-                }
-                if let Some(bits) = extensions.server_max_window_bits {
-                    request.push_str(&format!("; {}={}", SERVER_MAX_WINDOW_BITS, bits))
-                }
-            }
-            request.push_str("\r\n\r\n");
+        if let Some(true) = extensions.server_no_context_takeover {
+            request.push_str(&format!("; {}", SERVER_NO_CONTEXT_TAKEOVER))
+        }
+        if let Some(bits) = extensions.client_max_window_bits {
+            request.push_str(&format!("; {}={}", CLIENT_MAX_WINDOW_BITS, bits))
+        }
+        if let Some(bits) = extensions.server_max_window_bits {
+            request.push_str(&format!("; {}={}", SERVER_MAX_WINDOW_BITS, bits))
         }
+        request.push_str("\r\n");
     }
+    request.push_str("\r\n");
 }
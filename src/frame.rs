@@ -1,4 +1,5 @@
 use crate::error::Error;
+use bytes::Bytes;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum OpCode {
@@ -44,16 +45,23 @@ impl OpCode {
 pub struct Frame {
     pub final_fragment: bool,
     pub opcode: OpCode,
-    pub payload: Vec<u8>,
+    // Shared buffer, so the payload can be handed to a message, or split into
+    // multiple frames, without copying it
+    pub payload: Bytes,
     pub compressed: bool,
 }
 
 impl Frame {
-    pub fn new(final_fragment: bool, opcode: OpCode, payload: Vec<u8>, compressed: bool) -> Self {
+    pub fn new(
+        final_fragment: bool,
+        opcode: OpCode,
+        payload: impl Into<Bytes>,
+        compressed: bool,
+    ) -> Self {
         Self {
             final_fragment,
             opcode,
-            payload,
+            payload: payload.into(),
             compressed,
         }
     }
@@ -1,26 +1,50 @@
-use crate::config::{ClientConfig, WebSocketConfig};
+use crate::config::{
+    AcceptConfig, ClientConfig, ErrorResponse, HandshakeResponse, WebSocketConfig,
+};
 use crate::connection::WSConnection;
 use crate::decoder::Decoder;
 use crate::encoder::Encoder;
 use crate::error::Error;
-use crate::extensions::{add_extension_headers, merge_extensions, parse_extensions, Extensions};
+use crate::extensions::{
+    add_extension_headers, merge_extensions, parse_accepted_extensions, parse_extensions,
+    validate_window_bits, Extensions, NegotiatedExtensions,
+};
+use crate::keepalive::spawn_keepalive;
+use crate::limiter::ConnectionPermit;
 use crate::message::Message;
+use crate::proxy::connect_through_proxy;
+use crate::raw::RawConnection;
 use crate::read::ReadStream;
-use crate::request::{construct_http_request, HttpRequest};
-use crate::split::{WSReader, WSWriter};
-use crate::stream::SocketFlowStream;
-use crate::utils::{generate_websocket_accept_value, generate_websocket_key};
+use crate::request::{
+    combine_headers, construct_http_request, has_token, redirect_url, validate_header,
+    HandshakeRequest, HttpResponse,
+};
+use crate::split::{TaskGuard, WSReader, WSWriter};
+use crate::state::ConnectionState;
+use crate::stream::{
+    connect_tcp, tcp_stream, BoxedReader, BoxedWriter, SocketAddrs, SocketFlowStream,
+};
+use crate::tls::client_tls_config;
+use crate::utils::generate_websocket_key;
 use crate::write::{Writer, WriterKind};
-use std::fs::File;
-use std::io::BufReader as SyncBufReader;
+use accept_key::{compute_accept_key, is_valid_key, verify_accept_key};
+use std::future::Future;
+use std::io::Cursor;
 use std::path::Path;
 use std::sync::Arc;
-use tokio::io::{split, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::sync::mpsc::channel;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::timeout;
 use tokio_rustls::{TlsConnector, TlsStream};
 use tokio_stream::wrappers::ReceiverStream;
+use url::Url;
+
+pub mod accept_key;
+
+pub use crate::request::HttpRequest;
+pub use tokio_rustls::TlsAcceptor;
 
 pub(crate) const HTTP_ACCEPT_RESPONSE: &str = "HTTP/1.1 101 Switching Protocols\r\n\
         Connection: Upgrade\r\n\
@@ -29,10 +53,19 @@ pub(crate) const HTTP_ACCEPT_RESPONSE: &str = "HTTP/1.1 101 Switching Protocols\
         ";
 
 const HTTP_METHOD: &str = "GET";
+const HTTP_VERSION: &str = "HTTP/1.1";
+const ORIGIN: &str = "Origin";
 pub(crate) const SEC_WEBSOCKET_KEY: &str = "Sec-WebSocket-Key";
 pub(crate) const SEC_WEBSOCKET_EXTENSIONS: &str = "Sec-WebSocket-Extensions";
 pub(crate) const SEC_WEBSOCKET_ACCEPT: &str = "Sec-WebSocket-Accept";
+pub(crate) const SEC_WEBSOCKET_PROTOCOL: &str = "Sec-WebSocket-Protocol";
+pub(crate) const SEC_WEBSOCKET_VERSION: &str = "Sec-WebSocket-Version";
+// The only version defined by the WebSockets RFC
+pub(crate) const WEBSOCKET_VERSION: &str = "13";
 const HOST: &str = "Host";
+const CONNECTION: &str = "Connection";
+const UPGRADE: &str = "Upgrade";
+const LOCATION: &str = "Location";
 
 pub type Result = std::result::Result<WSConnection, Error>;
 
@@ -41,23 +74,281 @@ pub type Result = std::result::Result<WSConnection, Error>;
 /// It basically does the first step of verifying the client key in the request
 /// going to the second step, which is sending the acceptance response,
 /// finally creating the connection, and returning a `WSConnection`.
-pub async fn accept_async(stream: SocketFlowStream) -> Result {
+///
+/// Any stream implementing `AsyncRead` and `AsyncWrite` can be used, like a `SocketFlowStream`,
+/// a plain `TcpStream`, or even a `tokio::io::duplex` stream.
+pub async fn accept_async<S>(stream: S) -> Result
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     accept_async_with_config(stream, None).await
 }
 
 /// Same as accept_async, with an additional argument for custom websocket connection configurations.
-pub async fn accept_async_with_config(
-    stream: SocketFlowStream,
-    config: Option<WebSocketConfig>,
-) -> Result {
-    let (reader, mut write_half) = split(stream);
-    let mut buf_reader = BufReader::new(reader);
+pub async fn accept_async_with_config<S>(stream: S, config: Option<WebSocketConfig>) -> Result
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    accept_async_with(stream, AcceptConfig::new().web_socket_config(config.unwrap_or_default()))
+        .await
+}
+
+/// Same as accept_async, with a callback for inspecting the client handshake request, like
+/// the path, query string and headers, for routing or authentication purposes.
+/// Returning an error from the callback rejects the upgrade with a 403 Forbidden response,
+/// and `Error::HandshakeRejected` is returned.
+pub async fn accept_async_with_request<S, F>(stream: S, callback: F) -> Result
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(&HttpRequest) -> std::result::Result<(), String> + Send + Sync + 'static,
+{
+    accept_async_with(stream, AcceptConfig::new().on_request(callback)).await
+}
+
+/// Same as accept_async, with a callback deciding on the client handshake request, which borrows
+/// the parsed request, like its path and headers.
+/// Returning a `HandshakeResponse` accepts the upgrade, with an optional subprotocol and
+/// additional response headers, while returning an `ErrorResponse` rejects it, responding
+/// with its status, headers and body, and `Error::HandshakeRejected` is returned.
+pub async fn accept_hdr_async<S, F>(stream: S, callback: F) -> Result
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(&HttpRequest) -> std::result::Result<HandshakeResponse, ErrorResponse>
+        + Send
+        + Sync
+        + 'static,
+{
+    accept_async_with(stream, AcceptConfig::new().on_handshake(callback)).await
+}
+
+/// Same as accept_async, with an additional argument for handshake options, like selecting
+/// one of the subprotocols offered by the client.
+pub async fn accept_async_with<S>(stream: S, accept_config: AcceptConfig) -> Result
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if let Some(tcp) = tcp_stream(&stream) {
+        tcp.set_nodelay(accept_config.web_socket_config.nodelay)?;
+    }
+    let addrs = SocketAddrs::from_stream(&stream);
+    let (buf_reader, write_half) = split_stream(stream);
+    accept_halves(buf_reader, write_half, addrs, accept_config).await
+}
+
+/// Accepts a websocket connection over TLS, serving `wss://` directly from a plain stream,
+/// like an accepted `TcpStream`, without needing a reverse proxy for terminating TLS.
+///
+/// The TLS handshake is performed with the acceptor first, and then the websocket handshake
+/// over the encrypted stream. The handshake timeout, if any, bounds each of them.
+pub async fn accept_async_tls<S>(stream: S, tls_acceptor: &TlsAcceptor) -> Result
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    accept_async_tls_with(stream, tls_acceptor, AcceptConfig::new()).await
+}
+
+/// Same as accept_async_tls, with an additional argument for handshake options.
+pub async fn accept_async_tls_with<S>(
+    stream: S,
+    tls_acceptor: &TlsAcceptor,
+    accept_config: AcceptConfig,
+) -> Result
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let tls_stream =
+        with_handshake_timeout(accept_config.web_socket_config.handshake_timeout, async {
+            Ok(tls_acceptor.accept(stream).await?)
+        })
+        .await?;
+    accept_async_with(tls_stream, accept_config).await
+}
+
+/// Accepts a connection from a stream already split into its read and write halves, along with
+/// the bytes that were already read from it, like by a framework peeking at the first bytes for
+/// multiplexing HTTP and WebSockets on the same port.
+///
+/// The handshake request is parsed from the prebuffered bytes first, and then from the reader.
+/// Any halves can be used, like the ones from `TcpStream::into_split` or `tokio::io::split`,
+/// so `accept_async(stream)` is the same as accepting both halves of the stream, without
+/// prebuffered bytes. Since the halves aren't known as TCP, `nodelay` isn't applied, and
+/// the connection has no peer or local address.
+pub async fn accept_async_from_parts<R, W>(reader: R, writer: W, prebuffered: Vec<u8>) -> Result
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    accept_async_from_parts_with(reader, writer, prebuffered, AcceptConfig::new()).await
+}
+
+/// Same as accept_async_from_parts, with an additional argument for handshake options.
+pub async fn accept_async_from_parts_with<R, W>(
+    reader: R,
+    writer: W,
+    prebuffered: Vec<u8>,
+    accept_config: AcceptConfig,
+) -> Result
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let reader: Box<dyn AsyncRead + Send + Unpin> =
+        Box::new(Cursor::new(prebuffered).chain(reader));
+    accept_halves(
+        BufReader::new(reader),
+        Box::new(writer),
+        SocketAddrs::default(),
+        accept_config,
+    )
+    .await
+}
+
+/// Validates a handshake request already read by an HTTP framework, like hyper or axum,
+/// returning the 101 Switching Protocols response, and the `Upgrade` starting the connection.
+///
+/// The framework writes the response itself, and then passes the upgraded stream to
+/// `Upgrade::into_connection`, so the request isn't read or parsed again by this library.
+/// Only the headers are given, since the framework already routed the request, so it's taken
+/// as a `GET` request over HTTP/1.1, with the given URI, which is seen by the callbacks of
+/// the `AcceptConfig`.
+///
+/// When the handshake isn't accepted, the rejection carries the response the framework
+/// should write instead, like a 400 Bad Request, or a 426 Upgrade Required.
+pub fn upgrade_response<'a>(
+    uri: &str,
+    request_headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    accept_config: AcceptConfig,
+) -> std::result::Result<(String, Upgrade), UpgradeRejection> {
+    let req = HttpRequest {
+        method: HTTP_METHOD.to_string(),
+        uri: uri.to_string(),
+        version: HTTP_VERSION.to_string(),
+        headers: combine_headers(request_headers),
+        body: Vec::new(),
+    };
+    validate_accept_config(&accept_config)?;
+    let accepted = accept_request(&req, &accept_config)?;
+    let mut config = accept_config.web_socket_config;
+    config.extensions = accepted.extensions;
+    let upgrade = Upgrade {
+        config,
+        protocol: accepted.protocol,
+        permit: accepted.permit,
+    };
+    Ok((accepted.response, upgrade))
+}
+
+/// A handshake accepted by `upgrade_response`, which starts the connection over the stream
+/// upgraded by the HTTP framework, after it wrote the 101 Switching Protocols response
+pub struct Upgrade {
+    config: WebSocketConfig,
+    protocol: Option<String>,
+    // Taken when the handshake was accepted, so the limit also counts upgrades in progress
+    permit: Option<ConnectionPermit>,
+}
+
+impl Upgrade {
+    /// The subprotocol selected for the connection, if any
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Starts the read and write tasks of the connection over the upgraded stream.
+    ///
+    /// Any bytes the framework buffered after the request, should be part of the stream,
+    /// as hyper does with the upgraded connections it returns.
+    pub async fn into_connection<S>(self, stream: S) -> Result
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if let Some(tcp) = tcp_stream(&stream) {
+            tcp.set_nodelay(self.config.nodelay)?;
+        }
+        let addrs = SocketAddrs::from_stream(&stream);
+        let (buf_reader, write_half) = split_stream(stream);
+        start_server_connection(
+            buf_reader,
+            write_half,
+            addrs,
+            self.config,
+            self.protocol,
+            self.permit,
+        )
+        .await
+    }
+}
 
-    let mut config = config.unwrap_or_default();
-    let parsed_extensions =
-        parse_handshake_server(&mut buf_reader, &mut write_half, config.extensions).await?;
+/// Accepts a websocket connection as a `RawConnection`, which reads and writes single frames,
+/// without reassembling or validating them, check the `raw` module for more details.
+pub async fn accept_raw<S>(stream: S) -> std::result::Result<RawConnection, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let accept_config = AcceptConfig::default();
+    let (mut buf_reader, mut write_half) = split_stream(stream);
+    // Without extensions configured, none is negotiated
+    let (_, protocol, _) = with_handshake_timeout(
+        accept_config.web_socket_config.handshake_timeout,
+        parse_handshake_server(&mut buf_reader, &mut write_half, &accept_config),
+    )
+    .await?;
+    Ok(RawConnection::new(
+        buf_reader,
+        write_half,
+        WriterKind::Server,
+        accept_config.web_socket_config.frame_size_limit(),
+        protocol,
+    ))
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "accept_async",
+        skip_all,
+        fields(protocol = tracing::field::Empty, extensions = tracing::field::Empty)
+    )
+)]
+async fn accept_halves(
+    mut buf_reader: BoxedReader,
+    mut write_half: BoxedWriter,
+    addrs: SocketAddrs,
+    accept_config: AcceptConfig,
+) -> Result {
+    validate_accept_config(&accept_config)?;
+    // The same buffered reader is used for the handshake and the websocket frames, so
+    // any frame bytes read together with the handshake, remain buffered for the ReadStream
+    let (parsed_extensions, protocol, permit) = with_handshake_timeout(
+        accept_config.web_socket_config.handshake_timeout,
+        parse_handshake_server(&mut buf_reader, &mut write_half, &accept_config),
+    )
+    .await?;
+    let mut config = accept_config.web_socket_config;
     config.extensions = parsed_extensions;
+    #[cfg(feature = "tracing")]
+    record_handshake(&protocol, &config.extensions);
+
+    start_server_connection(buf_reader, write_half, addrs, config, protocol, permit).await
+}
 
+// A window size out of range in the server configuration is a mistake of the server, so it's
+// reported before reading the handshake request, instead of being answered to the client
+fn validate_accept_config(accept_config: &AcceptConfig) -> std::result::Result<(), Error> {
+    match accept_config.web_socket_config.extensions {
+        Some(ref extensions) => validate_window_bits(extensions),
+        None => Ok(()),
+    }
+}
+
+// Starts the connection of an accepted handshake, with the negotiated extensions in the config
+async fn start_server_connection(
+    buf_reader: BoxedReader,
+    write_half: BoxedWriter,
+    addrs: SocketAddrs,
+    config: WebSocketConfig,
+    protocol: Option<String>,
+    permit: Option<ConnectionPermit>,
+) -> Result {
     let decoder_extensions = config.extensions.clone().unwrap_or_default();
     // The decoder will be reading and decompressing all client messages,
     // so we need to pass all the client extensions to it
@@ -76,49 +367,197 @@ pub async fn accept_async_with_config(
         encoder_extensions.server_max_window_bits,
     );
 
-    // Identify permessage-deflate for enabling compression
-    second_stage_handshake(
+    let ws_connection = second_stage_handshake(
         buf_reader,
         write_half,
         WriterKind::Server,
         config,
         decoder,
         encoder,
+        protocol,
+        permit,
     )
-        .await
+    .await?;
+    Ok(ws_connection.with_addrs(addrs))
+}
+
+// Records the handshake outcome into the accept_async or connect_async span
+#[cfg(feature = "tracing")]
+fn record_handshake(protocol: &Option<String>, extensions: &Option<Extensions>) {
+    let span = tracing::Span::current();
+    span.record("protocol", protocol.as_deref().unwrap_or_default());
+    span.record("extensions", tracing::field::debug(extensions));
+    tracing::debug!("handshake completed");
+}
+
+// An HTTP response for a handshake that won't be upgraded, closing the connection afterward
+fn http_response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+    let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    response
+}
+
+// Responds to a malformed handshake request with a 400 Bad Request, so the client can diagnose
+// the failure, instead of only seeing the connection being dropped
+async fn bad_request<T>(
+    write_half: &mut BoxedWriter,
+    error: Error,
+) -> std::result::Result<T, Error> {
+    write_half
+        .write_all(http_response("400 Bad Request", &[], "").as_bytes())
+        .await?;
+    write_half.flush().await?;
+    Err(error)
+}
+
+// A handshake request validated by accept_request
+struct AcceptedRequest {
+    response: String,
+    extensions: Option<Extensions>,
+    protocol: Option<String>,
+    permit: Option<ConnectionPermit>,
+}
+
+/// A handshake request that won't be upgraded, returned by `upgrade_response`, along with
+/// the HTTP response telling the client why, like a 400 Bad Request for a malformed request.
+///
+/// There is no response when the server configuration itself is invalid, like a response
+/// header with a line break, which is up to the HTTP framework to answer, usually with a 500.
+/// A subprotocol selected by the server that the client didn't offer is answered with a 500.
+#[derive(Debug)]
+pub struct UpgradeRejection {
+    pub error: Error,
+    pub response: Option<String>,
+}
+
+impl UpgradeRejection {
+    fn new(status: &str, headers: &[(&str, &str)], body: &str, error: Error) -> Self {
+        Self {
+            error,
+            response: Some(http_response(status, headers, body)),
+        }
+    }
+
+    fn bad_request(error: Error) -> Self {
+        Self::new("400 Bad Request", &[], "", error)
+    }
+
+    // Rejects the handshake by the end-user, with the status, headers and body it chose
+    fn reject(error_response: ErrorResponse) -> Self {
+        let mut headers = Vec::with_capacity(error_response.headers.len());
+        for (name, value) in &error_response.headers {
+            if let Err(error) = validate_header(name, value) {
+                return error.into();
+            }
+            headers.push((name.as_str(), value.as_str()));
+        }
+        let status = format!(
+            "{} {}",
+            error_response.status,
+            status_reason(error_response.status)
+        );
+        Self::new(
+            &status,
+            &headers,
+            &error_response.body,
+            Error::HandshakeRejected(error_response.body.clone()),
+        )
+    }
+}
+
+impl From<Error> for UpgradeRejection {
+    fn from(error: Error) -> Self {
+        Self {
+            error,
+            response: None,
+        }
+    }
+}
+
+impl std::fmt::Display for UpgradeRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for UpgradeRejection {}
+
+impl From<UpgradeRejection> for Error {
+    fn from(rejection: UpgradeRejection) -> Self {
+        rejection.error
+    }
 }
 
+// Reason phrase of the status codes commonly used for rejecting a handshake.
+// Other status codes are sent with an empty reason, which is allowed by HTTP
+fn status_reason(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+// Splits the stream into boxed halves, which are used by the rest of the handshake and by the
+// connection itself, regardless of the underlying stream type
+fn split_stream<S>(stream: S) -> (BoxedReader, BoxedWriter)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (read_half, write_half) = split(stream);
+    let reader: Box<dyn AsyncRead + Send + Unpin> = Box::new(read_half);
+    (BufReader::new(reader), Box::new(write_half))
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn second_stage_handshake(
-    buf_reader: BufReader<ReadHalf<SocketFlowStream>>,
-    write_half: WriteHalf<SocketFlowStream>,
+    buf_reader: BoxedReader,
+    write_half: BoxedWriter,
     kind: WriterKind,
     config: WebSocketConfig,
     decoder: Decoder,
     encoder: Encoder,
+    protocol: Option<String>,
+    permit: Option<ConnectionPermit>,
 ) -> Result {
     // This writer instance would be used for writing frames into the socket.
     // Since it's going to be used by two different instances, we need to wrap it through an Arc
-    let writer = Arc::new(Mutex::new(Writer::new(write_half, kind)));
+    let state = Arc::new(ConnectionState::default());
+    let writer = Arc::new(Mutex::new(Writer::new(
+        write_half,
+        kind,
+        encoder,
+        state.clone(),
+        config.write_timeout,
+    )));
 
     let stream_writer = writer.clone();
 
     // ReadStream will be running on a separate task, capturing all the incoming frames from the connection, and broadcasting them through this
-    // tokio mpsc channel. Therefore, it can be consumed by the end-user of this library
-    let (read_tx, read_rx) = channel::<std::result::Result<Message, Error>>(20);
-    let mut read_stream =
-        ReadStream::new(buf_reader, read_tx, stream_writer, config.clone(), decoder);
-
-    let connection_writer = writer.clone();
-    // Transforming the receiver of the channel into a Stream, so we could leverage using
-    // next() method, for processing the values from this channel
-    let receiver_stream = ReceiverStream::new(read_rx);
-
-    // The WSConnection is the structure that will be delivered to the end-user, which contains
-    // a stream of frames, for consuming the incoming frames, and methods for writing frames into
-    // the socket
-    let ws_connection = WSConnection::new(
-        WSWriter::new(connection_writer, config, encoder),
-        WSReader::new(receiver_stream),
+    // tokio mpsc channel. Therefore, it can be consumed by the end-user of this library.
+    // A zero capacity would make tokio panic, so at least one message is buffered
+    let (read_tx, read_rx) =
+        channel::<std::result::Result<Message, Error>>(config.channel_capacity.max(1));
+    let weak_read_tx = read_tx.downgrade();
+    let pong_notify = Arc::new(Notify::new());
+    let mut read_stream = ReadStream::new(
+        buf_reader,
+        read_tx,
+        stream_writer,
+        kind,
+        config.clone(),
+        decoder,
+        pong_notify.clone(),
+        state.clone(),
     );
 
     // Spawning poll_messages which is the method for reading the frames from the socket concurrently,
@@ -127,11 +566,53 @@ async fn second_stage_handshake(
     // Since this is the only task that holds the ownership of BufReader, if some IO error happens,
     // poll_messages will return.
     // BufReader will be dropped, hence, the writeHalf and TCP connection
-    tokio::spawn(async move {
+    // The connection permit is held by the read task, being released when the connection is
+    // closed, or when the task is aborted, after the connection is dropped
+    let read_future = async move {
+        let _permit = permit;
         if let Err(err) = read_stream.poll_messages().await {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %err, "read loop failed");
+            read_stream.fail_connection(&err).await;
             let _ = read_stream.read_tx.send(Err(err)).await;
         }
-    });
+    };
+    // The frame events are recorded within the handshake span, identifying the connection
+    #[cfg(feature = "tracing")]
+    let read_future = tracing::Instrument::in_current_span(read_future);
+    let read_task = tokio::spawn(read_future);
+
+    // The read task is aborted as soon as the end-user drops the connection, or both reader and
+    // writer after splitting it, releasing the socket, instead of waiting for an IO error
+    let mut tasks = vec![read_task.abort_handle()];
+    if let Some(interval) = config.keepalive_interval {
+        let keepalive_task = spawn_keepalive(
+            interval,
+            config.keepalive_timeout.unwrap_or(interval),
+            writer.clone(),
+            pong_notify,
+            weak_read_tx,
+            read_task.abort_handle(),
+        );
+        tasks.push(keepalive_task.abort_handle());
+    }
+    let tasks = Arc::new(TaskGuard::new(tasks));
+
+    let connection_writer = writer.clone();
+    // Transforming the receiver of the channel into a Stream, so we could leverage using
+    // next() method, for processing the values from this channel
+    let receiver_stream = ReceiverStream::new(read_rx);
+
+    // The WSConnection is the structure that will be delivered to the end-user, which contains
+    // a stream of frames, for consuming the incoming frames, and methods for writing frames into
+    // the socket
+    let extensions = NegotiatedExtensions::new(&config.extensions);
+    let ws_connection = WSConnection::new(
+        WSWriter::new(connection_writer, config, state.clone(), tasks.clone()),
+        WSReader::new(receiver_stream, state, tasks),
+        protocol,
+    )
+    .with_extensions(extensions);
 
     Ok(ws_connection)
 }
@@ -147,76 +628,230 @@ pub async fn connect_async(addr: &str) -> Result {
 
 /// Same as connect_async, with an additional argument for custom websocket connection configurations.
 pub async fn connect_async_with_config(addr: &str, client_config: Option<ClientConfig>) -> Result {
-    let client_websocket_key = generate_websocket_key();
+    let client_config = client_config.unwrap_or_default();
+    // The handshake timeout covers the entire handshake, from the TCP connection,
+    // until the server response is parsed
+    with_handshake_timeout(
+        client_config.web_socket_config.handshake_timeout,
+        connect(addr, client_config),
+    )
+    .await
+}
+
+// If the handshake doesn't complete within the timeout, Error::HandshakeTimeout is returned,
+// which avoids tying up a task with peers that never complete the handshake
+async fn with_handshake_timeout<T>(
+    handshake_timeout: Option<Duration>,
+    handshake: impl Future<Output = std::result::Result<T, Error>>,
+) -> std::result::Result<T, Error> {
+    match handshake_timeout {
+        Some(duration) => timeout(duration, handshake)
+            .await
+            .map_err(|_| Error::HandshakeTimeout)?,
+        None => handshake.await,
+    }
+}
 
-    let client_extensions = client_config.clone().unwrap_or_default().web_socket_config.extensions;
+// The URL isn't recorded, since it may contain credentials, only the host and port
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "connect_async",
+        skip_all,
+        fields(
+            host = tracing::field::Empty,
+            protocol = tracing::field::Empty,
+            extensions = tracing::field::Empty
+        )
+    )
+)]
+async fn connect(addr: &str, mut client_config: ClientConfig) -> Result {
+    let mut url = addr.to_string();
+    // The URLs already requested, for detecting redirect loops
+    let mut visited = vec![Url::parse(addr)?.to_string()];
+    loop {
+        // Every handshake, including the redirected ones, uses a new key
+        let client_websocket_key = generate_websocket_key();
 
-    let (request, hostname, host, use_tls) = construct_http_request(addr, &client_websocket_key, client_extensions)?;
+        let request = construct_http_request(&url, &client_websocket_key, &client_config)?;
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("host", request.host_with_port.as_str());
 
-    let stream = TcpStream::connect(hostname).await?;
+        let (stream, addrs) = open_stream(&request, &client_config).await?;
+        let result = client_handshake(
+            stream,
+            &request.raw,
+            client_websocket_key,
+            client_config.clone(),
+        )
+        .await;
+        let location = match result {
+            Err(Error::Redirect { location, .. }) if client_config.max_redirects > 0 => location,
+            result => return Ok(result?.with_addrs(addrs)),
+        };
 
-    let maybe_ca_file = client_config.clone().unwrap_or_default().ca_file;
-    let maybe_tls = if use_tls {
-        // Creating a cert store, to inject the TLS certificates
-        let mut root_cert_store = rustls::RootCertStore::empty();
+        if visited.len() > client_config.max_redirects {
+            return Err(Error::TooManyRedirects { location });
+        }
+        url = redirect_url(&url, &location, &mut client_config)?;
+        if visited.contains(&url) {
+            return Err(Error::RedirectLoop { location: url });
+        }
+        visited.push(url.clone());
+    }
+}
 
-        // In the case you are using self-signed certificates on the server
-        // you are trying to connect, you must indicate a CA certificate of this server
-        // when connecting to it.
-        // Since the server has a self-signed cert, the only way of this library validating
-        // the cert is adding as an argument of the connect_async function
-        if let Some(file) = maybe_ca_file {
-            let mut pem = SyncBufReader::new(File::open(Path::new(file.as_str()))?);
-            for cert in rustls_pemfile::certs(&mut pem) {
-                root_cert_store.add(cert?).unwrap();
+/// Connects as a client to a websocket endpoint, returning a `RawConnection`, which reads and
+/// writes single frames, without reassembling or validating them, check the `raw` module
+/// for more details. The extensions of the configuration are ignored.
+pub async fn connect_raw(
+    addr: &str,
+    client_config: Option<ClientConfig>,
+) -> std::result::Result<RawConnection, Error> {
+    let mut client_config = client_config.unwrap_or_default();
+    client_config.web_socket_config.extensions = None;
+    with_handshake_timeout(client_config.web_socket_config.handshake_timeout, async {
+        let client_websocket_key = generate_websocket_key();
+        let request = construct_http_request(addr, &client_websocket_key, &client_config)?;
+        let (stream, _) = open_stream(&request, &client_config).await?;
+
+        let (mut buf_reader, mut write_half) = split_stream(stream);
+        write_half.write_all(request.raw.as_bytes()).await?;
+        let (_, protocol) = parse_handshake_client(
+            &mut buf_reader,
+            client_websocket_key,
+            &client_config.protocols,
+            None,
+        )
+        .await?;
+        Ok(RawConnection::new(
+            buf_reader,
+            write_half,
+            WriterKind::Client,
+            client_config.web_socket_config.frame_size_limit(),
+            protocol,
+        ))
+    })
+    .await
+}
+
+// Opens the TCP connection of the handshake request, directly or through a proxy,
+// wrapping it with TLS for wss URLs
+async fn open_stream(
+    request: &HandshakeRequest,
+    client_config: &ClientConfig,
+) -> std::result::Result<(SocketFlowStream, SocketAddrs), Error> {
+    let tcp_connect = async {
+        match client_config.proxy {
+            Some(ref proxy_url) => {
+                connect_through_proxy(proxy_url, &request.host_with_port, client_config.bind_to)
+                    .await
             }
-        } else {
-            // Here we are adding TLS_SERVER_ROOTS to the certificate store,
-            // which is basically a reference to a list of trusted root certificates
-            // issue by a CA.
-            // In the case, you are establishing a connection with a server
-            // that has a valid trusted certificate.
-            // You won't need a CA file
-            root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            None => Ok(connect_tcp(&request.host_with_port, client_config.bind_to).await?),
         }
+    };
+    let stream = match client_config.connect_timeout {
+        Some(connect_timeout) => timeout(connect_timeout, tcp_connect)
+            .await
+            .map_err(|_| Error::ConnectTimeout)??,
+        None => tcp_connect.await?,
+    };
+    // Set on the TCP stream itself, before it's wrapped by TLS.
+    // When connecting through a proxy, the peer is the proxy itself
+    stream.set_nodelay(client_config.web_socket_config.nodelay)?;
+    let addrs = SocketAddrs::from_tcp(&stream);
 
-        let config = rustls::ClientConfig::builder()
-            .with_root_certificates(root_cert_store)
-            .with_no_client_auth();
+    let maybe_tls = if request.use_tls {
+        let config = client_tls_config(client_config)?;
         let connector = TlsConnector::from(Arc::new(config));
 
-        let domain = pki_types::ServerName::try_from(host)?;
+        // The server name may be overridden, when the certificate name differs from the host
+        let server_name = client_config
+            .server_name
+            .clone()
+            .unwrap_or_else(|| request.host.clone());
+        let domain = pki_types::ServerName::try_from(server_name)?;
         let tls_stream = connector.connect(domain, stream).await?;
         SocketFlowStream::Secure(TlsStream::from(tls_stream))
     } else {
         SocketFlowStream::Plain(stream)
     };
+    Ok((maybe_tls, addrs))
+}
 
-    let (reader, mut write_half) = split(maybe_tls);
-    let mut buf_reader = BufReader::new(reader);
+/// Connects as a client to a websocket server listening on a Unix domain socket, like a sidecar.
+///
+/// The URL is only used for the `Host` header and the path of the handshake request, so a
+/// `wss` URL doesn't enable TLS, and the connection has no peer or local address.
+#[cfg(unix)]
+pub async fn connect_async_unix(path: impl AsRef<Path>, url: &str) -> Result {
+    connect_async_unix_with_config(path, url, None).await
+}
+
+/// Same as connect_async_unix, with an additional argument for custom websocket connection configurations.
+#[cfg(unix)]
+pub async fn connect_async_unix_with_config(
+    path: impl AsRef<Path>,
+    url: &str,
+    client_config: Option<ClientConfig>,
+) -> Result {
+    let client_config = client_config.unwrap_or_default();
+    with_handshake_timeout(client_config.web_socket_config.handshake_timeout, async {
+        let client_websocket_key = generate_websocket_key();
+        let request = construct_http_request(url, &client_websocket_key, &client_config)?;
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        client_handshake(stream, &request.raw, client_websocket_key, client_config).await
+    })
+    .await
+}
+
+// Sends the handshake request over an established stream, and creates the connection
+// after validating the server response
+async fn client_handshake<S>(
+    stream: S,
+    request: &str,
+    client_websocket_key: String,
+    client_config: ClientConfig,
+) -> Result
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // The same buffered reader is used for the handshake and the websocket frames, so
+    // any frame bytes read together with the response, remain buffered for the ReadStream
+    let (mut buf_reader, mut write_half) = split_stream(stream);
 
     write_half.write_all(request.as_bytes()).await?;
 
-    let mut config = client_config.unwrap_or_default().web_socket_config;
-    let extensions = parse_handshake_client(&mut buf_reader, client_websocket_key).await?;
+    let (extensions, protocol) = parse_handshake_client(
+        &mut buf_reader,
+        client_websocket_key,
+        &client_config.protocols,
+        client_config.web_socket_config.extensions.as_ref(),
+    )
+    .await?;
+    let mut config = client_config.web_socket_config;
     config.extensions = extensions;
+    #[cfg(feature = "tracing")]
+    record_handshake(&protocol, &config.extensions);
 
     let decoder_extensions = config.extensions.clone().unwrap_or_default();
-    // The decoder will be reading and decompressing all client messages,
-    // so we need to pass all the client extensions to it
+    // On the client side, it's the opposite of the server,
+    // the decoder will be reading and decompressing all server messages,
+    // so we need to pass all the server extensions to it
     let decoder = Decoder::new(
         decoder_extensions
-            .client_no_context_takeover
+            .server_no_context_takeover
             .unwrap_or_default(),
-        decoder_extensions.client_max_window_bits,
+        decoder_extensions.server_max_window_bits,
     );
 
+    // While the encoder compresses the client messages, following the client extensions
     let encoder_extensions = config.extensions.clone().unwrap_or_default();
     let encoder = Encoder::new(
         encoder_extensions
-            .server_no_context_takeover
+            .client_no_context_takeover
             .unwrap_or_default(),
-        encoder_extensions.server_max_window_bits,
+        encoder_extensions.client_max_window_bits,
     );
 
     second_stage_handshake(
@@ -226,77 +861,269 @@ pub async fn connect_async_with_config(addr: &str, client_config: Option<ClientC
         config,
         decoder,
         encoder,
+        protocol,
+        None,
     )
-        .await
+    .await
 }
 
 async fn parse_handshake_server(
-    buf_reader: &mut BufReader<ReadHalf<SocketFlowStream>>,
-    write_half: &mut WriteHalf<SocketFlowStream>,
-    server_extensions: Option<Extensions>,
-) -> std::result::Result<Option<Extensions>, Error> {
-    let mut req = HttpRequest::parse_http_request(buf_reader).await?;
+    buf_reader: &mut BoxedReader,
+    write_half: &mut BoxedWriter,
+    accept_config: &AcceptConfig,
+) -> std::result::Result<(Option<Extensions>, Option<String>, Option<ConnectionPermit>), Error> {
+    let req = match HttpRequest::parse_http_request(buf_reader).await {
+        Ok(req) => req,
+        // There is no point in responding, if the connection itself failed
+        Err(err @ Error::IOError { .. }) => return Err(err),
+        Err(err) => return bad_request(write_half, err).await,
+    };
+
+    match accept_request(&req, accept_config) {
+        Ok(accepted) => {
+            write_half
+                .write_all(accepted.response.as_bytes())
+                .await
+                .map_err(|source| Error::IOError { source })?;
+            write_half.flush().await?;
+            Ok((accepted.extensions, accepted.protocol, accepted.permit))
+        }
+        Err(rejection) => {
+            if let Some(response) = rejection.response {
+                write_half.write_all(response.as_bytes()).await?;
+                write_half.flush().await?;
+            }
+            Err(rejection.error)
+        }
+    }
+}
+
+// Validates the handshake request, negotiating the extensions and the subprotocol, and
+// building the 101 Switching Protocols response, without reading or writing anything,
+// so it's shared by the servers parsing the request, and by the HTTP frameworks
+fn accept_request(
+    req: &HttpRequest,
+    accept_config: &AcceptConfig,
+) -> std::result::Result<AcceptedRequest, UpgradeRejection> {
+    // Connections above the limit are rejected before anything else, shedding the load
+    let permit = match accept_config.connection_limiter {
+        Some(ref limiter) => match limiter.try_acquire() {
+            Some(permit) => Some(permit),
+            None => {
+                return Err(UpgradeRejection::new(
+                    "503 Service Unavailable",
+                    &[],
+                    "",
+                    Error::TooManyConnections,
+                ))
+            }
+        },
+        None => None,
+    };
 
     // Validate the WebSocket handshake
-    if !req.method.eq(HTTP_METHOD) {
-        return Err(Error::InvalidHTTPHandshake);
+    if !req.method.eq(HTTP_METHOD) || !req.version.eq(HTTP_VERSION) {
+        return Err(UpgradeRejection::bad_request(Error::InvalidHTTPHandshake));
     }
 
     if req.get_header_value(HOST).is_none() {
-        return Err(Error::NoHostHeaderPresent);
+        return Err(UpgradeRejection::bad_request(Error::NoHostHeaderPresent));
+    }
+
+    // Browsers send multiple tokens, like `Connection: keep-alive, Upgrade`, in any case
+    let upgrade = req.get_header_value(UPGRADE).unwrap_or_default();
+    if !has_token(&upgrade, "websocket") {
+        return Err(UpgradeRejection::bad_request(Error::NoUpgradeHeaderPresent));
     }
 
+    let connection = req.get_header_value(CONNECTION).unwrap_or_default();
+    if !has_token(&connection, "upgrade") {
+        return Err(UpgradeRejection::bad_request(
+            Error::NoConnectionHeaderPresent,
+        ));
+    }
+
+    // The accept value is computed over the key exactly as it was sent, so a malformed one
+    // is rejected, instead of answering with an accept value the client can't verify
     let sec_websocket_key = match req.get_header_value(SEC_WEBSOCKET_KEY) {
-        Some(key) => key.to_string(),
-        None => Err(Error::NoSecWebsocketKey)?,
+        Some(key) if is_valid_key(&key) => key,
+        Some(_) => return Err(UpgradeRejection::bad_request(Error::InvalidSecWebsocketKey)),
+        None => return Err(UpgradeRejection::bad_request(Error::NoSecWebsocketKey)),
+    };
+
+    // Clients offering another version are told which one is supported,
+    // through the Sec-WebSocket-Version header of a 426 Upgrade Required response
+    if req.get_header_value(SEC_WEBSOCKET_VERSION).as_deref() != Some(WEBSOCKET_VERSION) {
+        return Err(UpgradeRejection::new(
+            "426 Upgrade Required",
+            &[(SEC_WEBSOCKET_VERSION, WEBSOCKET_VERSION)],
+            "",
+            Error::UnsupportedWebsocketVersion,
+        ));
+    }
+
+    if !accept_config.allowed_origins.is_empty() {
+        let origin = req.get_header_value(ORIGIN).unwrap_or_default();
+        // Origins are compared case-insensitively, since scheme and host are case-insensitive
+        if !accept_config
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&origin))
+        {
+            return Err(UpgradeRejection::new(
+                "403 Forbidden",
+                &[],
+                "",
+                Error::OriginNotAllowed,
+            ));
+        }
+    }
+
+    // The end-user may reject the handshake, after inspecting the request
+    if let Some(ref callback) = accept_config.request_callback {
+        if let Err(reason) = callback(req) {
+            return Err(UpgradeRejection::new(
+                "403 Forbidden",
+                &[],
+                &reason,
+                Error::HandshakeRejected(reason.clone()),
+            ));
+        }
+    }
+
+    let handshake_response = match accept_config.handshake_callback {
+        Some(ref callback) => match callback(req) {
+            Ok(handshake_response) => handshake_response,
+            Err(error_response) => return Err(UpgradeRejection::reject(error_response)),
+        },
+        None => HandshakeResponse::default(),
     };
 
     let client_extensions = parse_extensions(
         req.get_header_value(SEC_WEBSOCKET_EXTENSIONS)
             .unwrap_or_default(),
     );
-    let agreed_extensions = merge_extensions(server_extensions, client_extensions);
+    // Offers with window sizes out of range were already declined, and the server ones
+    // were checked before the handshake, so the agreed sizes are in range
+    let agreed_extensions = merge_extensions(
+        accept_config.web_socket_config.extensions.clone(),
+        client_extensions,
+    );
+
+    // The subprotocols offered by the client are a comma-separated list, in the order of
+    // preference, and the server may select one of them, or none
+    let offered_protocols = req.get_header_value(SEC_WEBSOCKET_PROTOCOL);
+    let offered: Vec<&str> = offered_protocols
+        .iter()
+        .flat_map(|offered_protocols| offered_protocols.split(','))
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+        .collect();
+    let protocol = match (&offered_protocols, &accept_config.protocol_selector) {
+        _ if handshake_response.protocol.is_some() => handshake_response.protocol,
+        (Some(_), Some(selector)) => selector(&offered),
+        _ => None,
+    };
+    // Per RFC 6455, the server can only select one of the subprotocols offered by the client,
+    // which would fail the handshake otherwise, so it's reported as a server error
+    if let Some(ref protocol) = protocol {
+        if !offered.contains(&protocol.as_str()) {
+            return Err(UpgradeRejection::new(
+                "500 Internal Server Error",
+                &[],
+                "",
+                Error::InvalidSubprotocol,
+            ));
+        }
+    }
 
-    let accept_key = generate_websocket_accept_value(sec_websocket_key);
+    let accept_key = compute_accept_key(&sec_websocket_key);
 
     let mut response = HTTP_ACCEPT_RESPONSE.replace("{}", &accept_key);
+    if let Some(ref protocol) = protocol {
+        validate_header(SEC_WEBSOCKET_PROTOCOL, protocol)?;
+        response.push_str(&format!("{}: {}\r\n", SEC_WEBSOCKET_PROTOCOL, protocol));
+    }
+    // Validated as the other headers, so a header can't be injected through their values
+    for (name, value) in accept_config
+        .response_headers
+        .iter()
+        .chain(&handshake_response.headers)
+    {
+        validate_header(name, value)?;
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    // Also terminates the headers, with the final CRLF
     add_extension_headers(&mut response, agreed_extensions.clone());
 
-    write_half
-        .write_all(response.as_bytes())
-        .await
-        .map_err(|source| Error::IOError { source })?;
-    write_half.flush().await?;
-
-    Ok(agreed_extensions)
+    Ok(AcceptedRequest {
+        response,
+        extensions: agreed_extensions,
+        protocol,
+        permit,
+    })
 }
 
 async fn parse_handshake_client(
-    buf_reader: &mut BufReader<ReadHalf<SocketFlowStream>>,
+    buf_reader: &mut BoxedReader,
     client_websocket_key: String,
-) -> std::result::Result<Option<Extensions>, Error> {
-    let mut req = HttpRequest::parse_http_request(buf_reader).await?;
+    offered_protocols: &[String],
+    offered_extensions: Option<&Extensions>,
+) -> std::result::Result<(Option<Extensions>, Option<String>), Error> {
+    let req = HttpResponse::parse_http_response(buf_reader).await?;
 
-    let expected_accept_value = generate_websocket_accept_value(client_websocket_key);
+    // Redirects are reported with their location, so they can be followed by connect
+    if matches!(req.status, 301 | 302 | 303 | 307 | 308) {
+        if let Some(location) = req.get_header_value(LOCATION) {
+            return Err(Error::Redirect {
+                location,
+                response: req.snippet(),
+            });
+        }
+    }
 
-    // Some websockets server returns the SEC_WEBSOCKET_ACCEPT header, as lowercase.
-    // Therefore, we need to cover both cases, for the sake of having support, even though it's
-    // out of RFC standards
-    let sec_websocket_accept = if let Some(value) = req.get_header_value(SEC_WEBSOCKET_ACCEPT) {
-        value
-    } else {
-        req.get_header_value(SEC_WEBSOCKET_ACCEPT.to_lowercase().as_str())
-            .unwrap_or_default()
-    };
+    // Any other status than 101 means the server refused to upgrade the connection
+    if req.status != 101 {
+        return Err(Error::NoUpgrade {
+            response: req.snippet(),
+        });
+    }
 
-    if !sec_websocket_accept.contains(&expected_accept_value) {
-        return Err(Error::InvalidAcceptKey);
+    // Per WebSockets RFC, the response must confirm the upgrade through both headers,
+    // the same way the server checks the request
+    let upgrade = req.get_header_value(UPGRADE).unwrap_or_default();
+    let connection = req.get_header_value(CONNECTION).unwrap_or_default();
+    if !has_token(&upgrade, "websocket") || !has_token(&connection, "upgrade") {
+        return Err(Error::InvalidUpgradeResponse {
+            response: req.snippet(),
+        });
     }
 
-    let extensions = parse_extensions(
-        req.get_header_value(SEC_WEBSOCKET_EXTENSIONS)
+    // Some websockets server returns the SEC_WEBSOCKET_ACCEPT header, as lowercase, which is
+    // covered since header names are case-insensitive
+    let sec_websocket_accept = req
+        .get_header_value(SEC_WEBSOCKET_ACCEPT)
+        .unwrap_or_default();
+
+    if !verify_accept_key(&client_websocket_key, &sec_websocket_accept) {
+        return Err(Error::InvalidAcceptKey {
+            response: req.snippet(),
+        });
+    }
+
+    let extensions = parse_accepted_extensions(
+        &req.get_header_value(SEC_WEBSOCKET_EXTENSIONS)
             .unwrap_or_default(),
-    );
+        offered_extensions,
+    )?;
+
+    // The server can only select one of the subprotocols offered by the client
+    let protocol = req.get_header_value(SEC_WEBSOCKET_PROTOCOL);
+    if let Some(ref protocol) = protocol {
+        if !offered_protocols.contains(protocol) {
+            return Err(Error::InvalidSubprotocol);
+        }
+    }
 
-    Ok(extensions)
+    Ok((extensions, protocol))
 }
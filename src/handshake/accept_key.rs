@@ -0,0 +1,33 @@
+//! Computing and verifying the `Sec-WebSocket-Accept` value, which proves the server understood
+//! the websocket handshake, as defined in the
+//! [WebSocket Protocol RFC](https://datatracker.ietf.org/doc/html/rfc6455#section-4.2.2).
+//! It's useful for building a custom handshake layer on top of the library.
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+
+// GUID appended to the client key, defined by the RFC
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Computes the `Sec-WebSocket-Accept` value sent by the server, for the `Sec-WebSocket-Key`
+/// of the client request, which is the base64 encoded SHA-1 of the key followed by the GUID
+pub fn compute_accept_key(sec_websocket_key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(sec_websocket_key.as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    BASE64_STANDARD.encode(sha1.finalize())
+}
+
+/// Checks that a `Sec-WebSocket-Key` is well-formed, which per RFC is the base64 encoding
+/// of 16 bytes, always 24 characters long
+pub fn is_valid_key(sec_websocket_key: &str) -> bool {
+    sec_websocket_key.len() == 24
+        && BASE64_STANDARD
+            .decode(sec_websocket_key)
+            .is_ok_and(|key| key.len() == 16)
+}
+
+/// Verifies the `Sec-WebSocket-Accept` value received by the client, for the key it sent
+pub fn verify_accept_key(key: &str, received_accept: &str) -> bool {
+    received_accept.trim() == compute_accept_key(key)
+}
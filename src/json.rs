@@ -0,0 +1,34 @@
+use crate::error::Error;
+use crate::message::Message;
+use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+
+// Deserializes the payload of data messages, while control messages,
+// like pings and close frames, are skipped
+fn decode_json<T: DeserializeOwned>(message: Message) -> Option<Result<T, Error>> {
+    match message {
+        Message::Text(text) => Some(serde_json::from_str(&text).map_err(Error::from)),
+        Message::Binary(data) => Some(serde_json::from_slice(&data).map_err(Error::from)),
+        _ => None,
+    }
+}
+
+// Waits for the next data message of the stream, deserializing it.
+// Returns None when the connection is closed
+pub(crate) async fn next_json<S, T>(stream: &mut S) -> Option<Result<T, Error>>
+where
+    S: Stream<Item = Result<Message, Error>> + Unpin,
+    T: DeserializeOwned,
+{
+    while let Some(result) = stream.next().await {
+        match result {
+            Ok(message) => {
+                if let Some(value) = decode_json(message) {
+                    return Some(value);
+                }
+            }
+            Err(err) => return Some(Err(err)),
+        }
+    }
+    None
+}
@@ -0,0 +1,56 @@
+use crate::error::Error;
+use crate::frame::{Frame, OpCode};
+use crate::message::{CloseCode, CloseFrame, Message};
+use crate::write::Writer;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::WeakSender;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::{sleep, timeout};
+
+// Keeps the connection alive, by sending a ping every interval, which is useful for connections
+// behind load balancers or proxies that drop idle connections.
+// If the pong doesn't arrive within the pong timeout, the peer is considered dead. Therefore,
+// the read task is aborted, the end-user receives a PongTimeout error, and the connection is closed.
+// Since this task only holds a weak reference to the read channel, the end-user
+// stream still finishes as soon as the read task returns.
+pub(crate) fn spawn_keepalive(
+    interval: Duration,
+    pong_timeout: Duration,
+    writer: Arc<Mutex<Writer>>,
+    pong_notify: Arc<Notify>,
+    read_tx: WeakSender<Result<Message, Error>>,
+    read_task: AbortHandle,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            sleep(interval).await;
+            if read_task.is_finished() {
+                break;
+            }
+
+            let ping = Frame::new(true, OpCode::Ping, Vec::new(), false);
+            // If the ping can't be written, the read task will be the one reporting the error
+            if writer.lock().await.write_frame(ping, false).await.is_err() {
+                break;
+            }
+
+            if timeout(pong_timeout, pong_notify.notified()).await.is_err() {
+                // Upgrading the sender before aborting the read task, which holds the
+                // only strong reference to it
+                let read_tx = read_tx.upgrade();
+                read_task.abort();
+                writer.lock().await.set_close_reason(CloseFrame {
+                    code: CloseCode::Abnormal,
+                    reason: Error::PongTimeout.to_string(),
+                });
+                if let Some(read_tx) = read_tx {
+                    let _ = read_tx.send(Err(Error::PongTimeout)).await;
+                }
+                let _ = writer.lock().await.shutdown().await;
+                break;
+            }
+        }
+    })
+}
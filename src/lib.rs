@@ -8,6 +8,7 @@
 //! to implement the standards of [WebSocket Protocol RFC](https://datatracker.ietf.org/doc/html/rfc6455),
 //! performing handshakes, reading frames, parsing masks, handling opcodes and internal payload.
 //!
+pub mod broadcast;
 pub mod config;
 pub mod connection;
 mod decoder;
@@ -17,12 +18,24 @@ pub mod event;
 pub mod extensions;
 mod frame;
 pub mod handshake;
+#[cfg(feature = "serde")]
+mod json;
+mod keepalive;
+pub mod limiter;
+mod masking;
 pub mod message;
+mod proxy;
+pub mod raw;
 mod read;
+pub mod reconnect;
 mod request;
 pub mod server;
 pub mod split;
+mod state;
+pub mod stats;
 pub mod stream;
 mod tests;
+mod tls;
+pub mod tunnel;
 mod utils;
 mod write;
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Limits the number of concurrent connections accepted by a server.
+///
+/// The same limiter is shared by every accepted connection, through `AcceptConfig`.
+/// Each connection holds a permit until it's closed or dropped, and handshakes arriving when
+/// all permits are taken, are rejected with a 503 Service Unavailable.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimiter {
+    semaphore: Arc<Semaphore>,
+    max_connections: usize,
+}
+
+// Held by the read task of an accepted connection, which finishes when the connection is closed,
+// or is aborted when the connection is dropped, releasing the permit in both cases
+pub(crate) type ConnectionPermit = OwnedSemaphorePermit;
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_connections)),
+            max_connections,
+        }
+    }
+
+    /// The maximum number of concurrent connections
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// The number of connections currently holding a permit
+    pub fn active_connections(&self) -> usize {
+        self.max_connections - self.semaphore.available_permits()
+    }
+
+    // Takes a permit, if the maximum number of connections wasn't reached
+    pub(crate) fn try_acquire(&self) -> Option<ConnectionPermit> {
+        self.semaphore.clone().try_acquire_owned().ok()
+    }
+}
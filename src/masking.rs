@@ -0,0 +1,45 @@
+use rand::Rng;
+#[cfg(test)]
+use std::cell::Cell;
+
+#[cfg(test)]
+thread_local! {
+    // The masking key used by the clients of the current thread, when set by a test
+    static FIXED_MASK: Cell<Option<[u8; 4]>> = const { Cell::new(None) };
+}
+
+// Generates a new masking key, for each frame sent by a client.
+// Per WebSockets RFC, the key must be unpredictable, and never reused between frames,
+// so it's taken from the thread-local CSPRNG
+pub(crate) fn generate_mask() -> [u8; 4] {
+    #[cfg(test)]
+    if let Some(mask) = FIXED_MASK.get() {
+        return mask;
+    }
+    rand::rng().random()
+}
+
+// Makes the clients of the current thread mask every frame with the given key, or with random
+// keys again, for asserting the exact bytes of the frames. Only the tests can fix the key
+#[cfg(test)]
+pub(crate) fn set_fixed_mask(mask: Option<[u8; 4]>) {
+    FIXED_MASK.set(mask);
+}
+
+// Masks or unmasks the payload in place, since XOR is its own inverse.
+// Each byte is XOR'd with the corresponding byte (modulo 4) of the masking key.
+// The payload is processed eight bytes at a time, XOR'ing each word with the key repeated twice,
+// which keeps the key aligned with the payload, since eight is a multiple of four
+pub(crate) fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    let mask_word = u64::from_ne_bytes([
+        mask[0], mask[1], mask[2], mask[3], mask[0], mask[1], mask[2], mask[3],
+    ]);
+    let mut words = payload.chunks_exact_mut(8);
+    for word in &mut words {
+        let masked = u64::from_ne_bytes((&*word).try_into().unwrap()) ^ mask_word;
+        word.copy_from_slice(&masked.to_ne_bytes());
+    }
+    for (i, byte) in words.into_remainder().iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
@@ -1,35 +1,319 @@
 use crate::error::Error;
 use crate::frame::{Frame, OpCode};
+use bytes::Bytes;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Message {
     Text(String),
-    Binary(Vec<u8>),
+    /// Binary message, holding a shared buffer, so received payloads are handed out without
+    /// copying them, and the same payload can be sent to many connections, like when
+    /// broadcasting, without copying it for each one
+    Binary(Bytes),
+    /// Ping control frame, with its application data.
+    /// Incoming pings are only delivered if `forward_pings` is enabled in the config,
+    /// since they are automatically answered with a pong.
+    Ping(Vec<u8>),
+    /// Pong control frame, with its application data, which echoes the one of the ping
+    /// it answers, so both can be correlated.
+    /// Incoming pongs are only delivered if `forward_pongs` is enabled in the config.
+    Pong(Vec<u8>),
+    /// Close control frame, with the status code and the reason sent by the peer.
+    /// If the peer didn't send any status code, it's received as `CloseCode::NoStatus`.
+    /// When sending, both None and `CloseCode::NoStatus` result in a close frame without any
+    /// status code, since 1005 must not be sent
+    Close {
+        code: Option<CloseCode>,
+        reason: String,
+    },
+}
+
+/// Why a connection was closed, with its status code and reason.
+/// It's the close frame received from the peer, on a graceful close, or the status code sent
+/// when failing the connection, with the error as the reason. If the connection was lost without
+/// any close frame, like after an IO error, the code is `CloseCode::Abnormal`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+/// Status codes used in close frames, to indicate the reason of closing a connection,
+/// as defined in the [WebSocket Protocol RFC](https://datatracker.ietf.org/doc/html/rfc6455#section-7.4.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// 1000, the purpose for which the connection was established has been fulfilled
+    Normal,
+    /// 1001, an endpoint is going away, like a server going down, or a browser navigating away
+    GoingAway,
+    /// 1002, the endpoint is terminating the connection due to a protocol error
+    ProtocolError,
+    /// 1003, the endpoint received a type of data it can't accept
+    UnsupportedData,
+    /// 1005, reserved, indicates that no status code was present
+    NoStatus,
+    /// 1006, reserved, indicates that the connection was closed abnormally
+    Abnormal,
+    /// 1007, the endpoint received data inconsistent with the type of the message, like invalid UTF-8
+    InvalidPayload,
+    /// 1008, the endpoint received a message that violates its policy
+    PolicyViolation,
+    /// 1009, the endpoint received a message too big to process
+    MessageTooBig,
+    /// 1010, the client expected the server to negotiate one or more extensions
+    MandatoryExtension,
+    /// 1011, the server encountered an unexpected condition
+    InternalError,
+    /// 1012, the server is restarting
+    ServiceRestart,
+    /// 1013, the server is overloaded, and the client should try again later
+    TryAgainLater,
+    /// 1014, the server was acting as a gateway, and received an invalid response
+    BadGateway,
+    /// 1015, reserved, indicates a failure to perform the TLS handshake
+    TlsHandshake,
+    /// Any other code, like the ones registered by libraries/frameworks (3000-3999),
+    /// or for private use (4000-4999)
+    Other(u16),
+}
+
+impl CloseCode {
+    pub fn as_u16(&self) -> u16 {
+        match self {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::UnsupportedData => 1003,
+            CloseCode::NoStatus => 1005,
+            CloseCode::Abnormal => 1006,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError => 1011,
+            CloseCode::ServiceRestart => 1012,
+            CloseCode::TryAgainLater => 1013,
+            CloseCode::BadGateway => 1014,
+            CloseCode::TlsHandshake => 1015,
+            CloseCode::Other(code) => *code,
+        }
+    }
+
+    /// Whether the code can be sent in a close frame. Codes below 1000, the reserved ones
+    /// (1004, 1005, 1006 and 1015), the unregistered ones up to 2999, and the ones above 4999
+    /// aren't allowed
+    pub fn is_valid(&self) -> bool {
+        matches!(self.as_u16(), 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+
+    // Returns None for codes outside the range of valid close codes, 1000-4999
+    pub fn try_from_u16(code: u16) -> Option<Self> {
+        match code {
+            1000..=4999 => Some(CloseCode::from(code)),
+            _ => None,
+        }
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::UnsupportedData,
+            1005 => CloseCode::NoStatus,
+            1006 => CloseCode::Abnormal,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1010 => CloseCode::MandatoryExtension,
+            1011 => CloseCode::InternalError,
+            1012 => CloseCode::ServiceRestart,
+            1013 => CloseCode::TryAgainLater,
+            1014 => CloseCode::BadGateway,
+            1015 => CloseCode::TlsHandshake,
+            code => CloseCode::Other(code),
+        }
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::Binary(data.into())
+    }
+}
+
+impl From<Bytes> for Message {
+    fn from(data: Bytes) -> Self {
+        Message::Binary(data)
+    }
+}
+
+// The payload of a close frame is an optional 2-byte big-endian status code,
+// followed by an optional UTF-8 reason
+pub(crate) fn close_payload(code: CloseCode, reason: &str) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(2 + reason.len());
+    payload.extend_from_slice(&code.as_u16().to_be_bytes());
+    payload.extend_from_slice(reason.as_bytes());
+    payload
 }
 
 impl Message {
     // Converts a Frame into a Message variant
     pub fn from_frame(frame: Frame) -> Result<Self, Error> {
+        Self::from_frame_with(frame, true)
+    }
+
+    // Without the strict rules, close frames are accepted with any status code,
+    // even the ones that aren't allowed on the wire
+    pub(crate) fn from_frame_with(frame: Frame, strict: bool) -> Result<Self, Error> {
         match frame.opcode {
-            OpCode::Text => Ok(Message::Text(String::from_utf8(frame.payload)?)),
+            // According to WebSockets RFC, The text opcode MUST be encoded as UTF-8
+            OpCode::Text => Ok(Message::Text(
+                String::from_utf8(frame.payload.into()).map_err(|_| Error::InvalidUtf8)?,
+            )),
             OpCode::Binary => Ok(Message::Binary(frame.payload)),
+            OpCode::Ping => Ok(Message::Ping(frame.payload.into())),
+            OpCode::Pong => Ok(Message::Pong(frame.payload.into())),
+            // The payload of a close frame is either empty, or it starts with a 2 bytes
+            // status code, followed by an optional UTF-8 reason
+            OpCode::Close => {
+                match frame.payload.len() {
+                    0 => {
+                        return Ok(Message::Close {
+                            code: Some(CloseCode::NoStatus),
+                            reason: String::new(),
+                        })
+                    }
+                    1 => return Err(Error::InvalidClosePayload),
+                    _ => {}
+                }
+                let code =
+                    CloseCode::from(u16::from_be_bytes([frame.payload[0], frame.payload[1]]));
+                if strict && !code.is_valid() {
+                    return Err(Error::InvalidCloseCode);
+                }
+                Ok(Message::Close {
+                    code: Some(code),
+                    reason: String::from_utf8(frame.payload[2..].to_vec())
+                        .map_err(|_| Error::InvalidUtf8)?,
+                })
+            }
             _ => Err(Error::InvalidOpcode),
         }
     }
 
-    // Function to get the payload as binary (Vec<u8>)
-    pub fn as_binary(&self) -> Vec<u8> {
+    /// Creates a text message
+    pub fn text(text: impl Into<String>) -> Self {
+        Message::Text(text.into())
+    }
+
+    /// Creates a binary message.
+    /// A `Bytes` payload can be used with `Message::from`, or the Binary variant
+    pub fn binary(data: impl Into<Vec<u8>>) -> Self {
+        Message::Binary(data.into().into())
+    }
+
+    /// Creates a ping message, with its application data
+    pub fn ping(data: impl Into<Vec<u8>>) -> Self {
+        Message::Ping(data.into())
+    }
+
+    /// Creates a pong message, with its application data
+    pub fn pong(data: impl Into<Vec<u8>>) -> Self {
+        Message::Pong(data.into())
+    }
+
+    /// Creates a close message, with the status code and reason
+    pub fn close(code: CloseCode, reason: impl Into<String>) -> Self {
+        Message::Close {
+            code: Some(code),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(self, Message::Text(_))
+    }
+
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Message::Binary(_))
+    }
+
+    pub fn is_ping(&self) -> bool {
+        matches!(self, Message::Ping(_))
+    }
+
+    pub fn is_pong(&self) -> bool {
+        matches!(self, Message::Pong(_))
+    }
+
+    pub fn is_close(&self) -> bool {
+        matches!(self, Message::Close { .. })
+    }
+
+    /// Borrows the text, if it's a text message
+    pub fn as_text(&self) -> Option<&str> {
         match self {
-            Message::Text(text) => text.as_bytes().to_vec(),
-            Message::Binary(data) => data.clone(),
+            Message::Text(text) => Some(text),
+            _ => None,
         }
     }
 
-    // Function to get the payload as a String
-    pub fn as_text(&self) -> Result<String, Error> {
+    /// Borrows the data, if it's a binary message
+    pub fn as_binary(&self) -> Option<&[u8]> {
         match self {
-            Message::Text(text) => Ok(text.clone()),
-            Message::Binary(data) => Ok(String::from_utf8(data.clone())?),
+            Message::Binary(data) => Some(data.as_ref()),
+            _ => None,
         }
     }
+
+    /// Consumes the message, returning its payload, without copying it, unless a binary
+    /// payload is shared with other messages.
+    /// For close messages, it's the reason
+    pub fn into_data(self) -> Vec<u8> {
+        match self {
+            Message::Text(text) => text.into_bytes(),
+            Message::Binary(data) => data.into(),
+            Message::Ping(data) | Message::Pong(data) => data,
+            Message::Close { reason, .. } => reason.into_bytes(),
+        }
+    }
+
+    /// Consumes the message, returning its payload as `Bytes`, without copying it.
+    /// For close messages, it's the reason
+    pub fn into_bytes(self) -> Bytes {
+        match self {
+            Message::Binary(data) => data,
+            message => message.into_data().into(),
+        }
+    }
+
+    /// Size of the payload in bytes, regardless of the variant, without consuming it like
+    /// `into_data`. For close messages, it's the size of the reason
+    pub fn len(&self) -> usize {
+        match self {
+            Message::Text(text) => text.len(),
+            Message::Binary(data) => data.len(),
+            Message::Ping(data) | Message::Pong(data) => data.len(),
+            Message::Close { reason, .. } => reason.len(),
+        }
+    }
+
+    /// Returns true if the payload is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
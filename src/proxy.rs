@@ -0,0 +1,51 @@
+use crate::error::Error;
+use crate::request::HttpResponse;
+use crate::stream::connect_tcp;
+use crate::utils::basic_auth_value;
+use std::net::SocketAddr;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use url::Url;
+
+// Establishes a TCP connection with the proxy, asking it to open a tunnel to the server,
+// through the CONNECT method. Once the proxy replies with a successful status, the returned
+// stream is connected to the server, and can be used for the TLS and websocket handshakes
+pub(crate) async fn connect_through_proxy(
+    proxy_url: &str,
+    host_with_port: &str,
+    bind_to: Option<SocketAddr>,
+) -> Result<TcpStream, Error> {
+    let parsed_url = Url::parse(proxy_url)?;
+    if parsed_url.scheme() != "http" {
+        return Err(Error::InvalidSchemeURL);
+    }
+
+    let host = parsed_url.host_str().ok_or(Error::URLNoHost)?;
+    let port = parsed_url.port_or_known_default().ok_or(Error::URLNoPort)?;
+    let stream = connect_tcp((host, port), bind_to).await?;
+
+    let mut request = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n",
+        host_with_port, host_with_port
+    );
+    if let Some(credentials) = basic_auth_value(&parsed_url) {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+
+    let mut buf_reader = BufReader::new(stream);
+    buf_reader.get_mut().write_all(request.as_bytes()).await?;
+
+    let response = HttpResponse::parse_http_response(&mut buf_reader).await?;
+    if !(200..300).contains(&response.status) {
+        return Err(Error::ProxyConnectFailed(response.status));
+    }
+
+    // The server doesn't send anything before receiving the handshake request, so
+    // the proxy shouldn't send anything after its response, otherwise these bytes would be lost
+    if !buf_reader.buffer().is_empty() {
+        return Err(Error::HttpParseError);
+    }
+
+    Ok(buf_reader.into_inner())
+}
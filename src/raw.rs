@@ -0,0 +1,222 @@
+//! Low-level access to the frames of a websocket connection.
+//!
+//! A `RawConnection`, created by `accept_raw` or `connect_raw`, reads and writes single frames,
+//! exactly as they are, which is useful for proxies forwarding frames verbatim,
+//! or for experimenting with the protocol.
+//! Fragments aren't reassembled, control frames aren't replied, and nothing is validated,
+//! besides the frame size, like the RSV bits, the opcode, the masking, or the UTF-8 of text.
+//! Therefore, keeping the connection compliant with the WebSockets RFC is up to the end-user.
+//! Extensions are never negotiated, since payloads aren't compressed nor decompressed.
+use crate::error::Error;
+use crate::masking::{apply_mask, generate_mask};
+use crate::stream::{BoxedReader, BoxedWriter};
+use crate::write::WriterKind;
+use bytes::{Bytes, BytesMut};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// A single websocket frame, as read from or written into the socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrame {
+    /// Final fragment of a message
+    pub fin: bool,
+    /// The RSV1, RSV2 and RSV3 bits, as the three lowest bits, where RSV1 is `0b100`
+    pub rsv: u8,
+    /// The opcode, including the reserved ones, from `0x0` to `0xF`
+    pub opcode: u8,
+    /// The masking key of the frame. Received frames carry the key they were masked with,
+    /// while their payload is already unmasked.
+    /// When sending, the given key is used, otherwise clients mask the frame with a fresh key,
+    /// and servers don't mask it
+    pub mask: Option<[u8; 4]>,
+    /// The unmasked payload
+    pub payload: Bytes,
+}
+
+impl RawFrame {
+    /// Creates a final frame, without RSV bits and masking key
+    pub fn new(opcode: u8, payload: impl Into<Bytes>) -> Self {
+        Self {
+            fin: true,
+            rsv: 0,
+            opcode,
+            mask: None,
+            payload: payload.into(),
+        }
+    }
+}
+
+/// A websocket connection reading and writing single frames, check the module documentation
+pub struct RawConnection {
+    reader: RawReader,
+    writer: RawWriter,
+    protocol: Option<String>,
+}
+
+impl RawConnection {
+    pub(crate) fn new(
+        buf_reader: BoxedReader,
+        write_half: BoxedWriter,
+        kind: WriterKind,
+        max_frame_size: usize,
+        protocol: Option<String>,
+    ) -> Self {
+        Self {
+            reader: RawReader {
+                buf_reader,
+                max_frame_size,
+            },
+            writer: RawWriter { write_half, kind },
+            protocol,
+        }
+    }
+
+    /// The subprotocol agreed between client and server during the handshake, if any
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Reads the next frame, returning `None` if the peer closed the connection between frames
+    pub async fn read_frame(&mut self) -> Result<Option<RawFrame>, Error> {
+        self.reader.read_frame().await
+    }
+
+    /// Writes the frame exactly as it's given, only masking it as described in `RawFrame::mask`
+    pub async fn send_frame(&mut self, frame: RawFrame) -> Result<(), Error> {
+        self.writer.send_frame(frame).await
+    }
+
+    /// Shuts down the write half, closing the connection, without sending a close frame
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        self.writer.shutdown().await
+    }
+
+    /// Splits the connection, so frames can be read and written from different tasks
+    pub fn split(self) -> (RawReader, RawWriter) {
+        (self.reader, self.writer)
+    }
+}
+
+/// The read half of a `RawConnection`
+pub struct RawReader {
+    buf_reader: BoxedReader,
+    // Frames are still limited in size, since their payload is allocated before reading it
+    max_frame_size: usize,
+}
+
+impl RawReader {
+    /// Reads the next frame, returning `None` if the peer closed the connection between frames
+    pub async fn read_frame(&mut self) -> Result<Option<RawFrame>, Error> {
+        let mut header = [0u8; 2];
+        if self.buf_reader.read(&mut header[..1]).await? == 0 {
+            return Ok(None);
+        }
+        self.buf_reader.read_exact(&mut header[1..]).await?;
+
+        let fin = header[0] & 0b1000_0000 != 0;
+        let rsv = (header[0] >> 4) & 0b111;
+        let opcode = header[0] & 0b0000_1111;
+        let masked = header[1] & 0b1000_0000 != 0;
+
+        let mut length = (header[1] & 0b0111_1111) as u64;
+        if length == 126 {
+            length = self.buf_reader.read_u16().await? as u64;
+        } else if length == 127 {
+            length = self.buf_reader.read_u64().await?;
+        }
+        let length = usize::try_from(length).unwrap_or(usize::MAX);
+        if length > self.max_frame_size {
+            return Err(Error::MaxFrameSize);
+        }
+
+        let mask = if masked {
+            let mut mask = [0u8; 4];
+            self.buf_reader.read_exact(&mut mask).await?;
+            Some(mask)
+        } else {
+            None
+        };
+
+        let mut payload = BytesMut::zeroed(length);
+        self.buf_reader.read_exact(&mut payload).await?;
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+
+        Ok(Some(RawFrame {
+            fin,
+            rsv,
+            opcode,
+            mask,
+            payload: payload.freeze(),
+        }))
+    }
+
+    /// Turns the reader into a stream of frames, which finishes when the connection is closed
+    pub fn into_stream(self) -> BoxStream<'static, Result<RawFrame, Error>> {
+        futures::stream::unfold(Some(self), |reader| async move {
+            let mut reader = reader?;
+            match reader.read_frame().await {
+                Ok(Some(frame)) => Some((Ok(frame), Some(reader))),
+                Ok(None) => None,
+                // The stream finishes after an error, since the connection can't be read anymore
+                Err(error) => Some((Err(error), None)),
+            }
+        })
+        .boxed()
+    }
+}
+
+/// The write half of a `RawConnection`
+pub struct RawWriter {
+    write_half: BoxedWriter,
+    kind: WriterKind,
+}
+
+impl RawWriter {
+    /// Writes the frame exactly as it's given, only masking it as described in `RawFrame::mask`
+    pub async fn send_frame(&mut self, frame: RawFrame) -> Result<(), Error> {
+        let mask = frame
+            .mask
+            .or_else(|| (self.kind == WriterKind::Client).then(generate_mask));
+
+        let first_byte = (frame.fin as u8) << 7 | (frame.rsv & 0b111) << 4 | (frame.opcode & 0x0F);
+        let mask_bit = if mask.is_some() { 0b1000_0000 } else { 0 };
+        let payload_len = frame.payload.len();
+
+        let mut header = Vec::with_capacity(14);
+        header.push(first_byte);
+        if payload_len <= 125 {
+            header.push(mask_bit | payload_len as u8);
+        } else if payload_len <= 65535 {
+            header.push(mask_bit | 126);
+            header.extend_from_slice(&(payload_len as u16).to_be_bytes());
+        } else {
+            header.push(mask_bit | 127);
+            header.extend_from_slice(&(payload_len as u64).to_be_bytes());
+        }
+
+        match mask {
+            Some(mask) => {
+                header.extend_from_slice(&mask);
+                let mut payload = Vec::from(frame.payload);
+                apply_mask(&mut payload, mask);
+                self.write_half.write_all(&header).await?;
+                self.write_half.write_all(&payload).await?;
+            }
+            None => {
+                self.write_half.write_all(&header).await?;
+                self.write_half.write_all(&frame.payload).await?;
+            }
+        }
+        self.write_half.flush().await?;
+        Ok(())
+    }
+
+    /// Shuts down the write half, closing the connection, without sending a close frame
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        self.write_half.shutdown().await?;
+        Ok(())
+    }
+}
@@ -2,48 +2,136 @@ use crate::config::WebSocketConfig;
 use crate::decoder::Decoder;
 use crate::error::Error;
 use crate::frame::{Frame, OpCode};
-use crate::message::Message;
-use crate::stream::SocketFlowStream;
-use crate::write::Writer;
-use bytes::BytesMut;
+use crate::masking::apply_mask;
+use crate::message::{close_payload, CloseCode, CloseFrame, Message};
+use crate::state::ConnectionState;
+use crate::stream::BoxedReader;
+use crate::write::{Writer, WriterKind};
+use bytes::{Bytes, BytesMut};
+use std::io::ErrorKind;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, BufReader, ReadHalf};
+use tokio::io::AsyncReadExt;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
-use tokio::time::{timeout, Duration};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{timeout, Duration, Instant};
+
+// Initial capacity of the buffers reused for reading frames, and reassembling fragmented messages
+const READ_BUFFER_CAPACITY: usize = 8 * 1024;
+// Window used for counting the pings received, against max_pings_per_second
+const PING_WINDOW: Duration = Duration::from_secs(1);
 
-#[derive(Clone)]
 pub(crate) struct FragmentedMessage {
-    fragments: Vec<u8>,
+    fragments: BytesMut,
     op_code: OpCode,
     compressed: bool,
+    // Number of bytes from fragments, already validated as UTF-8
+    utf8_validated: usize,
+}
+
+impl FragmentedMessage {
+    fn new(op_code: OpCode, fragments: BytesMut, compressed: bool) -> Result<Self, Error> {
+        let mut fragmented_message = Self {
+            fragments,
+            op_code,
+            compressed,
+            utf8_validated: 0,
+        };
+        fragmented_message.validate_utf8()?;
+        Ok(fragmented_message)
+    }
+
+    fn push(&mut self, fragment: &[u8]) -> Result<(), Error> {
+        self.fragments.extend_from_slice(fragment);
+        self.validate_utf8()
+    }
+
+    // Text messages are validated as the fragments arrive, so an invalid message fails
+    // as soon as possible. Since a multibyte character may be split between two fragments,
+    // an incomplete sequence at the end is accepted, and validated with the next fragment.
+    // Compressed messages can only be validated once they are completely decompressed
+    fn validate_utf8(&mut self) -> Result<(), Error> {
+        if self.op_code != OpCode::Text || self.compressed {
+            return Ok(());
+        }
+
+        match std::str::from_utf8(&self.fragments[self.utf8_validated..]) {
+            Ok(_) => self.utf8_validated = self.fragments.len(),
+            Err(err) if err.error_len().is_none() => self.utf8_validated += err.valid_up_to(),
+            Err(_) => return Err(Error::InvalidUtf8),
+        }
+        Ok(())
+    }
 }
 
 pub struct ReadStream {
-    buf_reader: BufReader<ReadHalf<SocketFlowStream>>,
+    buf_reader: BoxedReader,
+    // Frame payloads are read into this buffer, and split off from it when handed to a message,
+    // so consecutive small frames share the same allocation. Once the messages holding it are
+    // dropped, the allocation is reclaimed, instead of allocating a new one for each frame
+    read_buffer: BytesMut,
+    // Fragments are accumulated into this buffer, which is kept for the next fragmented
+    // message after the reassembled one is delivered
+    message_buffer: BytesMut,
     fragmented_message: Option<FragmentedMessage>,
     pub read_tx: Sender<Result<Message, Error>>,
     writer: Arc<Mutex<Writer>>,
+    // Whether this is the server or the client side of the connection
+    kind: WriterKind,
     config: WebSocketConfig,
     decoder: Decoder,
+    // Notified every time a pong is received, used by the keepalive task
+    pong_notify: Arc<Notify>,
+    state: Arc<ConnectionState>,
+    // Start of the current ping window, and the number of pings received within it
+    ping_window_start: Instant,
+    pings_in_window: usize,
+}
+
+// The connection ending in the middle of a frame, or being dropped abruptly, is reported as a reset
+fn connection_reset(error: Error) -> Error {
+    match error {
+        Error::IOError { source }
+            if matches!(
+                source.kind(),
+                ErrorKind::UnexpectedEof
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+            ) =>
+        {
+            Error::ConnectionReset
+        }
+        error => error,
+    }
 }
 
 impl ReadStream {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        read: BufReader<ReadHalf<SocketFlowStream>>,
+        read: BoxedReader,
         read_tx: Sender<Result<Message, Error>>,
         writer: Arc<Mutex<Writer>>,
+        kind: WriterKind,
         config: WebSocketConfig,
         decoder: Decoder,
+        pong_notify: Arc<Notify>,
+        state: Arc<ConnectionState>,
     ) -> Self {
         let fragmented_message = None;
         Self {
             buf_reader: read,
+            read_buffer: BytesMut::with_capacity(READ_BUFFER_CAPACITY),
+            message_buffer: BytesMut::new(),
             fragmented_message,
             read_tx,
             writer,
+            kind,
             config,
             decoder,
+            pong_notify,
+            state,
+            ping_window_start: Instant::now(),
+            pings_in_window: 0,
         }
     }
 
@@ -56,19 +144,43 @@ impl ReadStream {
     pub async fn poll_messages(&mut self) -> Result<(), Error> {
         // Now in websocket mode, read frames
         loop {
-            match self.read_frame().await {
+            // The timeout restarts for every frame, so it only expires when the peer goes
+            // silent, and the connection is closed normally, ending the stream
+            let result = match self.config.idle_timeout {
+                Some(idle_timeout) => match timeout(idle_timeout, self.read_frame()).await {
+                    Ok(result) => result,
+                    Err(_) => return self.close_idle().await,
+                },
+                None => self.read_frame().await,
+            };
+            match result {
                 Ok(frame) => {
+                    self.state.stats.frame_received(&frame);
+                    // Only the metadata is recorded, since payloads may contain sensitive data
+                    #[cfg(feature = "tracing")]
+                    tracing::trace!(
+                        opcode = ?frame.opcode,
+                        size = frame.payload.len(),
+                        final_fragment = frame.final_fragment,
+                        "frame received"
+                    );
                     match frame.opcode {
                         // By default, in order to start a fragmented message, the first frame should have a Text or Binary opcode,
                         // with a FIN bit set to 0
                         OpCode::Text | OpCode::Binary if !frame.final_fragment => {
                             // Starting a new fragmented message
                             if self.fragmented_message.is_none() {
-                                self.fragmented_message = Some(FragmentedMessage {
-                                    op_code: frame.opcode,
-                                    fragments: frame.payload,
-                                    compressed: frame.compressed,
-                                });
+                                // The buffer is only allocated once a fragmented message arrives
+                                let mut fragments = std::mem::take(&mut self.message_buffer);
+                                if fragments.capacity() == 0 {
+                                    fragments = BytesMut::with_capacity(READ_BUFFER_CAPACITY);
+                                }
+                                fragments.extend_from_slice(&frame.payload);
+                                self.fragmented_message = Some(FragmentedMessage::new(
+                                    frame.opcode,
+                                    fragments,
+                                    frame.compressed,
+                                )?);
                             } else {
                                 Err(Error::FragmentedInProgress)?
                             }
@@ -78,46 +190,47 @@ impl ReadStream {
                         // From the second frame to the last frame but one, the opcode should be set to continue (0x0),
                         // and the fin set to 0. The last frame should have the opcode set to continue and fin set to 1
                         OpCode::Continue => {
-                            if let Some(ref mut fragmented_message) = self.fragmented_message {
-                                fragmented_message
-                                    .fragments
-                                    .extend_from_slice(&frame.payload);
-
-                                if fragmented_message.fragments.len()
-                                    > self.config.max_message_size.unwrap_or_default()
-                                {
-                                    Err(Error::MaxMessageSize)?;
-                                }
-
-                                let mut fragmented_message_clone = fragmented_message.clone();
-                                // If it's the final fragment, then you can process the complete message here.
-                                // You could move the message to somewhere else as well.
-                                if frame.final_fragment {
-                                    // Clean the buffer after processing
-                                    self.fragmented_message = None;
-                                    if fragmented_message_clone.compressed {
-                                        fragmented_message_clone.fragments =
-                                            self.decoder.decompress(&mut BytesMut::from(
-                                                &fragmented_message_clone.fragments[..],
-                                            ))?;
-                                    }
-
-                                    // Since a clone copies the entire reference to a new reference,
-                                    // if you change the original data,
-                                    // the copy won't be modified
-                                    // and this copy variable will be dropped
-                                    // when the scope of this
-                                    // match ends
-                                    self.transmit_message(Frame::new(
-                                        true,
-                                        fragmented_message_clone.op_code,
-                                        fragmented_message_clone.fragments,
-                                        false,
-                                    ))
-                                    .await?;
-                                }
-                            } else {
+                            let Some(ref mut fragmented_message) = self.fragmented_message else {
                                 Err(Error::InvalidContinuationFrame)?
+                            };
+
+                            // Checking the size before appending the fragment,
+                            // so an oversized message is never buffered
+                            if fragmented_message.fragments.len() + frame.payload.len()
+                                > self.config.message_size_limit()
+                            {
+                                Err(Error::MaxMessageSize)?;
+                            }
+
+                            fragmented_message.push(&frame.payload)?;
+
+                            // If it's the final fragment, then you can process the complete message here.
+                            // Taking the message out of the buffer, also cleans it for the next
+                            // fragmented message
+                            if let Some(fragmented_message) =
+                                self.fragmented_message.take_if(|_| frame.final_fragment)
+                            {
+                                let mut fragments = fragmented_message.fragments;
+                                let payload = if fragmented_message.compressed {
+                                    let payload = self.decoder.decompress(
+                                        &mut fragments,
+                                        self.config.message_size_limit(),
+                                    )?;
+                                    fragments.clear();
+                                    Bytes::from(payload)
+                                } else {
+                                    fragments.split().freeze()
+                                };
+                                // The emptied buffer keeps its remaining capacity
+                                self.message_buffer = fragments;
+
+                                self.transmit_message(Frame::new(
+                                    true,
+                                    fragmented_message.op_code,
+                                    payload,
+                                    false,
+                                ))
+                                .await?;
                             }
                         }
                         OpCode::Text | OpCode::Binary => {
@@ -133,42 +246,124 @@ impl ReadStream {
                         OpCode::Close => {
                             // Either if this is being used as a client or server, per websocket
                             // RFC, if we receive a close,
-                            // we need to respond with a close opcode.
-                            // If the close was initiated by this library, we still want to call
-                            // send_close_frame, to close all the tokio mpsc channels of this connection
+                            // we need to respond with a close opcode, echoing the status code.
+                            // If the close was initiated by this side, the received frame is
+                            // the reply, so there is nothing else to send.
+                            // The peer may drop the connection right after its close frame,
+                            // which is still a clean close, so a failed reply is ignored
+                            // An invalid payload fails the connection, instead of being echoed
+                            let message = Message::from_frame_with(frame, self.strict())?;
+                            let code = match message {
+                                Message::Close {
+                                    code: Some(code), ..
+                                } if code.is_valid() => code,
+                                _ => CloseCode::Normal,
+                            };
+                            if let Message::Close { code, reason } = &message {
+                                self.state.set_close_reason(CloseFrame {
+                                    code: code.unwrap_or(CloseCode::NoStatus),
+                                    reason: reason.clone(),
+                                });
+                            }
+                            let _ = self.send_close_frame(code).await;
+                            self.state.close_read();
 
-                            self.send_close_frame().await?;
+                            // The end-user is notified about the close, although it may
+                            // not be consuming the messages anymore
+                            let _ = self.read_tx.send(Ok(message)).await;
 
                             break;
                         }
                         OpCode::Ping => {
-                            self.send_pong_frame(frame.payload).await?;
+                            self.check_ping_rate()?;
+                            // Per WebSockets RFC, only the most recent ping needs a pong, so
+                            // when another ping is already buffered, this one isn't answered,
+                            // which avoids writing a pong for each ping of a flood
+                            if !self.ping_buffered() {
+                                self.send_pong_frame(frame.payload.clone()).await?;
+                            }
+                            if self.config.forward_pings {
+                                self.transmit_message(frame).await?;
+                            }
                         }
                         OpCode::Pong => {
-                            // handle Pong here or just absorb and do nothing
-                            // You could implement code to log these messages or perform other custom behavior
+                            self.pong_notify.notify_one();
+                            self.state.pong_received(&frame.payload);
+                            if self.config.forward_pongs {
+                                self.transmit_message(frame).await?;
+                            }
                         }
                     }
                 }
-                Err(error) => Err(error)?,
+                Err(error) => Err(connection_reset(error))?,
             }
         }
         Ok(())
     }
 
-    async fn send_pong_frame(&mut self, payload: Vec<u8>) -> Result<(), Error> {
+    async fn close_idle(&mut self) -> Result<(), Error> {
+        #[cfg(feature = "tracing")]
+        tracing::debug!("idle timeout expired");
+        self.state.set_close_reason(CloseFrame {
+            code: CloseCode::Normal,
+            reason: String::new(),
+        });
+        self.state.close_read();
+        let mut writer = self.writer.lock().await;
+        if !writer.close_sent() {
+            let payload = close_payload(CloseCode::Normal, "");
+            let _ = writer
+                .write_frame(Frame::new(true, OpCode::Close, payload, false), false)
+                .await;
+        }
+        let _ = writer.shutdown().await;
+        Ok(())
+    }
+
+    // Fails the connection if the peer sends more pings than allowed within the window
+    fn check_ping_rate(&mut self) -> Result<(), Error> {
+        let Some(max_pings) = self.config.max_pings_per_second else {
+            return Ok(());
+        };
+        if self.ping_window_start.elapsed() >= PING_WINDOW {
+            self.ping_window_start = Instant::now();
+            self.pings_in_window = 0;
+        }
+        self.pings_in_window += 1;
+        if self.pings_in_window > max_pings {
+            return Err(Error::PingFlood);
+        }
+        Ok(())
+    }
+
+    // Checks if the next frame, already buffered, is a ping
+    fn ping_buffered(&self) -> bool {
+        self.buf_reader
+            .buffer()
+            .first()
+            .is_some_and(|first_byte| first_byte & 0x0F == OpCode::Ping.as_u8())
+    }
+
+    async fn send_pong_frame(&mut self, payload: Bytes) -> Result<(), Error> {
+        let mut writer = self.writer.lock().await;
+        // After closing the connection, pings are still read while waiting for the close reply,
+        // but they can't be answered anymore
+        if writer.close_sent() {
+            return Ok(());
+        }
         let pong_frame = Frame::new(true, OpCode::Pong, payload, false);
-        self.writer
-            .lock()
-            .await
-            .write_frame(pong_frame, false)
-            .await
+        writer.write_frame(pong_frame, false).await
     }
 
     pub async fn read_frame(&mut self) -> Result<Frame, Error> {
         let mut header = [0u8; 2];
 
-        self.buf_reader.read_exact(&mut header).await?;
+        // A connection closed by the peer between two frames is reported differently from
+        // one that dropped in the middle of a frame, which is considered reset
+        if self.buf_reader.read(&mut header[..1]).await? == 0 {
+            return Err(Error::ConnectionClosed);
+        }
+        self.buf_reader.read_exact(&mut header[1..]).await?;
 
         // The first bit in the first byte in the frame tells us whether the current frame is the final fragment of a message
         // here we are getting the native binary 0b10000000 and doing a bitwise AND operation
@@ -185,15 +380,18 @@ impl ReadStream {
         let rsv2 = (header[0] & 0b00100000) != 0;
         let rsv3 = (header[0] & 0b00010000) != 0;
 
+        // permessage-deflate is the only supported extension, and it only uses RSV1, to mark
+        // the first frame of a compressed message. Therefore, it's never valid on control
+        // or continuation frames
+        let compression_negotiated = self
+            .config
+            .extensions
+            .as_ref()
+            .is_some_and(|extensions| extensions.permessage_deflate);
         if rsv2
             || rsv3
             || (rsv1
-                && !self
-                    .config
-                    .extensions
-                    .clone()
-                    .unwrap_or_default()
-                    .permessage_deflate)
+                && (!compression_negotiated || opcode.is_control() || opcode == OpCode::Continue))
         {
             return Err(Error::RSVNotZero);
         }
@@ -210,6 +408,16 @@ impl ReadStream {
         // it tells us if the payload is masked or not
         let masked = (header[1] & 0b10000000) != 0;
 
+        // Clients must mask every frame they send, while servers must never mask them.
+        // With the no-masking-check feature, unmasked client frames are accepted,
+        // for trusted networks where masking is already enforced before reaching the server
+        match self.kind {
+            #[cfg(not(feature = "no-masking-check"))]
+            WriterKind::Server if !masked => Err(Error::UnmaskedClientFrame)?,
+            WriterKind::Client if masked => Err(Error::MaskedServerFrame)?,
+            _ => {}
+        }
+
         // In the second byte of a WebSocket frame, the first bit is used to represent the
         // Mask bit - which we discussed before - and the next 7 bits are used to represent the
         // payload length, or the size of the data being sent in the frame.
@@ -220,20 +428,36 @@ impl ReadStream {
             Err(Error::ControlFramePayloadSize)?;
         }
 
+        // Lengths of 126 and 127 mean the actual length comes in the next 2 or 8 bytes,
+        // in network byte order. The minimal number of bytes must be used for encoding the length,
+        // unless the strict rules are disabled, and the most significant bit of the 8 bytes
+        // length must always be 0
         if length == 126 {
             let mut be_bytes = [0u8; 2];
             self.buf_reader.read_exact(&mut be_bytes).await?;
             length = u16::from_be_bytes(be_bytes) as usize;
+            if length < 126 && self.strict() {
+                Err(Error::InvalidPayloadLength)?;
+            }
         } else if length == 127 {
             let mut be_bytes = [0u8; 8];
             self.buf_reader.read_exact(&mut be_bytes).await?;
-            length = u64::from_be_bytes(be_bytes) as usize;
+            let extended_length = u64::from_be_bytes(be_bytes);
+            if extended_length >> 63 != 0 || (extended_length <= u16::MAX as u64 && self.strict()) {
+                Err(Error::InvalidPayloadLength)?;
+            }
+            // Lengths that don't fit usize can't fit the max frame size either
+            length = usize::try_from(extended_length).unwrap_or(usize::MAX);
         }
 
-        if length > self.config.max_frame_size.unwrap_or_default() {
+        if length > self.config.frame_size_limit() {
             Err(Error::MaxFrameSize)?;
         }
 
+        // A single frame can't be bigger than a whole message either, and
+        // this is checked before allocating the payload
+        self.check_message_size(length)?;
+
         // According to Websockets RFC, a client should always send masked frames,
         // while frames sent from server to a client are not masked
         let mask = if masked {
@@ -244,7 +468,10 @@ impl ReadStream {
             None
         };
 
-        let mut payload = vec![0u8; length];
+        // Reserving the payload in the read buffer, which reuses its allocation if there's
+        // enough capacity left, or if the messages holding it were already dropped
+        self.read_buffer.clear();
+        self.read_buffer.resize(length, 0);
 
         // Adding a timeout function from Tokio, to avoid malicious TCP connections, that passes through handshake
         // and starts to send invalid websockets frames to overload the socket
@@ -252,7 +479,7 @@ impl ReadStream {
         // first connection, to simulate a handshake, and start sending huge payloads.
         let read_result = timeout(
             Duration::from_secs(5),
-            self.buf_reader.read_exact(&mut payload),
+            self.buf_reader.read_exact(&mut self.read_buffer),
         )
         .await;
         match read_result {
@@ -270,16 +497,20 @@ impl ReadStream {
         // is XOR'd with the corresponding byte (modulo 4) of the 4-byte mask. The server then uses
         // the masking key to reverse the process, recovering the original data.
         if let Some(mask) = mask {
-            for (i, byte) in payload.iter_mut().enumerate() {
-                *byte ^= mask[i % 4];
-            }
+            apply_mask(&mut self.read_buffer, mask);
         }
 
-        // println!("payload size: {}", payload.len());
-        if rsv1 && final_fragment {
-            payload = self.decoder.decompress(&mut BytesMut::from(&payload[..]))?;
-            // Call your custom decompression function
-        }
+        let mut payload = self.read_buffer.split();
+        let payload = if rsv1 && final_fragment {
+            // A small compressed payload may be inflated into a huge message,
+            // so the decompression stops as soon as it exceeds the max message size
+            let payload = self
+                .decoder
+                .decompress(&mut payload, self.config.message_size_limit())?;
+            Bytes::from(payload)
+        } else {
+            payload.freeze()
+        };
 
         Ok(Frame {
             final_fragment,
@@ -289,21 +520,76 @@ impl ReadStream {
         })
     }
 
-    pub async fn send_close_frame(&mut self) -> Result<(), Error> {
-        self.writer
-            .lock()
-            .await
-            .write_frame(Frame::new(true, OpCode::Close, Vec::new(), false), false)
-            .await
+    // Servers enforce the strict framing rules by default, while clients relax them
+    fn strict(&self) -> bool {
+        self.config
+            .strict
+            .unwrap_or(self.kind == WriterKind::Server)
     }
 
-    pub async fn transmit_message(&mut self, frame: Frame) -> Result<(), Error> {
-        // According to WebSockets RFC, The text opcode MUST be encoded as UTF-8
-        if frame.opcode == OpCode::Text {
-            let text_payload = frame.clone().payload;
-            _ = String::from_utf8(text_payload)?
+    fn check_message_size(&self, size: usize) -> Result<(), Error> {
+        if size > self.config.message_size_limit() {
+            return Err(Error::MaxMessageSize);
         }
+        Ok(())
+    }
 
+    // When the connection fails due to a protocol violation, per WebSockets RFC,
+    // a close frame with the matching status code should be sent before closing it.
+    // Other failures, like IO errors, are kept as an abnormal close, since no close frame is sent
+    pub async fn fail_connection(&mut self, error: &Error) {
+        // Kept before the read side is closed, so senders see the cause right away
+        self.state.fail_read(error);
+        let code = match error {
+            Error::MaxMessageSize | Error::MaxFrameSize => CloseCode::MessageTooBig,
+            Error::FragmentedInProgress
+            | Error::InvalidContinuationFrame
+            | Error::InvalidOpcode
+            | Error::InvalidFrameFragmentation
+            | Error::InvalidPayloadLength
+            | Error::InvalidClosePayload
+            | Error::InvalidCloseCode
+            | Error::RSVNotZero
+            | Error::ControlFramesFragmented
+            | Error::ControlFramePayloadSize
+            | Error::UnmaskedClientFrame
+            | Error::MaskedServerFrame
+            | Error::InvalidCompressedPayload => CloseCode::ProtocolError,
+            Error::InvalidUtf8 => CloseCode::InvalidPayload,
+            Error::PingFlood => CloseCode::PolicyViolation,
+            _ => CloseCode::Abnormal,
+        };
+        self.state.set_close_reason(CloseFrame {
+            code,
+            reason: error.to_string(),
+        });
+        if code == CloseCode::Abnormal {
+            return;
+        }
+
+        let mut writer = self.writer.lock().await;
+        if !writer.close_sent() {
+            let close_frame = Frame::new(true, OpCode::Close, close_payload(code, ""), false);
+            let _ = writer.write_frame(close_frame, false).await;
+        }
+    }
+
+    // Only the status code is echoed, since the reason is meant to the receiver of the close frame.
+    // Afterward, the close handshake is complete, so the write half is shut down
+    pub async fn send_close_frame(&mut self, code: CloseCode) -> Result<(), Error> {
+        let mut writer = self.writer.lock().await;
+        if writer.close_sent() {
+            return Ok(());
+        }
+        let payload = close_payload(code, "");
+        writer
+            .write_frame(Frame::new(true, OpCode::Close, payload, false), false)
+            .await?;
+        writer.shutdown().await
+    }
+
+    // Text payloads are validated as UTF-8 when converted into a Message
+    pub async fn transmit_message(&mut self, frame: Frame) -> Result<(), Error> {
         self.read_tx
             .send(Ok(Message::from_frame(frame)?))
             .await
@@ -319,5 +605,8 @@ impl ReadStream {
 impl Drop for ReadStream {
     fn drop(&mut self) {
         // No need to manually drop parts of our struct, Rust will take care of it automatically.
+        // Although, whoever is waiting for the read side to close needs to be notified, since
+        // the read task may finish due to an error, or being aborted
+        self.state.close_read();
     }
 }
@@ -0,0 +1,199 @@
+use crate::config::{ClientConfig, ReconnectConfig};
+use crate::connection::WSConnection;
+use crate::error::Error;
+use crate::handshake::connect_async_with_config;
+use crate::message::Message;
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::watch;
+use tokio::time::sleep;
+
+type ConnectFuture = Pin<Box<dyn Future<Output = Result<WSConnection, Error>> + Send>>;
+
+/// A client that re-establishes the connection whenever it drops, with the same `ClientConfig`,
+/// including its headers and subprotocols, waiting between attempts as set by `ReconnectConfig`.
+///
+/// It's used as a `WSConnection`, being a `Stream` of the incoming messages, and a `Sink`
+/// for sending them. Errors that end the connection aren't yielded, since the connection is
+/// reestablished instead, unless the retries are exhausted, where the last error is yielded,
+/// and the stream finishes. Messages sent while reconnecting wait for the new connection,
+/// and a connection found over when sending is reestablished too, even if the stream isn't polled.
+/// Every reconnection is notified through `reconnections`, so the end-user can re-subscribe
+/// to whatever the server needs.
+pub struct ReconnectingClient {
+    url: String,
+    config: ClientConfig,
+    reconnect_config: ReconnectConfig,
+    connection: Option<WSConnection>,
+    reconnecting: Option<ConnectFuture>,
+    reconnections: watch::Sender<u64>,
+    // Set when the end-user closes the client, or when it gives up reconnecting
+    closed: bool,
+}
+
+impl ReconnectingClient {
+    /// Connects to the server, returning an error if the first attempt fails
+    pub async fn connect(
+        url: &str,
+        config: ClientConfig,
+        reconnect_config: ReconnectConfig,
+    ) -> Result<Self, Error> {
+        let connection = connect_async_with_config(url, Some(config.clone())).await?;
+        Ok(Self {
+            url: url.to_string(),
+            config,
+            reconnect_config,
+            connection: Some(connection),
+            reconnecting: None,
+            reconnections: watch::Sender::new(0),
+            closed: false,
+        })
+    }
+
+    /// Returns a receiver of the number of reconnections so far, which changes every time
+    /// the connection is reestablished
+    pub fn reconnections(&self) -> watch::Receiver<u64> {
+        self.reconnections.subscribe()
+    }
+
+    /// Sends a message over the current connection, waiting for it to be reestablished first,
+    /// in the case it's reconnecting
+    pub async fn send_message(&mut self, message: Message) -> Result<(), Error> {
+        SinkExt::send(self, message).await
+    }
+
+    /// Closes the connection, without reconnecting anymore
+    pub async fn close(&mut self) -> Result<(), Error> {
+        self.closed = true;
+        self.reconnecting = None;
+        match self.connection.as_mut() {
+            Some(connection) => connection.close_connection().await,
+            None => Ok(()),
+        }
+    }
+
+    fn start_reconnecting(&mut self) {
+        self.connection = None;
+        let url = self.url.clone();
+        let config = self.config.clone();
+        let reconnect_config = self.reconnect_config.clone();
+        self.reconnecting = Some(Box::pin(async move {
+            let mut attempt = 0;
+            loop {
+                sleep(reconnect_config.delay(attempt)).await;
+                match connect_async_with_config(&url, Some(config.clone())).await {
+                    Ok(connection) => return Ok(connection),
+                    Err(err) => {
+                        attempt += 1;
+                        if reconnect_config
+                            .max_retries
+                            .is_some_and(|max| attempt >= max)
+                        {
+                            return Err(err);
+                        }
+                    }
+                }
+            }
+        }));
+    }
+
+    // Returns the current connection, driving the reconnection first, if there is one going on
+    fn poll_connection(&mut self, cx: &mut Context<'_>) -> Poll<Result<&mut WSConnection, Error>> {
+        if let Some(reconnecting) = self.reconnecting.as_mut() {
+            let result = ready!(reconnecting.as_mut().poll(cx));
+            self.reconnecting = None;
+            match result {
+                Ok(connection) => {
+                    self.connection = Some(connection);
+                    self.reconnections.send_modify(|count| *count += 1);
+                }
+                Err(err) => {
+                    self.closed = true;
+                    return Poll::Ready(Err(err));
+                }
+            }
+        }
+        match self.connection.as_mut() {
+            Some(connection) if !self.closed => Poll::Ready(Ok(connection)),
+            _ => Poll::Ready(Err(Error::AlreadyClosed)),
+        }
+    }
+}
+
+impl Stream for ReconnectingClient {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if this.closed {
+                return Poll::Ready(None);
+            }
+            let connection = match ready!(this.poll_connection(cx)) {
+                Ok(connection) => connection,
+                Err(err) => return Poll::Ready(Some(Err(err))),
+            };
+            match ready!(connection.poll_next_unpin(cx)) {
+                Some(Ok(message)) => return Poll::Ready(Some(Ok(message))),
+                // The connection is over, either after an error or a close frame, which is
+                // followed by the end of its stream
+                Some(Err(_)) | None => this.start_reconnecting(),
+            }
+        }
+    }
+}
+
+impl Sink<Message> for ReconnectingClient {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        loop {
+            let connection = ready!(this.poll_connection(cx))?;
+            match ready!(Pin::new(connection).poll_ready(cx)) {
+                Ok(()) => return Poll::Ready(Ok(())),
+                // The connection is over, which a client only sending messages finds out here,
+                // so the message waits for the new connection
+                Err(_) => this.start_reconnecting(),
+            }
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let result = match this.connection.as_mut() {
+            Some(connection) => Pin::new(connection).start_send(item),
+            None => Err(Error::AlreadyClosed),
+        };
+        if result.is_err() && !this.closed {
+            this.start_reconnecting();
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        let Some(connection) = this.connection.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let result = ready!(Pin::new(connection).poll_flush(cx));
+        // The messages being flushed are lost, but the following ones are sent over a new
+        // connection
+        if result.is_err() && !this.closed {
+            this.start_reconnecting();
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        this.closed = true;
+        this.reconnecting = None;
+        match this.connection.as_mut() {
+            Some(connection) => Pin::new(connection).poll_close(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
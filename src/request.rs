@@ -1,11 +1,38 @@
+use crate::config::ClientConfig;
 use crate::error::Error;
+use crate::extensions::{add_extension_headers, validate_window_bits};
+use crate::handshake::{SEC_WEBSOCKET_PROTOCOL, WEBSOCKET_VERSION};
+use crate::utils::basic_auth_value;
 use std::collections::HashMap;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader, ReadHalf};
-use tokio::time::{timeout, Duration};
-use url::Url;
-use crate::extensions::{add_extension_headers, Extensions};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use url::{Host, Url};
 
-const HTTP_REQUEST_DELIMITER: &str = "\r\n\r\n";
+const HTTP_REQUEST_DELIMITER: &[u8] = b"\r\n\r\n";
+// Maximum size of the HTTP handshake, including the body, which protects against peers
+// sending endless headers
+const MAX_HANDSHAKE_SIZE: usize = 16 * 1024;
+// Maximum number of headers in the HTTP handshake, well above the ones sent by browsers
+const MAX_HEADERS: usize = 128;
+// Maximum size of the server response carried by handshake errors
+const MAX_RESPONSE_SNIPPET: usize = 1024;
+
+// Holds the client handshake request, together with all the info needed for establishing
+// the connection with the server
+#[derive(Debug)]
+pub struct HandshakeRequest {
+    // The raw HTTP request, ready to be written into the socket
+    pub raw: String,
+    // Host and port used for establishing the TCP connection
+    pub host_with_port: String,
+    // Hostname without the port, used as the TLS server name
+    pub host: String,
+    // If the URL scheme is wss, the connection needs to be established over TLS
+    pub use_tls: bool,
+}
+
+const AUTHORIZATION: &str = "Authorization";
+const HOST: &str = "Host";
+const COOKIE: &str = "Cookie";
 
 // Function used for client connection, parsing the ws/wss URL to http, for constructing the
 // handshake request, which includes the sec-websockets-key, the URL path, scheme and another relevant
@@ -13,8 +40,8 @@ const HTTP_REQUEST_DELIMITER: &str = "\r\n\r\n";
 pub fn construct_http_request(
     ws_url: &str,
     key: &str,
-    extensions: Option<Extensions>
-) -> Result<(String, String, String, bool), Error> {
+    config: &ClientConfig,
+) -> Result<HandshakeRequest, Error> {
     let parsed_url = Url::parse(ws_url)?;
     let mut use_tls = false;
 
@@ -30,6 +57,8 @@ pub fn construct_http_request(
         _ => return Err(Error::InvalidSchemeURL),
     };
 
+    // For IPv6 addresses, the host is already enclosed in brackets, like [::1], which is the
+    // form used in the Host header, and in the TCP connection string
     let host = parsed_url.host_str().ok_or(Error::URLNoHost)?;
     // In the case ws_url is a domain instead of an IP, we need the HTTP port for using in the
     // TCP connection string
@@ -43,30 +72,183 @@ pub fn construct_http_request(
         None => String::from(host),
     };
 
+    // Overriding the Host header changes the host the request is meant for, which is also
+    // used as the TLS server name, while the connection is still established with the URL one
+    let (request_host_field, server_name) = match config.host_header {
+        Some(ref host_header) => {
+            validate_header(HOST, host_header)?;
+            let authority = Url::parse(&format!("ws://{}", host_header))
+                .ok()
+                .filter(|authority| {
+                    authority.path() == "/"
+                        && authority.username().is_empty()
+                        && authority.password().is_none()
+                        && authority.query().is_none()
+                        && authority.fragment().is_none()
+                })
+                .ok_or(Error::InvalidHeader)?;
+            (host_header.clone(), tls_server_name(&authority)?)
+        }
+        None => (request_host_field, tls_server_name(&parsed_url)?),
+    };
+
     // We need the port together with the host for establishing a TCP connection
     // regardless ws_url is an IP or domain
     let host_with_port = format!("{}:{}", host, port);
 
-    let request_path = match parsed_url.query() {
-        Some(query) => format!("{}?{}", parsed_url.path(), query),
-        None => parsed_url.path().to_string(),
-    };
+    let request_path = request_target(ws_url, &parsed_url);
 
     // Since we already have all the info, it isn't worth converting everything to a HTTP request type
     // and considering everything is bits into the TCP packets, we simply manipulate the string, and
     // convert it to bytes when sending to the server
     let mut request = format!(
-        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: {}\r\n",
         request_path,
         request_host_field,
         key,
+        websocket_version(config)?,
     );
 
-    add_extension_headers(&mut request, extensions);
+    if !config.protocols.is_empty() {
+        let protocols = config.protocols.join(", ");
+        validate_header(SEC_WEBSOCKET_PROTOCOL, &protocols)?;
+        request.push_str(&format!("{}: {}\r\n", SEC_WEBSOCKET_PROTOCOL, protocols));
+    }
+
+    // Credentials in the URL, like ws://user:password@host/, are sent as Basic auth,
+    // unless an Authorization header was explicitly set.
+    // They are never part of the Host header, which only has the host and port
+    let has_authorization = config
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case(AUTHORIZATION));
+    if let Some(credentials) = basic_auth_value(&parsed_url).filter(|_| !has_authorization) {
+        request.push_str(&format!("{}: {}\r\n", AUTHORIZATION, credentials));
+    }
+
+    for (name, value) in &config.headers {
+        validate_header(name, value)?;
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    if let Some(ref extensions) = config.web_socket_config.extensions {
+        validate_window_bits(extensions)?;
+    }
+    add_extension_headers(&mut request, config.web_socket_config.extensions.clone());
+
+    Ok(HandshakeRequest {
+        raw: request,
+        host_with_port,
+        host: server_name,
+        use_tls,
+    })
+}
+
+// The host of the URL, without the port, as used for the TLS server name
+fn tls_server_name(url: &Url) -> Result<String, Error> {
+    let host = url.host_str().ok_or(Error::URLNoHost)?;
+    // TLS server names don't accept brackets for IPv6 addresses
+    Ok(match url.host() {
+        Some(Host::Ipv6(address)) => address.to_string(),
+        _ => String::from(host),
+    })
+}
+
+// Resolves the location of a redirect against the URL of the redirected handshake, mapping http
+// and https locations to ws and wss. The Authorization and Cookie headers are only sent again
+// to the same host and port, since they may carry credentials that aren't meant for another one
+pub(crate) fn redirect_url(
+    url: &str,
+    location: &str,
+    config: &mut ClientConfig,
+) -> Result<String, Error> {
+    let current = Url::parse(url)?;
+    let mut next = current.join(location)?;
+    let scheme = match next.scheme() {
+        "http" => "ws",
+        "https" => "wss",
+        scheme => scheme,
+    }
+    .to_string();
+    // Changing between special schemes, like http and ws, never fails
+    let _ = next.set_scheme(&scheme);
+
+    if current.scheme() == "wss" && next.scheme() == "ws" && !config.allow_insecure_redirects {
+        return Err(Error::InsecureRedirect {
+            location: next.to_string(),
+        });
+    }
+
+    if next.host_str() != current.host_str()
+        || next.port_or_known_default() != current.port_or_known_default()
+    {
+        config.headers.retain(|(name, _)| {
+            !name.eq_ignore_ascii_case(AUTHORIZATION) && !name.eq_ignore_ascii_case(COOKIE)
+        });
+    }
+    Ok(next.to_string())
+}
+
+// Builds the request-target, which is the path and query of the URL, preserving them exactly as
+// they were supplied, like their percent-encoding and dot segments, since some servers match on
+// the raw path. Only if the supplied path has characters not allowed in a request-target,
+// like spaces or non-ASCII ones, the one normalized and percent-encoded by the URL parser is used.
+// An empty path is always sent as `/`, since some servers reject an empty request-target.
+fn request_target(ws_url: &str, parsed_url: &Url) -> String {
+    let raw_target = ws_url
+        .trim()
+        .split_once("://")
+        .map(|(_, rest)| {
+            // The authority ends at the first slash, question mark or hash,
+            // none of which are allowed in the userinfo, host or port
+            let rest = rest.split('#').next().unwrap_or_default();
+            let start = rest.find(['/', '?']).unwrap_or(rest.len());
+            &rest[start..]
+        })
+        // Backslashes are read as slashes by the URL parser, so the raw path can't be found
+        .filter(|_| !ws_url.contains('\\'))
+        .filter(|target| target.bytes().all(|byte| byte.is_ascii_graphic()));
+
+    match raw_target {
+        Some("") => String::from("/"),
+        Some(target) if target.starts_with('?') => format!("/{}", target),
+        Some(target) => target.to_string(),
+        None => match parsed_url.query() {
+            Some(query) => format!("{}?{}", parsed_url.path(), query),
+            None => parsed_url.path().to_string(),
+        },
+    }
+}
+
+// The version can only be overridden for testing, like checking how servers handle
+// unsupported versions
+#[cfg(feature = "testing")]
+fn websocket_version(config: &ClientConfig) -> Result<&str, Error> {
+    match config.websocket_version.as_deref() {
+        Some(version) => {
+            validate_header(crate::handshake::SEC_WEBSOCKET_VERSION, version)?;
+            Ok(version)
+        }
+        None => Ok(WEBSOCKET_VERSION),
+    }
+}
 
-    Ok((request, host_with_port, String::from(host), use_tls))
+#[cfg(not(feature = "testing"))]
+fn websocket_version(_config: &ClientConfig) -> Result<&str, Error> {
+    Ok(WEBSOCKET_VERSION)
 }
 
+// Since headers are written straight into the HTTP request/response, a CR or LF character
+// would allow injecting arbitrary headers, so these are rejected
+pub(crate) fn validate_header(name: &str, value: &str) -> Result<(), Error> {
+    let invalid_char = |c: char| c == '\r' || c == '\n';
+    if name.is_empty() || name.contains(invalid_char) || value.contains(invalid_char) {
+        return Err(Error::InvalidHeader);
+    }
+    Ok(())
+}
+
+/// The HTTP request sent by the client, for starting the handshake
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct HttpRequest {
@@ -77,57 +259,117 @@ pub struct HttpRequest {
     pub body: Vec<u8>,
 }
 
-impl HttpRequest {
-    pub async fn parse_http_request<T: AsyncReadExt + Unpin>(
-        reader: &mut BufReader<ReadHalf<T>>,
-    ) -> Result<HttpRequest, Error> {
-        let mut buffer = String::new();
-
-        // Adding a timeout to the buffer read, since some attackers may only connect to the TCP
-        // endpoint, and froze without sending the HTTP handshake.
-        // Therefore, we need to drop all these cases
-        timeout(Duration::from_secs(5), async {
-            // Read headers until we find the blank line (\r\n\r\n)
-            while let Ok(bytes_read) = reader.read_line(&mut buffer).await {
-                if bytes_read == 0 || buffer.ends_with(HTTP_REQUEST_DELIMITER) {
-                    break;
-                }
-            }
-        })
-        .await?;
+// Reads the head of an HTTP message, which are the start line and the headers,
+// until we find the blank line (\r\n\r\n). It's read as bytes, since nothing guarantees
+// the peer sent valid UTF-8, which is only checked once the head is parsed
+async fn read_http_head<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Result<Vec<u8>, Error> {
+    let mut buffer = Vec::new();
 
-        // Split the headers from the body
-        let (header_part, body_part) = match buffer.split_once("\r\n\r\n") {
-            Some(parts) => parts,
-            None => return Err(Error::HttpParseError),
-        };
+    // Some attackers may only connect to the TCP endpoint, and froze without sending the
+    // HTTP handshake, those cases are dropped by the handshake timeout, applied around the
+    // entire handshake
+    loop {
+        let remaining = MAX_HANDSHAKE_SIZE - buffer.len();
+        if remaining == 0 {
+            return Err(Error::HandshakeTooLarge);
+        }
+        // Limiting each read, so a single line can't exceed the handshake size either
+        let bytes_read = (&mut *reader)
+            .take(remaining as u64)
+            .read_until(b'\n', &mut buffer)
+            .await?;
+        if bytes_read == 0 || buffer.ends_with(HTTP_REQUEST_DELIMITER) {
+            return Ok(buffer);
+        }
+    }
+}
+
+// Room for all the headers of the head, since each one takes at least a line, up to MAX_HEADERS,
+// so httparse fails with TooManyHeaders past it
+fn header_slots(head: &[u8]) -> Vec<httparse::Header<'_>> {
+    let lines = head.iter().filter(|&&byte| byte == b'\n').count();
+    vec![httparse::EMPTY_HEADER; lines.min(MAX_HEADERS)]
+}
+
+// A head that httparse can't parse, or that ends before the blank line, is malformed
+fn check_parsed(parsed: httparse::Result<usize>) -> Result<(), Error> {
+    match parsed {
+        Ok(httparse::Status::Complete(_)) => Ok(()),
+        Err(httparse::Error::TooManyHeaders) => Err(Error::TooManyHeaders),
+        Ok(httparse::Status::Partial) | Err(_) => Err(Error::HttpParseError),
+    }
+}
 
-        // Parse the request line (e.g., "GET /path HTTP/1.1")
-        let mut lines = header_part.lines();
-        let request_line = lines.next().ok_or(Error::InvalidHTTPRequestLine)?;
-        let mut parts = request_line.split_whitespace();
-        let method = parts.next().ok_or(Error::MissingHTTPMethod)?.to_string();
-        let uri = parts.next().ok_or(Error::MissingHTTPUri)?.to_string();
-        let version = parts.next().ok_or(Error::MissingHTTPVersion)?.to_string();
-
-        // Parse headers
-        let mut headers = HashMap::new();
-        for line in lines {
-            if let Some((key, value)) = line.split_once(": ") {
-                headers.insert(key.to_string(), value.trim().to_string());
+// Header values are kept as strings, so a value that isn't valid UTF-8 makes the head malformed
+fn parse_headers(headers: &[httparse::Header<'_>]) -> Result<HashMap<String, String>, Error> {
+    let headers = headers
+        .iter()
+        .map(|header| {
+            let value = std::str::from_utf8(header.value).map_err(|_| Error::HttpParseError)?;
+            Ok((header.name, value))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    Ok(combine_headers(headers))
+}
+
+// Collects the header names and values, either parsed from the request, or already parsed
+// by an HTTP framework
+pub(crate) fn combine_headers<'a>(
+    headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> HashMap<String, String> {
+    let mut combined_headers: HashMap<String, String> = HashMap::new();
+    for (key, value) in headers {
+        // Only the optional whitespace around the value is trimmed, which per HTTP are
+        // spaces and tabs, so values like the websocket key are kept exactly as sent
+        let (key, value) = (key.trim(), value.trim_matches([' ', '\t']));
+        // Repeated headers are combined into a comma-separated list, per HTTP,
+        // like extensions offered through multiple Sec-WebSocket-Extensions headers
+        match combined_headers
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+        {
+            Some((_, combined)) => {
+                combined.push_str(", ");
+                combined.push_str(value);
+            }
+            None => {
+                combined_headers.insert(key.to_string(), value.to_string());
             }
         }
+    }
+    combined_headers
+}
+
+impl HttpRequest {
+    pub async fn parse_http_request<R: AsyncRead + Unpin>(
+        reader: &mut BufReader<R>,
+    ) -> Result<HttpRequest, Error> {
+        let head = read_http_head(reader).await?;
+
+        // Parse the request line (e.g., "GET /path HTTP/1.1"), and the headers
+        let mut headers = header_slots(&head);
+        let mut request = httparse::Request::new(&mut headers);
+        check_parsed(request.parse(&head))?;
+        let method = request.method.ok_or(Error::MissingHTTPMethod)?.to_string();
+        let uri = request.path.ok_or(Error::MissingHTTPUri)?.to_string();
+        let version = request.version.ok_or(Error::MissingHTTPVersion)?;
+        let version = format!("HTTP/1.{}", version);
+
+        let headers = parse_headers(request.headers)?;
 
         // Read the body based on Content-Length
-        let body = if let Some(content_length) = headers.get("Content-Length") {
+        let body = if let Some(content_length) = get_header_value(&headers, "Content-Length") {
             let length: usize = content_length
                 .parse()
                 .map_err(|_| Error::InvalidContentLength)?;
+            if head.len() + length > MAX_HANDSHAKE_SIZE {
+                return Err(Error::HandshakeTooLarge);
+            }
             let mut body_buf = vec![0; length];
             reader.read_exact(&mut body_buf).await?;
             body_buf
         } else {
-            body_part.as_bytes().to_vec() // No Content-Length, use existing body part
+            Vec::new()
         };
 
         Ok(HttpRequest {
@@ -139,7 +381,90 @@ impl HttpRequest {
         })
     }
 
-    pub fn get_header_value(&mut self, key: &str) -> Option<String> {
-        self.headers.get(key).cloned()
+    /// Returns the value of the header, where the header name is case-insensitive
+    pub fn get_header_value(&self, key: &str) -> Option<String> {
+        get_header_value(&self.headers, key)
+    }
+
+    /// Returns the path of the request URI, without the query string
+    pub fn path(&self) -> &str {
+        self.uri.split_once('?').map_or(&self.uri, |(path, _)| path)
+    }
+
+    /// Returns the query string of the request URI, if any
+    pub fn query(&self) -> Option<&str> {
+        self.uri.split_once('?').map(|(_, query)| query)
+    }
+}
+
+// HTTP header names are case-insensitive
+fn get_header_value(headers: &HashMap<String, String>, key: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(key))
+        .map(|(_, value)| value.clone())
+}
+
+// Checks if a comma-separated header value, like `Connection: keep-alive, Upgrade`,
+// contains the given token, which is compared case-insensitively
+pub(crate) fn has_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|item| item.trim().eq_ignore_ascii_case(token))
+}
+
+// The server response to the client handshake request.
+// Only the head is parsed, since all the bytes after it belong to the websocket connection
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct HttpResponse {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: HashMap<String, String>,
+    // The status line and headers as received, used for reporting failed handshakes
+    head: String,
+}
+
+impl HttpResponse {
+    pub async fn parse_http_response<R: AsyncRead + Unpin>(
+        reader: &mut BufReader<R>,
+    ) -> Result<HttpResponse, Error> {
+        let head = read_http_head(reader).await?;
+
+        // Parse the status line (e.g., "HTTP/1.1 101 Switching Protocols"), and the headers
+        let mut headers = header_slots(&head);
+        let mut response = httparse::Response::new(&mut headers);
+        check_parsed(response.parse(&head))?;
+        let version = response.version.ok_or(Error::InvalidHTTPStatusLine)?;
+        let status = response.code.ok_or(Error::InvalidHTTPStatusLine)?;
+        let reason = response.reason.unwrap_or_default().to_string();
+
+        let headers = parse_headers(response.headers)?;
+        Ok(HttpResponse {
+            version: format!("HTTP/1.{}", version),
+            status,
+            reason,
+            headers,
+            head: String::from_utf8_lossy(&head).into_owned(),
+        })
+    }
+
+    // Returns the status line and headers, limited to MAX_RESPONSE_SNIPPET bytes,
+    // so a huge response isn't carried in errors and logs
+    pub(crate) fn snippet(&self) -> String {
+        let head = self.head.trim_end();
+        if head.len() <= MAX_RESPONSE_SNIPPET {
+            return head.to_string();
+        }
+        let mut end = MAX_RESPONSE_SNIPPET;
+        while !head.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}...", &head[..end])
+    }
+
+    pub fn get_header_value(&self, key: &str) -> Option<String> {
+        get_header_value(&self.headers, key)
     }
 }
@@ -1,12 +1,11 @@
-use crate::config::ServerConfig;
+use crate::config::{AcceptConfig, ServerConfig};
 use crate::event::{generate_new_uuid, Event, EventStream};
-use crate::handshake::accept_async_with_config;
-use crate::stream::SocketFlowStream;
+use crate::handshake::{accept_async_tls_with, accept_async_with_config};
 use futures::StreamExt;
 use std::io::Error;
 use tokio::net::TcpListener;
 use tokio::sync::mpsc;
-use tokio_rustls::{TlsAcceptor, TlsStream};
+use tokio_rustls::TlsAcceptor;
 
 /// A ready to use websockets server
 ///
@@ -34,29 +33,22 @@ pub async fn start_server_with_config(
             let uuid = generate_new_uuid();
             match listener.accept().await {
                 Ok((stream, _)) => {
-                    let socket_stream = if let Some(config) = tls_config.clone() {
-                        let acceptor = TlsAcceptor::from(config);
-                        match acceptor.accept(stream).await {
-                            Ok(tls_stream) => SocketFlowStream::Secure(TlsStream::from(tls_stream)),
-                            Err(err) => {
-                                tx.send(Event::Error(uuid, err.into())).await.unwrap();
-                                continue;
-                            }
+                    let handshake = match tls_config.clone() {
+                        Some(config) => {
+                            let acceptor = TlsAcceptor::from(config);
+                            let accept_config = AcceptConfig::new()
+                                .web_socket_config(web_socket_config.clone().unwrap_or_default());
+                            accept_async_tls_with(stream, &acceptor, accept_config).await
+                        }
+                        None => accept_async_with_config(stream, web_socket_config.clone()).await,
+                    };
+                    let ws_connection = match handshake {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            tx.send(Event::Error(uuid, err)).await.unwrap();
+                            continue;
                         }
-                    } else {
-                        SocketFlowStream::Plain(stream)
                     };
-
-                    let ws_connection =
-                        match accept_async_with_config(socket_stream, web_socket_config.clone())
-                            .await
-                        {
-                            Ok(conn) => conn,
-                            Err(err) => {
-                                tx.send(Event::Error(uuid, err)).await.unwrap();
-                                continue;
-                            }
-                        };
                     // splitting the connection, so we could monitor incoming messages into a
                     // separate task, and handover the writer to the end-user
                     let (mut ws_reader, ws_writer) = ws_connection.split();
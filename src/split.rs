@@ -1,28 +1,92 @@
 use crate::config::WebSocketConfig;
-use crate::encoder::Encoder;
 use crate::error::Error;
 use crate::frame::{Frame, OpCode};
-use crate::message::Message;
+use crate::message::{close_payload, CloseCode, CloseFrame, Message};
+use crate::state::ConnectionState;
+use crate::stats::ConnectionStats;
 use crate::write::Writer;
-use bytes::BytesMut;
-use futures::Stream;
+use bytes::{Bytes, BytesMut};
+use futures::{ready, Sink, Stream, StreamExt};
+use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as SyncMutex, PoisonError};
 use std::task::{Context, Poll};
-use std::time::Duration;
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio::task::AbortHandle;
+use tokio::time::timeout;
 use tokio_stream::wrappers::ReceiverStream;
 
+// Payloads up to this size aren't compressed, since they can't get any smaller
 const PAYLOAD_SIZE_COMPRESSION_ENABLE: usize = 1;
+// Maximum time to wait for the peer to reply a close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Holds the background tasks of a connection, like the read task, and the keepalive timer.
+// Since it's shared between the reader and writer, once both are dropped, these tasks are
+// aborted, so they don't outlive the connection
+#[derive(Default)]
+pub(crate) struct TaskGuard {
+    tasks: Vec<AbortHandle>,
+}
+
+impl TaskGuard {
+    pub(crate) fn new(tasks: Vec<AbortHandle>) -> Self {
+        Self { tasks }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
 
 pub struct WSReader {
     read_rx: ReceiverStream<Result<Message, Error>>,
+    state: Arc<ConnectionState>,
+    _tasks: Arc<TaskGuard>,
 }
 
 impl WSReader {
-    pub fn new(read_rx: ReceiverStream<Result<Message, Error>>) -> Self {
-        Self { read_rx }
+    pub(crate) fn new(
+        read_rx: ReceiverStream<Result<Message, Error>>,
+        state: Arc<ConnectionState>,
+        tasks: Arc<TaskGuard>,
+    ) -> Self {
+        Self {
+            read_rx,
+            state,
+            _tasks: tasks,
+        }
+    }
+
+    /// Returns why the connection was closed, once the stream has finished, check `CloseFrame`.
+    /// It's None while the connection is open
+    pub fn close_reason(&self) -> Option<CloseFrame> {
+        self.state.close_reason()
+    }
+
+    /// Waits up to the given duration for the next message, returning ReadTimeout if none
+    /// arrives in time, and None when the connection is closed.
+    /// It's cancel-safe, so a message arriving after the timeout is kept for the next read
+    pub async fn recv_timeout(&mut self, duration: Duration) -> Result<Option<Message>, Error> {
+        match timeout(duration, self.read_rx.next()).await {
+            Ok(message) => message.transpose(),
+            Err(_) => Err(Error::ReadTimeout),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl WSReader {
+    /// Receives the next text or binary message, deserializing it from JSON.
+    /// Control messages are skipped, and None is returned when the connection is closed
+    pub async fn recv_json<T: serde::de::DeserializeOwned>(&mut self) -> Option<Result<T, Error>> {
+        crate::json::next_json(self).await
     }
 }
 
@@ -34,96 +98,382 @@ impl Stream for WSReader {
     }
 }
 
+/// Returned by `WSWriter::try_send`, when the message can't be sent right away
+#[derive(Debug)]
+pub enum TrySendError<T> {
+    /// The writer is busy with another message, so the given one is returned
+    Busy(T),
+    /// The connection is closed, so the given one is returned
+    Closed(T),
+    /// The message can't be sent, like a ping with a payload above 125 bytes
+    Invalid(Error),
+    /// Writing the message failed, which also fails the connection
+    Failed(Error),
+}
+
+impl<T> std::fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrySendError::Busy(_) => write!(f, "The writer is busy with another message"),
+            TrySendError::Closed(_) => write!(f, "Connection is already closed"),
+            TrySendError::Invalid(error) | TrySendError::Failed(error) => error.fmt(f),
+        }
+    }
+}
+
+impl<T: std::fmt::Debug> std::error::Error for TrySendError<T> {}
+
+type SinkFuture = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+
+// Holds the message being written through the Sink implementation, since the writer
+// is asynchronous, it can't be written directly in start_send
+#[derive(Default)]
+struct SinkState {
+    future: Option<SinkFuture>,
+    closed: bool,
+}
+
+/// The writer side of the connection. It can be cloned, so multiple tasks can send messages
+/// over the same connection, where each message is written entirely before the next one,
+/// including the messages streamed through a `FragmentedWriter`
 pub struct WSWriter {
-    pub writer: Arc<Mutex<Writer>>,
+    // Only reachable through the send methods, which hold the lock for a whole message,
+    // so frames written concurrently can't be interleaved with its fragments
+    pub(crate) writer: Arc<Mutex<Writer>>,
     pub web_socket_config: WebSocketConfig,
-    encoder: Encoder,
+    state: Arc<ConnectionState>,
+    // A sync mutex only used for keeping the writer Sync, it's never locked,
+    // since the Sink methods have exclusive access to it
+    sink: SyncMutex<SinkState>,
+    _tasks: Arc<TaskGuard>,
+}
+
+// Each clone has its own Sink state, since it refers to the messages sent by that clone only
+impl Clone for WSWriter {
+    fn clone(&self) -> Self {
+        Self {
+            writer: self.writer.clone(),
+            web_socket_config: self.web_socket_config.clone(),
+            state: self.state.clone(),
+            sink: SyncMutex::default(),
+            _tasks: self._tasks.clone(),
+        }
+    }
 }
 
 impl WSWriter {
-    pub fn new(
+    pub(crate) fn new(
         writer: Arc<Mutex<Writer>>,
         web_socket_config: WebSocketConfig,
-        encoder: Encoder,
+        state: Arc<ConnectionState>,
+        tasks: Arc<TaskGuard>,
     ) -> Self {
         Self {
             writer,
             web_socket_config,
-            encoder,
+            state,
+            sink: SyncMutex::default(),
+            _tasks: tasks,
         }
     }
 
+    /// Returns true once the connection is closed, either because a close frame was sent or
+    /// received, or because the connection failed. Afterward, sending returns `Error::AlreadyClosed`
+    pub fn is_closed(&self) -> bool {
+        self.state.is_closed()
+    }
+
+    /// Returns a snapshot of the messages and bytes sent and received over the connection
+    pub fn stats(&self) -> ConnectionStats {
+        self.state.stats.snapshot()
+    }
+
+    // If the read side failed, like the peer resetting the connection, its error is returned,
+    // so senders learn the cause, instead of a generic one
+    fn check_open(&self) -> Result<(), Error> {
+        if let Some(error) = self
+            .state
+            .read_error()
+            .or_else(|| self.state.background_write_error())
+        {
+            return Err(error);
+        }
+        if self.is_closed() || self.state.shutting_down() {
+            return Err(Error::AlreadyClosed);
+        }
+        Ok(())
+    }
+
     /// This function will be used for closing the connection between two instances, mainly it will
     /// be used by a client,
-    /// to request disconnection with a server.It first sends a close frame
-    /// through the socket, and waits until it receives the confirmation in a channel
-    /// executing it inside a timeout, to avoid a long waiting time
-    pub async fn close_connection(&mut self) -> Result<(), Error> {
-        self.write_frames(vec![Frame::new(true, OpCode::Close, Vec::new(), false)])
-            .await?;
+    /// to request disconnection with a server. It sends a close frame with a Normal status code,
+    /// check `close` for more details
+    pub async fn close_connection(&self) -> Result<(), Error> {
+        self.close(CloseCode::Normal, None).await
+    }
 
-        sleep(Duration::from_millis(500)).await;
+    /// Performs the close handshake, sending a close frame with the given status code and reason,
+    /// and waiting until the peer replies with its own close frame, executing it inside a timeout,
+    /// to avoid a long waiting time. Afterward, the write half of the connection is shut down.
+    /// The reason is limited to 123 bytes, since control frames payload can't exceed 125 bytes
+    pub async fn close(&self, code: CloseCode, reason: Option<String>) -> Result<(), Error> {
+        self.check_open()?;
+        self.close_handshake(code, reason).await
+    }
 
-        Ok(())
+    /// Gracefully shuts down the connection, which is useful for servers rolling out deploys.
+    /// New messages are rejected with `Error::AlreadyClosed`, while the ones already submitted,
+    /// either through the Sink, or by clones of this writer waiting to write, are written first.
+    /// Afterward, it performs the close handshake with a GoingAway status code, like `close`
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        if self.is_closed() || self.state.shutting_down() {
+            return Err(Error::AlreadyClosed);
+        }
+        futures::SinkExt::flush(self).await?;
+        self.state.start_shutdown();
+        // The writer lock is fair, so the clones already waiting for it write their
+        // messages before the close frame
+        self.writer.lock().await.flush().await?;
+        self.close_handshake(CloseCode::GoingAway, None).await
+    }
 
-        // match timeout(Duration::from_secs(CLOSE_TIMEOUT), self.write.lock().await.closed()).await {
-        //     Err(err) => Err(err)?,
-        //     _ => Ok(()),
-        // }
+    async fn close_handshake(&self, code: CloseCode, reason: Option<String>) -> Result<(), Error> {
+        if !code.is_valid() {
+            return Err(Error::InvalidCloseCode);
+        }
+        let payload = close_payload(code, reason.as_deref().unwrap_or_default());
+        if payload.len() > 125 {
+            return Err(Error::ControlFramePayloadSize);
+        }
+
+        // Another clone of the writer may have started the close handshake in the meantime,
+        // so there is no need to send it again
+        {
+            let mut writer = self.writer.lock().await;
+            if !writer.close_sent() {
+                let close_frame = Frame::new(true, OpCode::Close, payload, false);
+                writer.write_frame(close_frame, false).await?;
+            }
+        }
+
+        // If the peer doesn't reply in time, the connection is closed anyway
+        let _ = timeout(CLOSE_TIMEOUT, self.state.read_closed()).await;
+
+        self.writer.lock().await.shutdown().await
+    }
+
+    /// Sends a message without waiting, if the writer isn't busy with another message,
+    /// returning it back otherwise, with `TrySendError::Busy`, or `TrySendError::Closed`
+    /// if the connection is closed. It's meant for select loops and actors, which can't wait.
+    ///
+    /// The message is written right away, as much as the socket takes without waiting,
+    /// and a failure doing so is returned with `TrySendError::Failed`. If the socket can't
+    /// take all of it, the rest is written by a task, still holding the writer, so the message
+    /// is written before any other sent afterward. A failure writing the rest fails the
+    /// connection, and is returned by the following sends.
+    /// Messages given to the Sink, but not flushed yet, may still be written after it,
+    /// so the order is only kept between messages sent through the same path.
+    /// Outside a tokio runtime, a message which can't be written entirely right away
+    /// fails the connection, since nothing can finish writing it
+    pub fn try_send(&self, message: Message) -> Result<(), TrySendError<Message>> {
+        if self.check_open().is_err() {
+            return Err(TrySendError::Closed(message));
+        }
+        let Ok(mut writer) = self.writer.clone().try_lock_owned() else {
+            return Err(TrySendError::Busy(message));
+        };
+        // Converted while holding the writer, since it compresses with its context
+        let mut buffer = Vec::new();
+        self.convert_to_frames(Some(&mut writer), message)
+            .and_then(|frames| encode_frames(&writer, frames, &mut buffer))
+            .map_err(TrySendError::Invalid)?;
+        let (written, flushed) = writer.try_write_encoded(&buffer).map_err(|error| {
+            self.state.fail_write(&error);
+            TrySendError::Failed(error)
+        })?;
+        if flushed {
+            return Ok(());
+        }
+        let Ok(runtime) = Handle::try_current() else {
+            let error = Error::IOError {
+                source: std::io::Error::from(std::io::ErrorKind::WouldBlock),
+            };
+            self.state.fail_write(&error);
+            return Err(TrySendError::Failed(error));
+        };
+        let state = self.state.clone();
+        runtime.spawn(async move {
+            let result = async {
+                writer.write_encoded(&buffer[written..]).await?;
+                writer.flush().await
+            };
+            if let Err(error) = result.await {
+                state.fail_write(&error);
+            }
+        });
+        Ok(())
     }
 
-    pub async fn send_message(&mut self, message: Message) -> Result<(), Error> {
+    /// Sends a message, which is written entirely, including all of its fragments, before any
+    /// other message sent concurrently from a clone of this writer
+    pub async fn send_message(&self, message: Message) -> Result<(), Error> {
         self.write_message(message).await
     }
 
+    /// Sends a message without compressing it, even if permessage-deflate was negotiated,
+    /// which avoids wasting CPU on payloads that are already compressed, like images or gzip data
+    pub async fn send_uncompressed(&self, message: Message) -> Result<(), Error> {
+        self.check_open()?;
+        let mut writer = self.writer.lock().await;
+        self.write_locked_message(&mut writer, message, false).await
+    }
+
+    /// Sends all the messages in order, locking the writer once for the whole batch, and
+    /// encoding their frames into a single buffer, written into the socket at once, which is
+    /// cheaper than sending them one by one, when relaying bursts of small messages.
+    /// Since the payloads are copied into the buffer, large messages are better sent alone.
+    /// Messages sent concurrently from clones of this writer can't be interleaved with the batch.
+    /// If a message fails, the following ones aren't sent, and `Error::SendAllFailed` is returned,
+    /// with the number of messages written before the failure, and the error that caused it.
+    /// If writing the buffer fails, none of the messages is counted as written
+    pub async fn send_all(&self, messages: impl IntoIterator<Item = Message>) -> Result<(), Error> {
+        self.check_open()?;
+        let mut writer = self.writer.lock().await;
+        let mut buffer = Vec::new();
+        let mut written = 0;
+        let mut failure = None;
+        for message in messages {
+            let message_start = buffer.len();
+            let encoded = self
+                .convert_to_frames(Some(&mut writer), message)
+                .and_then(|frames| encode_frames(&writer, frames, &mut buffer));
+            if let Err(source) = encoded {
+                // The frames of the failed message, if any, aren't written
+                buffer.truncate(message_start);
+                failure = Some(source);
+                break;
+            }
+            written += 1;
+        }
+
+        let write = async {
+            writer.write_encoded(&buffer).await?;
+            writer.flush().await
+        };
+        if let Err(source) = write.await {
+            return Err(Error::SendAllFailed {
+                written: 0,
+                source: Box::new(source),
+            });
+        }
+        match failure {
+            Some(source) => Err(Error::SendAllFailed {
+                written,
+                source: Box::new(source),
+            }),
+            None => Ok(()),
+        }
+    }
+
     // This function will be used to send general data as a Vector of bytes, and by default will
     // be sent as a text opcode
-    pub async fn send(&mut self, data: Vec<u8>) -> Result<(), Error> {
+    pub async fn send(&self, data: Vec<u8>) -> Result<(), Error> {
         self.write_message(Message::Text(String::from_utf8(data)?))
             .await
     }
 
-    pub async fn send_as_binary(&mut self, data: Vec<u8>) -> Result<(), Error> {
-        self.write_message(Message::Binary(data)).await
+    // Sends a binary message, which also takes `Bytes`, so a shared payload isn't copied
+    pub async fn send_as_binary(&self, data: impl Into<Bytes>) -> Result<(), Error> {
+        self.write_message(Message::Binary(data.into())).await
     }
 
-    pub async fn send_as_text(&mut self, data: String) -> Result<(), Error> {
+    pub async fn send_as_text(&self, data: String) -> Result<(), Error> {
         self.write_message(Message::Text(data)).await
     }
 
+    /// Serializes the value as JSON, sending it as a text message
+    #[cfg(feature = "serde")]
+    pub async fn send_json<T: serde::Serialize + ?Sized>(&self, value: &T) -> Result<(), Error> {
+        self.send_as_text(serde_json::to_string(value)?).await
+    }
+
     // It will send a ping frame through the socket
-    pub async fn send_ping(&mut self) -> Result<(), Error> {
+    pub async fn send_ping(&self) -> Result<(), Error> {
         self.write_frames(vec![Frame::new(true, OpCode::Ping, Vec::new(), false)])
             .await
     }
 
+    /// Sends a ping with the given payload, which the peer echoes in its pong, so a custom
+    /// heartbeat can correlate them, when `forward_pongs` is enabled.
+    /// The payload is limited to 125 bytes, returning `Error::ControlFramePayloadSize` otherwise
+    pub async fn ping(&self, payload: impl Into<Vec<u8>>) -> Result<(), Error> {
+        self.write_message(Message::Ping(payload.into())).await
+    }
+
+    /// Sends a ping with a unique payload, and waits for the pong echoing it, returning the
+    /// round-trip time, or `Error::PongTimeout` if it doesn't arrive within the timeout.
+    /// Messages received in the meantime are still delivered to the reader, and the pong
+    /// is also delivered, as any other, if `forward_pongs` is enabled
+    pub async fn send_ping_and_wait_pong(&self, wait: Duration) -> Result<Duration, Error> {
+        let token = rand::random::<[u8; 8]>().to_vec();
+        // Registered before sending the ping, so a fast pong can't be missed
+        let pong_rx = self.state.wait_pong(token.clone());
+        let sent_at = Instant::now();
+        if let Err(error) = self.ping(token.clone()).await {
+            self.state.cancel_pong(&token);
+            return Err(error);
+        }
+
+        match timeout(wait, pong_rx).await {
+            Ok(Ok(received_at)) => Ok(received_at.saturating_duration_since(sent_at)),
+            // The connection was closed before the pong arrived
+            Ok(Err(_)) => Err(self.state.read_error().unwrap_or(Error::ConnectionClosed)),
+            Err(_) => {
+                self.state.cancel_pong(&token);
+                Err(Error::PongTimeout)
+            }
+        }
+    }
+
+    /// Sends an unsolicited pong, which works as a unidirectional heartbeat, since the peer
+    /// doesn't reply to it. The payload is limited to 125 bytes
+    pub async fn pong(&self, payload: impl Into<Vec<u8>>) -> Result<(), Error> {
+        self.write_message(Message::Pong(payload.into())).await
+    }
+
     // This function can be used to send large payloads, that will be divided in chunks using fragmented
     // messages, and Continue opcode
     pub async fn send_large_data_fragmented(
-        &mut self,
-        mut data: Vec<u8>,
+        &self,
+        data: Vec<u8>,
         fragment_size: usize,
     ) -> Result<(), Error> {
+        self.check_open()?;
         // Each fragment size will be limited by max_frame_size config,
         // that had been given by the user,
         // or it will use the default max frame size which is 16 MiB.
-        if fragment_size > self.web_socket_config.max_frame_size.unwrap_or_default() {
+        if fragment_size > self.web_socket_config.frame_size_limit() {
             return Err(Error::CustomFragmentSizeExceeded(
                 fragment_size,
-                self.web_socket_config.max_frame_size.unwrap_or_default(),
+                self.web_socket_config.frame_size_limit(),
             ));
         }
 
-        if data.len() > self.web_socket_config.max_message_size.unwrap_or_default() {
+        if data.len() > self.web_socket_config.message_size_limit() {
             return Err(Error::MaxMessageSize);
         }
 
+        // Holding the lock for the entire message, so the compression context, and the
+        // fragments aren't mixed with other messages
+        let mut writer = self.writer.lock().await;
+        let mut data = Bytes::from(data);
         // This function will check if compression is enabled, and apply if needed
-        let compressed = self.check_compression(&mut data)?;
+        let compressed = self.check_compression(&mut writer, &mut data)?;
 
         let chunks = data.chunks(fragment_size);
         let total_chunks = chunks.len();
 
+        let mut frames = Vec::with_capacity(total_chunks);
         for (i, chunk) in chunks.enumerate() {
             let is_final = i == total_chunks - 1;
             let opcode = if i == 0 {
@@ -132,21 +482,33 @@ impl WSWriter {
                 OpCode::Continue
             };
 
-            self.write_frames(vec![Frame::new(
-                is_final,
-                opcode,
-                Vec::from(chunk),
-                compressed,
-            )])
-            .await?
+            let chunk = data.slice_ref(chunk);
+            frames.push(Frame::new(is_final, opcode, chunk, compressed));
         }
 
-        Ok(())
+        write_frames(&mut writer, frames).await
     }
 
-    pub(crate) fn check_compression(&mut self, data: &mut Vec<u8>) -> Result<bool, Error> {
+    /// Starts a binary message, that will be sent as multiple fragments through the returned
+    /// `FragmentedWriter`, which is useful for streaming large payloads without buffering them
+    pub fn fragmented_binary(&mut self) -> FragmentedWriter<'_> {
+        FragmentedWriter::new(self, OpCode::Binary)
+    }
+
+    /// Starts a text message, that will be sent as multiple fragments through the returned
+    /// `FragmentedWriter`. The fragments don't need to be valid UTF-8 individually,
+    /// only the whole message
+    pub fn fragmented_text(&mut self) -> FragmentedWriter<'_> {
+        FragmentedWriter::new(self, OpCode::Text)
+    }
+
+    pub(crate) fn check_compression(
+        &self,
+        writer: &mut Writer,
+        data: &mut Bytes,
+    ) -> Result<bool, Error> {
         let mut compressed = false;
-        // If compression is enabled, and the payload is greater than 8KB, compress the payload
+        // If compression is enabled, every payload above PAYLOAD_SIZE_COMPRESSION_ENABLE is compressed
         if self
             .web_socket_config
             .extensions
@@ -155,24 +517,73 @@ impl WSWriter {
             .permessage_deflate
             && data.len() > PAYLOAD_SIZE_COMPRESSION_ENABLE
         {
-            *data = self.encoder.compress(&mut BytesMut::from(&data[..]))?;
+            let compressed_data = writer.encoder.compress(&mut BytesMut::from(&data[..]))?;
+            *data = Bytes::from(compressed_data);
             compressed = true;
         }
 
         Ok(compressed)
     }
 
-    pub(crate) fn convert_to_frames(&mut self, message: Message) -> Result<Vec<Frame>, Error> {
+    // Returns true if the message would be compressed when sent, making its frames specific
+    // to this connection, since they depend on its compression context
+    pub(crate) fn compresses(&self, message: &Message) -> bool {
+        self.web_socket_config
+            .extensions
+            .as_ref()
+            .is_some_and(|extensions| extensions.permessage_deflate)
+            && matches!(message, Message::Text(_) | Message::Binary(_))
+            && message.len() > PAYLOAD_SIZE_COMPRESSION_ENABLE
+    }
+
+    // Messages are only compressed if the writer is given, and compression was negotiated.
+    // Since each frame signals compression through RSV1, uncompressed messages don't
+    // affect the compression context
+    pub(crate) fn convert_to_frames(
+        &self,
+        writer: Option<&mut Writer>,
+        message: Message,
+    ) -> Result<Vec<Frame>, Error> {
+        if message.len() > self.web_socket_config.message_size_limit() {
+            return Err(Error::MaxMessageSize);
+        }
+
         let opcode = match message {
             Message::Text(_) => OpCode::Text,
             Message::Binary(_) => OpCode::Binary,
+            Message::Ping(_) => OpCode::Ping,
+            Message::Pong(_) => OpCode::Pong,
+            Message::Close { .. } => OpCode::Close,
         };
 
         let mut payload = match message {
-            Message::Text(text) => text.into_bytes(),
+            Message::Text(text) => Bytes::from(text.into_bytes()),
             Message::Binary(data) => data,
+            Message::Ping(data) | Message::Pong(data) => Bytes::from(data),
+            Message::Close {
+                code: None | Some(CloseCode::NoStatus),
+                ..
+            } => Bytes::new(),
+            Message::Close {
+                code: Some(code),
+                reason,
+            } => {
+                if !code.is_valid() {
+                    return Err(Error::InvalidCloseCode);
+                }
+                Bytes::from(close_payload(code, &reason))
+            }
         };
 
+        // Control frames can't be fragmented, neither compressed,
+        // and their payload is limited to 125 bytes
+        if opcode.is_control() {
+            if payload.len() > 125 {
+                return Err(Error::ControlFramePayloadSize);
+            }
+            return Ok(vec![Frame::new(true, opcode, payload, false)]);
+        }
+
         // Empty payloads aren't compressed
         if payload.is_empty() {
             return Ok(vec![Frame {
@@ -183,11 +594,15 @@ impl WSWriter {
             }]);
         }
 
-        let max_frame_size = self.web_socket_config.max_frame_size.unwrap_or_default();
+        let max_frame_size = self.write_frame_size();
         let mut frames = Vec::new();
         // This function will check if compression is enabled, and apply if needed
-        let compressed = self.check_compression(&mut payload)?;
+        let compressed = match writer {
+            Some(writer) => self.check_compression(writer, &mut payload)?,
+            None => false,
+        };
 
+        // The frames are slices of the same buffer, so the payload isn't copied
         for chunk in payload.chunks(max_frame_size) {
             frames.push(Frame {
                 final_fragment: false,
@@ -196,7 +611,7 @@ impl WSWriter {
                 } else {
                     OpCode::Continue
                 },
-                payload: chunk.to_vec(),
+                payload: payload.slice_ref(chunk),
                 compressed,
             });
         }
@@ -208,31 +623,217 @@ impl WSWriter {
         Ok(frames)
     }
 
-    pub(crate) async fn write_message(&mut self, message: Message) -> Result<(), Error> {
-        if message.as_binary().len() > self.web_socket_config.max_message_size.unwrap_or_default() {
-            return Err(Error::MaxMessageSize);
+    // Maximum payload size of the frames written, where a zero size
+    // wouldn't be able to split the payload, so it's ignored.
+    // Without any limit, messages are written in a single frame
+    pub(crate) fn write_frame_size(&self) -> usize {
+        self.web_socket_config
+            .max_write_frame_size
+            .filter(|size| *size > 0)
+            .or(self.web_socket_config.max_frame_size)
+            .filter(|size| *size > 0)
+            .unwrap_or(usize::MAX)
+    }
+
+    pub(crate) async fn write_message(&self, message: Message) -> Result<(), Error> {
+        self.check_open()?;
+        // Holding the lock while compressing and writing, so messages sent by different
+        // clones of the writer are written in the same order they were compressed
+        let mut writer = self.writer.lock().await;
+        self.write_locked_message(&mut writer, message, true).await
+    }
+
+    async fn write_locked_message(
+        &self,
+        writer: &mut Writer,
+        message: Message,
+        compress: bool,
+    ) -> Result<(), Error> {
+        let frames = self.convert_to_frames(compress.then_some(&mut *writer), message)?;
+        write_frames(writer, frames).await
+    }
+
+    pub(crate) async fn write_frames(&self, frames: Vec<Frame>) -> Result<(), Error> {
+        self.check_open()?;
+        write_frames(&mut *self.writer.lock().await, frames).await
+    }
+
+    fn sink_state(&mut self) -> &mut SinkState {
+        self.sink.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Drives the message currently being written by the Sink, if any
+    fn poll_sink_future(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let state = self.sink_state();
+        if let Some(future) = state.future.as_mut() {
+            let result = ready!(future.as_mut().poll(cx));
+            state.future = None;
+            return Poll::Ready(result);
         }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Implementing Sink allows the writer to be used with the SinkExt combinators, like send_all, or
+// being the target of StreamExt::forward. Each message is written and flushed into the socket,
+// before the next one is accepted.
+// Since the writer has its own send and send_all methods, the SinkExt ones need to be called
+// as SinkExt::send_all(&mut writer, stream).
+// Closing the Sink performs the close handshake, like close_connection
+impl Sink<Message> for WSWriter {
+    type Error = Error;
 
-        let frames = self.convert_to_frames(message)?;
-        self.write_frames(frames).await
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_sink_future(cx))?;
+        // A connection that is over is reported before taking the message, instead of once
+        // it's flushed, so the message isn't lost
+        Poll::Ready(this.check_open())
     }
 
-    pub(crate) async fn write_frames(&mut self, frames: Vec<Frame>) -> Result<(), Error> {
-        // For compressed messages, regardless if it's fragmented or not, we always set the RSV1 bit
-        // for the first frame.
-        let mut set_rsv1_first_frame = !frames.is_empty() && frames[0].compressed;
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let writer = this.clone();
+        this.sink_state().future = Some(Box::pin(async move {
+            writer.write_message(item).await?;
+            writer.writer.lock().await.flush().await
+        }));
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_sink_future(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_sink_future(cx))?;
+            if this.sink_state().closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            let writer = this.clone();
+            let state = this.sink_state();
+            state.closed = true;
+            // Closing the Sink of a connection that is already closed, like when the peer
+            // closed it first, has nothing left to do
+            state.future = Some(Box::pin(async move {
+                match writer.close_connection().await {
+                    Err(Error::AlreadyClosed) => Ok(()),
+                    result => result,
+                }
+            }));
+        }
+    }
+}
+
+async fn write_frames(writer: &mut Writer, frames: Vec<Frame>) -> Result<(), Error> {
+    // For compressed messages, regardless if it's fragmented or not, we always set the RSV1 bit
+    // for the first frame.
+    let mut set_rsv1_first_frame = !frames.is_empty() && frames[0].compressed;
+
+    for frame in frames {
+        writer.write_frame(frame, set_rsv1_first_frame).await?;
+        // Setting it to false,
+        // since we only need
+        // to set RSV1 bit for the first frame if compression is enabled
+        set_rsv1_first_frame = false;
+    }
+    Ok(())
+}
+
+// Same as write_frames, but encoding the frames into the buffer
+fn encode_frames(writer: &Writer, frames: Vec<Frame>, buffer: &mut Vec<u8>) -> Result<(), Error> {
+    let mut set_rsv1_first_frame = !frames.is_empty() && frames[0].compressed;
+    for frame in frames {
+        writer.encode_frame(frame, set_rsv1_first_frame, buffer)?;
+        set_rsv1_first_frame = false;
+    }
+    Ok(())
+}
+
+/// Writes a single message as a sequence of frames, where the first frame has the message opcode,
+/// the following ones a Continue opcode, and only the last one has the FIN bit set.
+/// Since each fragment is written as soon as it's given, fragmented messages aren't compressed.
+/// The writer is held from the first fragment until the message is finished, so no other frame
+/// can be interleaved with the fragments. Meanwhile, the messages sent from clones of the writer,
+/// the keepalive pings, and the replies to the pings and close frames of the peer, wait for it.
+/// If it's dropped without calling `finish`, the message is completed with an empty final fragment
+pub struct FragmentedWriter<'a> {
+    writer: &'a mut WSWriter,
+    // Taken when the first fragment is sent, and released once the last one is written
+    guard: Option<OwnedMutexGuard<Writer>>,
+    opcode: OpCode,
+    message_size: usize,
+}
+
+impl<'a> FragmentedWriter<'a> {
+    fn new(writer: &'a mut WSWriter, opcode: OpCode) -> Self {
+        Self {
+            writer,
+            guard: None,
+            opcode,
+            message_size: 0,
+        }
+    }
+
+    /// Sends a fragment of the message, which isn't the last one
+    pub async fn send(&mut self, fragment: Vec<u8>) -> Result<(), Error> {
+        self.write_fragment(fragment, false).await
+    }
+
+    /// Sends the last fragment of the message, completing it
+    pub async fn finish(mut self, fragment: Vec<u8>) -> Result<(), Error> {
+        self.write_fragment(fragment, true).await
+    }
+
+    async fn write_fragment(&mut self, fragment: Vec<u8>, is_final: bool) -> Result<(), Error> {
+        let max_frame_size = self.writer.web_socket_config.frame_size_limit();
+        if fragment.len() > max_frame_size {
+            return Err(Error::CustomFragmentSizeExceeded(
+                fragment.len(),
+                max_frame_size,
+            ));
+        }
+
+        self.message_size += fragment.len();
+        if self.message_size > self.writer.web_socket_config.message_size_limit() {
+            return Err(Error::MaxMessageSize);
+        }
 
-        for frame in frames {
-            self.writer
-                .lock()
-                .await
-                .write_frame(frame, set_rsv1_first_frame)
-                .await?;
-            // Setting it to false,
-            // since we only need
-            // to set RSV1 bit for the first frame if compression is enabled
-            set_rsv1_first_frame = false;
+        self.writer.check_open()?;
+        let writer = match self.guard {
+            Some(ref mut writer) => writer,
+            None => self
+                .guard
+                .insert(self.writer.writer.clone().lock_owned().await),
+        };
+        // After the first fragment, all the following ones are continuation frames
+        let opcode = std::mem::replace(&mut self.opcode, OpCode::Continue);
+        writer
+            .write_frame(Frame::new(is_final, opcode, fragment, false), false)
+            .await?;
+        if is_final {
+            self.guard = None;
         }
         Ok(())
     }
 }
+
+// An unfinished message would make the peer take the following messages as its fragments,
+// so it's completed in the background, still holding the writer
+impl Drop for FragmentedWriter<'_> {
+    fn drop(&mut self) {
+        let Some(mut writer) = self.guard.take() else {
+            return;
+        };
+        // Without a runtime, the connection can't be used anymore anyway
+        if let Ok(runtime) = Handle::try_current() {
+            runtime.spawn(async move {
+                let last_fragment = Frame::new(true, OpCode::Continue, Vec::new(), false);
+                let _ = writer.write_frame(last_fragment, false).await;
+            });
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::error::Error;
+use crate::message::CloseFrame;
+use crate::stats::StatsCounters;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+use tokio::sync::{oneshot, Notify};
+
+// State of a connection, shared between the read task and the writer side.
+// It allows the writer to follow what happens in the read task, like waiting for the
+// close reply of the peer, after a close frame has been sent.
+#[derive(Default)]
+pub(crate) struct ConnectionState {
+    read_closed: AtomicBool,
+    read_closed_notify: Notify,
+    close_sent: AtomicBool,
+    shutting_down: AtomicBool,
+    read_failure: Mutex<Option<Failure>>,
+    write_failure: Mutex<Option<Failure>>,
+    close_reason: Mutex<Option<CloseFrame>>,
+    // Pings waiting for the pong echoing their payload, notified with the time it was received
+    pending_pings: Mutex<Vec<(Vec<u8>, oneshot::Sender<Instant>)>>,
+    pub(crate) stats: StatsCounters,
+}
+
+// The cause of the read side failing, or of a write done in the background, kept so the writer
+// can report it to the senders, instead of only knowing that the connection is closed.
+// Since errors can't be cloned, only the connection failures are kept, which is enough
+// for rebuilding the error for every sender
+#[derive(Clone)]
+enum Failure {
+    Reset,
+    Closed,
+    WriteTimeout,
+    Io(io::ErrorKind, String),
+}
+
+impl Failure {
+    fn from_error(error: &Error) -> Option<Self> {
+        Some(match error {
+            Error::ConnectionReset => Failure::Reset,
+            Error::ConnectionClosed => Failure::Closed,
+            Error::WriteTimeout => Failure::WriteTimeout,
+            Error::IOError { source } => Failure::Io(source.kind(), source.to_string()),
+            _ => return None,
+        })
+    }
+
+    fn to_error(&self) -> Error {
+        match self {
+            Failure::Reset => Error::ConnectionReset,
+            Failure::Closed => Error::ConnectionClosed,
+            Failure::WriteTimeout => Error::WriteTimeout,
+            Failure::Io(kind, message) => Error::IOError {
+                source: io::Error::new(*kind, message.clone()),
+            },
+        }
+    }
+}
+
+impl ConnectionState {
+    // The connection is closed as soon as a close frame is sent, since no more frames can follow
+    // it, or when the read side is closed, like after receiving the close frame of the peer,
+    // or due to an IO error
+    pub(crate) fn is_closed(&self) -> bool {
+        self.close_sent() || self.read_closed.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn close_sent(&self) -> bool {
+        self.close_sent.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn mark_close_sent(&self) {
+        self.close_sent.store(true, Ordering::SeqCst);
+    }
+
+    // Set once a graceful shutdown starts, so no more messages are accepted,
+    // while the ones already submitted are still written
+    pub(crate) fn shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn start_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    // Keeps the error that made the read task fail, if it's a connection failure.
+    // Protocol errors aren't kept, since the connection is closed with a close frame,
+    // and further sends are reported as AlreadyClosed
+    pub(crate) fn fail_read(&self, error: &Error) {
+        if let Some(failure) = Failure::from_error(error) {
+            *self
+                .read_failure
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(failure);
+        }
+    }
+
+    // Returns the error that made the read task fail, if any
+    pub(crate) fn read_error(&self) -> Option<Error> {
+        self.read_failure
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .map(Failure::to_error)
+    }
+
+    // Keeps the error of a write that no sender is waiting for, like the rest of a message
+    // given to try_send, so the following sends report it. Since the frame may have been
+    // partially written, no more frames can follow it
+    pub(crate) fn fail_write(&self, error: &Error) {
+        self.mark_close_sent();
+        if let Some(failure) = Failure::from_error(error) {
+            self.write_failure
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_or_insert(failure);
+        }
+    }
+
+    // Returns the error of a failed background write, if any
+    pub(crate) fn background_write_error(&self) -> Option<Error> {
+        self.write_failure
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .map(Failure::to_error)
+    }
+
+    // A write failing due to the connection, while the read side has already detected
+    // the failure, is reported with the cause found by the read side
+    pub(crate) fn write_error(&self, error: Error) -> Error {
+        match error {
+            Error::IOError { .. } | Error::AlreadyClosed => self
+                .read_error()
+                .or_else(|| self.background_write_error())
+                .unwrap_or(error),
+            error => error,
+        }
+    }
+
+    // Keeps why the connection was closed, set by the read task before it finishes, so it's
+    // available once the stream ends. Only the first reason is kept
+    pub(crate) fn set_close_reason(&self, close_frame: CloseFrame) {
+        self.close_reason
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert(close_frame);
+    }
+
+    pub(crate) fn close_reason(&self) -> Option<CloseFrame> {
+        self.close_reason
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // Registers a ping, returning the receiver notified once its pong is received.
+    // Pings still waiting when the read side is closed are dropped, failing their receivers
+    pub(crate) fn wait_pong(&self, payload: Vec<u8>) -> oneshot::Receiver<Instant> {
+        let (pong_tx, pong_rx) = oneshot::channel();
+        self.pending_pings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((payload, pong_tx));
+        pong_rx
+    }
+
+    // Stops waiting for the pong of a ping, like after its timeout expired
+    pub(crate) fn cancel_pong(&self, payload: &[u8]) {
+        self.pending_pings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(pending, _)| pending != payload);
+    }
+
+    // Notifies the ping echoed by a received pong, if any is waiting for it
+    pub(crate) fn pong_received(&self, payload: &[u8]) {
+        let mut pending_pings = self
+            .pending_pings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(index) = pending_pings
+            .iter()
+            .position(|(pending, _)| pending == payload)
+        {
+            let (_, pong_tx) = pending_pings.swap_remove(index);
+            let _ = pong_tx.send(Instant::now());
+        }
+    }
+
+    // Marks the read side as closed, either because a close frame was received,
+    // or because the read task finished due to an error
+    pub(crate) fn close_read(&self) {
+        self.read_closed.store(true, Ordering::SeqCst);
+        self.read_closed_notify.notify_waiters();
+        self.pending_pings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    // Waits until the read side is closed
+    pub(crate) async fn read_closed(&self) {
+        loop {
+            // The notified future needs to be created before checking the flag,
+            // so a notification sent in between isn't missed
+            let notified = self.read_closed_notify.notified();
+            if self.read_closed.load(Ordering::SeqCst) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use crate::frame::Frame;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Snapshot of the traffic of a connection, returned by `WSConnection::stats`.
+/// Only the payloads are counted as bytes, without the frame headers, and when compression
+/// is enabled, it's the compressed size. Control frames, like pings and close frames,
+/// aren't counted as messages, neither their payloads as bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub control_frames_sent: u64,
+    pub control_frames_received: u64,
+}
+
+// Counters shared between the reader and the writer of a connection. Since they are only
+// read for taking a snapshot, relaxed ordering is enough
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    control_frames_sent: AtomicU64,
+    control_frames_received: AtomicU64,
+}
+
+impl StatsCounters {
+    pub(crate) fn frame_sent(&self, frame: &Frame) {
+        Self::count(
+            frame,
+            &self.messages_sent,
+            &self.bytes_sent,
+            &self.control_frames_sent,
+        );
+    }
+
+    pub(crate) fn frame_received(&self, frame: &Frame) {
+        Self::count(
+            frame,
+            &self.messages_received,
+            &self.bytes_received,
+            &self.control_frames_received,
+        );
+    }
+
+    // A message is counted on its final frame, so a fragmented message is counted only once
+    fn count(frame: &Frame, messages: &AtomicU64, bytes: &AtomicU64, control_frames: &AtomicU64) {
+        if frame.opcode.is_control() {
+            control_frames.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        bytes.fetch_add(frame.payload.len() as u64, Ordering::Relaxed);
+        if frame.final_fragment {
+            messages.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            control_frames_sent: self.control_frames_sent.load(Ordering::Relaxed),
+            control_frames_received: self.control_frames_received.load(Ordering::Relaxed),
+        }
+    }
+}
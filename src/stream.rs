@@ -1,15 +1,102 @@
+use std::any::Any;
+use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::net::TcpStream;
-use tokio_rustls::TlsStream as RustTlsStream;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadBuf};
+use tokio::net::{lookup_host, TcpSocket, TcpStream, ToSocketAddrs};
+use tokio_rustls::{client, server, TlsStream as RustTlsStream};
+
+// After splitting any stream given to the handshake functions, both halves are boxed,
+// so the reader, writer and the connection itself don't need to carry a generic around.
+// Therefore, any AsyncRead + AsyncWrite stream can be used (TCP, TLS, Unix sockets, or
+// even an in-memory duplex stream for testing)
+pub(crate) type BoxedReader = BufReader<Box<dyn AsyncRead + Send + Unpin>>;
+pub(crate) type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+// Addresses of the TCP connection underlying a websocket connection, if there is one
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SocketAddrs {
+    pub(crate) peer: Option<SocketAddr>,
+    pub(crate) local: Option<SocketAddr>,
+}
+
+impl SocketAddrs {
+    pub(crate) fn from_tcp(stream: &TcpStream) -> Self {
+        Self {
+            peer: stream.peer_addr().ok(),
+            local: stream.local_addr().ok(),
+        }
+    }
+
+    pub(crate) fn from_stream<S: Any>(stream: &S) -> Self {
+        tcp_stream(stream).map(Self::from_tcp).unwrap_or_default()
+    }
+}
+
+// Connects to the address, from the given local address, if any, like for using a specific
+// interface on multi-homed hosts. Only the resolved addresses of the same IP version as the local
+// address can be reached from it, and they are tried in order, until one of them connects
+pub(crate) async fn connect_tcp(
+    addr: impl ToSocketAddrs,
+    bind_to: Option<SocketAddr>,
+) -> io::Result<TcpStream> {
+    let Some(bind_to) = bind_to else {
+        return TcpStream::connect(addr).await;
+    };
+
+    let mut last_error = io::Error::new(
+        io::ErrorKind::AddrNotAvailable,
+        "no address of the same IP version as the bound address",
+    );
+    for addr in lookup_host(addr).await? {
+        if addr.is_ipv4() != bind_to.is_ipv4() {
+            continue;
+        }
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.bind(bind_to)?;
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+// Since the handshake functions accept any stream, the underlying TCP stream can only be
+// found for the TCP based streams that are known, including the TLS ones.
+// Other streams, like an in-memory one, don't have a TCP stream
+pub(crate) fn tcp_stream<S: Any>(stream: &S) -> Option<&TcpStream> {
+    let stream = stream as &dyn Any;
+    if let Some(tcp) = stream.downcast_ref::<TcpStream>() {
+        Some(tcp)
+    } else if let Some(stream) = stream.downcast_ref::<SocketFlowStream>() {
+        match stream {
+            SocketFlowStream::Plain(tcp) => Some(tcp),
+            SocketFlowStream::Secure(tls) => Some(tls.get_ref().0),
+        }
+    } else if let Some(tls) = stream.downcast_ref::<RustTlsStream<TcpStream>>() {
+        Some(tls.get_ref().0)
+    } else if let Some(tls) = stream.downcast_ref::<server::TlsStream<TcpStream>>() {
+        Some(tls.get_ref().0)
+    } else {
+        stream
+            .downcast_ref::<client::TlsStream<TcpStream>>()
+            .map(|tls| tls.get_ref().0)
+    }
+}
 
 // We need to implement AsyncRead and AsyncWrite for SocketFlowStream,
 // because when we split a TlsStream, it returns a ReadHalf<T>, WriteHalf<T>
 // where T: AsyncRead + AsyncWrite
-// This is a good solution, when you don't want to use a generic for your own functions
-// If we use a generic in accept_async(handshake.rs), we will need to add trait signatures to all the
-// functions that are called inside accept_async recursively.
+// This is a good solution, when you don't want to use a generic for your own functions,
+// for example, a client that may be either connected over plain TCP or TLS, and still
+// be handled as a single type.
+#[allow(clippy::large_enum_variant)]
 pub enum SocketFlowStream {
     Plain(TcpStream),
     Secure(RustTlsStream<TcpStream>),
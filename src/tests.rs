@@ -1,23 +1,35 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use crate::frame::{Frame, OpCode};
-    use crate::request::{construct_http_request, HttpRequest};
+    use crate::request::{construct_http_request, redirect_url, HttpRequest};
 
-    use crate::extensions::{add_extension_headers, Extensions};
-    use crate::handshake::{accept_async, accept_async_with_config, connect_async, connect_async_with_config, HTTP_ACCEPT_RESPONSE, SEC_WEBSOCKET_KEY};
+    use crate::extensions::{add_extension_headers, parse_extensions, DeflateParams, Extensions};
+    use crate::handshake::{accept_async, accept_async_from_parts, accept_async_tls, accept_async_tls_with, accept_async_with, accept_hdr_async, accept_raw, connect_raw, accept_async_with_config, accept_async_with_request, connect_async, connect_async_with_config, HTTP_ACCEPT_RESPONSE, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY};
     use crate::stream::SocketFlowStream;
-    use crate::utils::generate_websocket_accept_value;
+    use crate::handshake::accept_key::{compute_accept_key, is_valid_key, verify_accept_key};
     use futures::StreamExt;
     use std::error::Error;
-    use bytes::BytesMut;
+    use bytes::{Bytes, BytesMut};
     use rand::Rng;
-    use tokio::io::{split, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::io::{duplex, split, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::io::DuplexStream;
     use tokio::net::{TcpListener, TcpStream};
+    use crate::connection::WSConnection;
+    use crate::message::{CloseCode, CloseFrame, Message};
     use serde::Serialize;
-    use crate::config::{ClientConfig, WebSocketConfig};
+    use crate::config::{AcceptConfig, ClientConfig, ErrorResponse, HandshakeResponse, ReconnectConfig, WebSocketConfig};
+    use crate::reconnect::ReconnectingClient;
+    use crate::limiter::ConnectionLimiter;
+    use crate::broadcast::Broadcaster;
+    use crate::raw::RawFrame;
+    use crate::stats::ConnectionStats;
     use crate::decoder::Decoder;
     use crate::encoder::Encoder;
     use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio_rustls::{TlsAcceptor, TlsStream};
 
     #[test]
     fn test_opcode() {
@@ -29,8 +41,8 @@ mod tests {
         let op_byte = opcode.as_u8();
         assert_eq!(op_byte, 0x1);
 
-        assert_eq!(OpCode::Close.is_control(), true);
-        assert_eq!(OpCode::Text.is_control(), false);
+        assert!(OpCode::Close.is_control());
+        assert!(!OpCode::Text.is_control());
     }
 
     #[test]
@@ -45,28 +57,136 @@ mod tests {
         assert_eq!(frame.payload, payload);
     }
 
+    #[test]
+    fn test_accept_key() {
+        // Example of the WebSocket Protocol RFC
+        let accept_key = compute_accept_key("dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(accept_key, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert!(verify_accept_key(
+            "dGhlIHNhbXBsZSBub25jZQ==",
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        ));
+        assert!(!verify_accept_key(
+            "dGhlIHNhbXBsZSBub25jZQ==",
+            "dGhlIHNhbXBsZSBub25jZQ=="
+        ));
+        assert!(!verify_accept_key("dGhlIHNhbXBsZSBub25jZQ==", ""));
+    }
+
     #[test]
     fn test_parse_to_http_request_valid() {
-        let (request, host_with_port, host, use_tls) =
-            construct_http_request("ws://localhost:8080", "dGhlIHNhbXBsZSBub25jZQ==", None).unwrap();
-        assert_eq!(host_with_port, "localhost:8080");
-        assert_eq!(host, "localhost");
-        assert_eq!(use_tls, false);
-        assert!(request.starts_with("GET / HTTP/1.1"));
-        assert!(request.contains("Host: localhost"));
-        assert!(request.contains("Upgrade: websocket"));
-        assert!(request.contains("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ=="));
+        let request =
+            construct_http_request("ws://localhost:8080", "dGhlIHNhbXBsZSBub25jZQ==", &ClientConfig::default()).unwrap();
+        assert_eq!(request.host_with_port, "localhost:8080");
+        assert_eq!(request.host, "localhost");
+        assert!(!request.use_tls);
+        assert!(request.raw.starts_with("GET / HTTP/1.1"));
+        assert!(request.raw.contains("Host: localhost"));
+        assert!(request.raw.contains("Upgrade: websocket"));
+        assert!(request.raw.contains("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ=="));
+    }
+
+    #[test]
+    fn test_parse_to_http_request_custom_headers() {
+        let config = ClientConfig::new()
+            .header("Authorization", "Bearer token")
+            .origin("https://app.example");
+        let request =
+            construct_http_request("ws://localhost:8080", "dGhlIHNhbXBsZSBub25jZQ==", &config).unwrap();
+        assert!(request.raw.contains("Authorization: Bearer token\r\n"));
+        assert!(request.raw.contains("Origin: https://app.example\r\n"));
+        assert!(request.raw.ends_with("\r\n\r\n"));
+
+        let config = ClientConfig::new().header("X-Injected", "value\r\nHost: evil.com");
+        let result = construct_http_request("ws://localhost:8080", "dGhlIHNhbXBsZSBub25jZQ==", &config);
+        assert!(matches!(result, Err(crate::error::Error::InvalidHeader)));
+    }
+
+    #[test]
+    fn test_parse_to_http_request_default_version() {
+        let request = construct_http_request(
+            "ws://localhost:8080",
+            "dGhlIHNhbXBsZSBub25jZQ==",
+            &ClientConfig::default(),
+        )
+        .unwrap();
+        assert!(request.raw.contains("Sec-WebSocket-Version: 13\r\n"));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn test_websocket_version_override() -> Result<(), Box<dyn Error>> {
+        let config = ClientConfig::new().websocket_version("8");
+        let request =
+            construct_http_request("ws://localhost:8080", "dGhlIHNhbXBsZSBub25jZQ==", &config)
+                .unwrap();
+        assert!(request.raw.contains("Sec-WebSocket-Version: 8\r\n"));
+        assert!(!request.raw.contains("Sec-WebSocket-Version: 13"));
+
+        let config = ClientConfig::new().websocket_version("13\r\nHost: evil.com");
+        let result =
+            construct_http_request("ws://localhost:8080", "dGhlIHNhbXBsZSBub25jZQ==", &config);
+        assert!(matches!(result, Err(crate::error::Error::InvalidHeader)));
+
+        // The server rejects unsupported versions, so the upgrade fails
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept_async(stream).await
+        });
+        let config = ClientConfig::new().websocket_version("8");
+        assert!(connect_async_with_config(&addr, Some(config))
+            .await
+            .is_err());
+        assert!(matches!(
+            server.await?,
+            Err(crate::error::Error::UnsupportedWebsocketVersion)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_to_http_request_target() {
+        let cases = [
+            ("ws://localhost", "/"),
+            ("ws://localhost/", "/"),
+            ("ws://localhost:8080", "/"),
+            ("wss://localhost#top", "/"),
+            ("ws://localhost/a%20b", "/a%20b"),
+            ("ws://localhost/?x=1&y=2", "/?x=1&y=2"),
+            ("ws://localhost?x=1", "/?x=1"),
+            ("ws://localhost:8080?x=1", "/?x=1"),
+            ("ws://localhost/a/../b%2F?q=%7e#top", "/a/../b%2F?q=%7e"),
+            ("ws://user:password@localhost:8080/chat", "/chat"),
+            // Characters not allowed in a request-target are percent-encoded
+            ("ws://localhost/a b", "/a%20b"),
+        ];
+        for (url, target) in cases {
+            let config = ClientConfig::default();
+            let request = construct_http_request(url, "dGhlIHNhbXBsZSBub25jZQ==", &config).unwrap();
+            let request_line = format!("GET {} HTTP/1.1\r\n", target);
+            assert!(
+                request.raw.starts_with(&request_line),
+                "{}: {}",
+                url,
+                request.raw
+            );
+            // The request-target is always an absolute path, between single spaces
+            let parts: Vec<&str> = request.raw.lines().next().unwrap().split(' ').collect();
+            assert!(parts.len() == 3 && parts[1].starts_with('/'), "{}", url);
+        }
     }
 
     #[test]
     fn test_parse_to_http_request_invalid_scheme() {
-        let result = construct_http_request("ftp://localhost:8080", "dGhlIHNhbXBsZSBub25jZQ==", None);
+        let result = construct_http_request("ftp://localhost:8080", "dGhlIHNhbXBsZSBub25jZQ==", &ClientConfig::default());
         assert!(result.is_err());
     }
 
     #[test]
     fn test_parse_to_http_request_no_host() {
-        let result = construct_http_request("ws://:8080", "dGhlIHNhbXBsZSBub25jZQ==", None);
+        let result = construct_http_request("ws://:8080", "dGhlIHNhbXBsZSBub25jZQ==", &ClientConfig::default());
         assert!(result.is_err());
     }
 
@@ -86,7 +206,7 @@ mod tests {
                                 Host: 127.0.0.1\r\n\
                                 Upgrade: websocket\r\n\
                                 Connection: Upgrade\r\n\
-                                Sec-WebSocket-Key: SGVsbG8sIHdvcmxkIQ==\r\n\
+                                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
                                 Sec-WebSocket-Version: 13\r\n\r\n";
             stream
                 .write_all(handshake_request.as_bytes())
@@ -127,7 +247,7 @@ mod tests {
             let (read, mut write) = split(stream);
             let mut buf_reader = BufReader::new(read);
 
-            let mut req = HttpRequest::parse_http_request(&mut buf_reader)
+            let req = HttpRequest::parse_http_request(&mut buf_reader)
                 .await
                 .unwrap();
 
@@ -135,7 +255,7 @@ mod tests {
             // find sec_websocket_key
             let sec_websocket_key = req.get_header_value(SEC_WEBSOCKET_KEY).unwrap();
 
-            let accept_key = generate_websocket_accept_value(sec_websocket_key);
+            let accept_key = compute_accept_key(&sec_websocket_key);
 
             let mut response = HTTP_ACCEPT_RESPONSE.replace("{}", &accept_key);
             add_extension_headers(&mut response, None);
@@ -177,10 +297,10 @@ mod tests {
             match result {
                 Ok(message) => {
                     assert_eq!(
-                        message.as_text()?,
+                        message.as_text().unwrap(),
                         String::from(MESSAGE),
-                        "{}",
-                        format!("Message receive from client should be: {}", MESSAGE)
+                        "Message receive from client should be: {}",
+                        MESSAGE
                     );
                     break;
                 }
@@ -206,7 +326,7 @@ mod tests {
             let mut server_connection = accept_async(SocketFlowStream::Plain(stream)).await.unwrap();
             if let Some(result) = server_connection.next().await {
                 match result {
-                    Ok(message) => assert_eq!(message.as_binary(), payload_clone),
+                    Ok(message) => assert_eq!(message.into_data(), payload_clone),
                     Err(e) => panic!("Error occurred: {:?}", e),
                 };
             }
@@ -230,7 +350,8 @@ mod tests {
         let mut decoder = Decoder::new(true, Some(15));
 
         let encoded_data = encoder.compress(&mut BytesMut::from(&payload[..]))?;
-        let decoded_data = decoder.decompress(&mut BytesMut::from(&encoded_data[..]))?;
+        let decoded_data =
+            decoder.decompress(&mut BytesMut::from(&encoded_data[..]), usize::MAX)?;
 
         assert_eq!(payload, decoded_data);
         Ok(())
@@ -244,10 +365,11 @@ mod tests {
         let mut decoder = Decoder::new(false, Some(15));
 
         let encoded_data = encoder.compress(&mut BytesMut::from(&payload[..]))?;
-        let _ = decoder.decompress(&mut BytesMut::from(&encoded_data[..]))?;
+        let _ = decoder.decompress(&mut BytesMut::from(&encoded_data[..]), usize::MAX)?;
 
         let _ = encoder.compress(&mut BytesMut::from(&payload[..]))?;
-        let second_decoded_data = decoder.decompress(&mut BytesMut::from(&encoded_data[..]))?;
+        let second_decoded_data =
+            decoder.decompress(&mut BytesMut::from(&encoded_data[..]), usize::MAX)?;
 
         assert_eq!(payload, second_decoded_data);
         Ok(())
@@ -286,34 +408,40 @@ mod tests {
         let payload_clone = payload.clone();
         let server = tokio::spawn(async move {
             let (stream, _) = listener.accept().await.unwrap();
-            let mut config = WebSocketConfig::default();
-            config.extensions = Some(Extensions {
-                permessage_deflate: true,
-                client_no_context_takeover: Some(true),
-                server_no_context_takeover: Some(true),
-                client_max_window_bits: None,
-                server_max_window_bits: None,
-            });
+            let config = WebSocketConfig {
+                extensions: Some(Extensions {
+                    permessage_deflate: true,
+                    client_no_context_takeover: Some(true),
+                    server_no_context_takeover: Some(true),
+                    client_max_window_bits: None,
+                    server_max_window_bits: None,
+                }),
+                ..Default::default()
+            };
 
             let mut server_connection = accept_async_with_config(SocketFlowStream::Plain(stream), Some(config)).await.unwrap();
             if let Some(result) = server_connection.next().await {
                 match result {
-                    Ok(message) => assert_eq!(message.as_binary(), payload_clone),
+                    Ok(message) => assert_eq!(message.into_data(), payload_clone),
                     Err(e) => panic!("Error occurred: {:?}", e),
                 };
             }
         });
 
-        let mut websocket_config = WebSocketConfig::default();
-        websocket_config.extensions = Some(Extensions {
-            permessage_deflate: true,
-            client_no_context_takeover: Some(true),
-            server_no_context_takeover: Some(true),
-            client_max_window_bits: None,
-            server_max_window_bits: None,
-        });
-        let mut client_config = ClientConfig::default();
-        client_config.web_socket_config = websocket_config;
+        let websocket_config = WebSocketConfig {
+            extensions: Some(Extensions {
+                permessage_deflate: true,
+                client_no_context_takeover: Some(true),
+                server_no_context_takeover: Some(true),
+                client_max_window_bits: None,
+                server_max_window_bits: None,
+            }),
+            ..Default::default()
+        };
+        let client_config = ClientConfig {
+            web_socket_config: websocket_config,
+            ..Default::default()
+        };
 
         // Call the connect_async function for connecting to the server
         let mut client_connection = connect_async_with_config("ws://127.0.0.1:9008", Some(client_config)).await?;
@@ -324,4 +452,5424 @@ mod tests {
         server.await?;
         Ok(())
     }
+
+    // Builds a TLS acceptor from the test certificates, stored at certs folder
+    fn tls_acceptor() -> TlsAcceptor {
+        let mut cert_file = std::io::BufReader::new(std::fs::File::open("certs/server.crt").unwrap());
+        let mut key_file = std::io::BufReader::new(std::fs::File::open("certs/server.key").unwrap());
+        let certs = rustls_pemfile::certs(&mut cert_file)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = rustls_pemfile::private_key(&mut key_file).unwrap().unwrap();
+
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_tls() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let acceptor = tls_acceptor();
+
+        // Echo server, running over TLS
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let tls_stream = acceptor.accept(stream).await.unwrap();
+            let mut server_connection =
+                accept_async(SocketFlowStream::Secure(TlsStream::from(tls_stream)))
+                    .await
+                    .unwrap();
+            if let Some(Ok(message)) = server_connection.next().await {
+                server_connection.send_message(message).await.unwrap();
+            }
+        });
+
+        let client_config = ClientConfig {
+            ca_file: Some(String::from("certs/ca.crt")),
+            ..Default::default()
+        };
+        let mut client_connection =
+            connect_async_with_config(&format!("wss://localhost:{}", port), Some(client_config))
+                .await?;
+        client_connection
+            .send_as_text(String::from("secure"))
+            .await?;
+
+        let message = client_connection.next().await.unwrap()?;
+        assert_eq!(message.as_text(), Some("secure"));
+
+        server.await?;
+        Ok(())
+    }
+
+    const CLIENT_HANDSHAKE_REQUEST: &str = "GET / HTTP/1.1\r\n\
+                                Host: 127.0.0.1\r\n\
+                                Upgrade: websocket\r\n\
+                                Connection: Upgrade\r\n\
+                                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                                Sec-WebSocket-Version: 13\r\n\r\n";
+
+    // Builds a frame the same way a client would do, with a masked payload
+    fn masked_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![first_byte];
+        if payload.len() <= 125 {
+            frame.push(0b1000_0000 | payload.len() as u8);
+        } else if payload.len() <= 65535 {
+            frame.push(0b1000_0000 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        } else {
+            frame.push(0b1000_0000 | 127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    // Reads an HTTP response byte by byte, until the end of the headers.
+    // Thus, no bytes from the subsequent frames are consumed
+    async fn read_http_response<R: AsyncRead + Unpin>(stream: &mut R) -> String {
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_duplex_stream() -> Result<(), Box<dyn Error>> {
+        let (mut client, server) = duplex(1024);
+
+        let server = tokio::spawn(async move {
+            let mut server_connection = accept_async(server).await.unwrap();
+            server_connection.next().await.unwrap().unwrap()
+        });
+
+        client.write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes()).await?;
+        let response = read_http_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols"));
+
+        client.write_all(&masked_frame(0x81, b"duplex")).await?;
+
+        let message = server.await?;
+        assert_eq!(message.as_text(), Some("duplex"));
+        Ok(())
+    }
+
+    // Simulates a server, that replies to the client handshake with a 101 response, including
+    // the extra headers given. It returns the handshake request sent by the client
+    async fn fake_server(listener: TcpListener, extra_headers: &str) -> String {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = split(stream);
+        let mut buf_reader = BufReader::new(read);
+
+        let req = HttpRequest::parse_http_request(&mut buf_reader)
+            .await
+            .unwrap();
+        let sec_websocket_key = req.get_header_value(SEC_WEBSOCKET_KEY).unwrap();
+        let accept_key = compute_accept_key(&sec_websocket_key);
+
+        let mut response = HTTP_ACCEPT_RESPONSE.replace("{}", &accept_key);
+        response.push_str(extra_headers);
+        add_extension_headers(&mut response, None);
+        write.write_all(response.as_bytes()).await.unwrap();
+        write.flush().await.unwrap();
+
+        req.headers
+            .iter()
+            .map(|(key, value)| format!("{}: {}\r\n", key, value))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_subprotocol() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let server = tokio::spawn(fake_server(listener, "Sec-WebSocket-Protocol: mqtt\r\n"));
+
+        let config = ClientConfig::new().protocol("graphql-ws").protocol("mqtt");
+        let client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        assert_eq!(client_connection.protocol(), Some("mqtt"));
+
+        let request_headers = server.await?;
+        assert!(request_headers.contains("Sec-WebSocket-Protocol: graphql-ws, mqtt\r\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_subprotocol_not_offered() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let server = tokio::spawn(fake_server(listener, "Sec-WebSocket-Protocol: chat\r\n"));
+
+        let config = ClientConfig::new().protocol("graphql-ws");
+        let result = connect_async_with_config(&addr, Some(config)).await;
+        assert!(matches!(result, Err(crate::error::Error::InvalidSubprotocol)));
+
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_select_subprotocol() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let config = AcceptConfig::new().select_protocol(|offered| {
+                offered.iter().find(|p| **p == "mqtt").map(|p| p.to_string())
+            });
+            let server_connection = accept_async_with(stream, config).await.unwrap();
+            server_connection.protocol().map(String::from)
+        });
+
+        let config = ClientConfig::new().protocol("graphql-ws").protocol("mqtt");
+        let client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        assert_eq!(client_connection.protocol(), Some("mqtt"));
+        assert_eq!(server.await?, Some(String::from("mqtt")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_no_subprotocol_selected() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let config = AcceptConfig::new().select_protocol(|_| None);
+            let server_connection = accept_async_with(stream, config).await.unwrap();
+            server_connection.protocol().map(String::from)
+        });
+
+        let config = ClientConfig::new().protocol("graphql-ws");
+        let client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        assert_eq!(client_connection.protocol(), None);
+        assert_eq!(server.await?, None);
+        Ok(())
+    }
+
+    fn compression_config(
+        client_no_context_takeover: bool,
+        server_no_context_takeover: bool,
+    ) -> WebSocketConfig {
+        WebSocketConfig {
+            extensions: Some(Extensions {
+                permessage_deflate: true,
+                client_no_context_takeover: Some(client_no_context_takeover),
+                server_no_context_takeover: Some(server_no_context_takeover),
+                client_max_window_bits: None,
+                server_max_window_bits: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compressed_frame_on_wire() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let payload = "socket-flow ".repeat(100 * 1024 / 12);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = split(stream);
+            let mut buf_reader = BufReader::new(read);
+
+            let req = HttpRequest::parse_http_request(&mut buf_reader)
+                .await
+                .unwrap();
+            assert!(req
+                .get_header_value(SEC_WEBSOCKET_EXTENSIONS)
+                .unwrap()
+                .contains("permessage-deflate"));
+            let accept_key =
+                compute_accept_key(&req.get_header_value(SEC_WEBSOCKET_KEY).unwrap());
+            let mut response = HTTP_ACCEPT_RESPONSE.replace("{}", &accept_key);
+            add_extension_headers(&mut response, compression_config(true, true).extensions);
+            write.write_all(response.as_bytes()).await.unwrap();
+
+            // Reading the raw frame, to check it's compressed
+            let mut header = [0u8; 2];
+            buf_reader.read_exact(&mut header).await.unwrap();
+            assert_ne!(header[0] & 0b0100_0000, 0, "RSV1 should be set");
+            let length = match header[1] & 0b0111_1111 {
+                126 => buf_reader.read_u16().await.unwrap() as usize,
+                127 => buf_reader.read_u64().await.unwrap() as usize,
+                length => length as usize,
+            };
+            let mut mask = [0u8; 4];
+            buf_reader.read_exact(&mut mask).await.unwrap();
+            let mut compressed = vec![0u8; length];
+            buf_reader.read_exact(&mut compressed).await.unwrap();
+            for (i, byte) in compressed.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+
+            let mut decoder = Decoder::new(true, None);
+            let decompressed = decoder
+                .decompress(&mut BytesMut::from(&compressed[..]), usize::MAX)
+                .unwrap();
+            (length, decompressed)
+        });
+
+        let config = ClientConfig::new().web_socket_config(compression_config(true, true));
+        let mut client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        client_connection.send_as_text(payload.clone()).await?;
+
+        let (wire_length, decompressed) = server.await?;
+        assert!(wire_length < payload.len() / 10);
+        assert_eq!(decompressed, payload.into_bytes());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compression_server_keeps_context() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let messages: Vec<String> = (0..5)
+            .map(|i| format!("{} {}", "compressed message, kept context".repeat(20), i))
+            .collect();
+
+        // Only the client resets its context, the server keeps it between messages,
+        // so the client decoder needs to keep its context as well
+        let messages_clone = messages.clone();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection =
+                accept_async_with_config(stream, Some(compression_config(true, false)))
+                    .await
+                    .unwrap();
+            for message in messages_clone {
+                server_connection.send_as_text(message).await.unwrap();
+            }
+            server_connection
+        });
+
+        let config = ClientConfig::new().web_socket_config(compression_config(true, false));
+        let mut client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        for message in messages {
+            assert_eq!(
+                client_connection.next().await.unwrap()?.as_text().unwrap(),
+                message
+            );
+        }
+
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deflate_disabled_on_one_side() -> Result<(), Box<dyn Error>> {
+        let disabled = WebSocketConfig {
+            extensions: Some(Extensions {
+                permessage_deflate: false,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        for (server_config, client_config) in [
+            (compression_config(true, false), disabled.clone()),
+            (disabled.clone(), compression_config(true, false)),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = format!("ws://{}", listener.local_addr()?);
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut server_connection = accept_async_with_config(stream, Some(server_config))
+                    .await
+                    .unwrap();
+                let message = server_connection.next().await.unwrap().unwrap();
+                (server_connection.extensions().permessage_deflate, message)
+            });
+
+            // The heads end with a single blank line, so the first frame is read right after it
+            let config = ClientConfig::new().web_socket_config(client_config);
+            let mut client_connection = connect_async_with_config(&addr, Some(config)).await?;
+            client_connection.send_as_text("hi".to_string()).await?;
+            assert_eq!(client_connection.extensions().permessage_deflate, None);
+            assert_eq!(server.await?, (None, Message::text("hi")));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_extensions() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept_async_with_config(stream, Some(compression_config(true, false)))
+                .await
+                .unwrap()
+        });
+
+        let config = ClientConfig::new().web_socket_config(compression_config(true, false));
+        let client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        let server_connection = server.await?;
+
+        let expected = Some(DeflateParams {
+            client_no_context_takeover: true,
+            server_no_context_takeover: false,
+            client_max_window_bits: None,
+            server_max_window_bits: None,
+        });
+        assert_eq!(client_connection.extensions().permessage_deflate, expected);
+        assert_eq!(server_connection.extensions().permessage_deflate, expected);
+
+        // Without compression configured, nothing is negotiated
+        let (connection, _client) = raw_client_connection(None).await;
+        assert_eq!(connection.extensions().permessage_deflate, None);
+        Ok(())
+    }
+
+    // Performs the handshake between a server connection and a raw client over a duplex stream,
+    // so tests can write and read raw frames from the client side
+    async fn raw_client_connection(config: Option<WebSocketConfig>) -> (WSConnection, DuplexStream) {
+        raw_client_connection_with(CLIENT_HANDSHAKE_REQUEST, config).await
+    }
+
+    // Same as raw_client_connection, but with a custom handshake request
+    async fn raw_client_connection_with(
+        request: &str,
+        config: Option<WebSocketConfig>,
+    ) -> (WSConnection, DuplexStream) {
+        let (mut client, server) = duplex(1 << 20);
+        let server = tokio::spawn(accept_async_with_config(server, config));
+
+        client.write_all(request.as_bytes()).await.unwrap();
+        let response = read_http_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols"));
+
+        (server.await.unwrap().unwrap(), client)
+    }
+
+    #[tokio::test]
+    async fn test_ping_replied_with_pong() -> Result<(), Box<dyn Error>> {
+        let (_server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x89, b"hello")).await?;
+
+        let mut pong = [0u8; 7];
+        client.read_exact(&mut pong).await?;
+        assert_eq!(pong[0], 0x8A);
+        assert_eq!(pong[1], 5);
+        assert_eq!(&pong[2..], b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_forwarded_when_enabled() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            forward_pings: true,
+            ..Default::default()
+        };
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        client.write_all(&masked_frame(0x89, b"hello")).await?;
+        client.write_all(&masked_frame(0x81, b"text")).await?;
+
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Ping(b"hello".to_vec())
+        );
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Text(String::from("text"))
+        );
+
+        // Pings are still replied, even when forwarded
+        let mut pong = [0u8; 7];
+        client.read_exact(&mut pong).await?;
+        assert_eq!(&pong[..], &[0x8A, 5, b'h', b'e', b'l', b'l', b'o']);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_pong_timeout() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            keepalive_interval: Some(Duration::from_millis(50)),
+            keepalive_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        // The raw client never answers the pings
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        let mut ping = [0u8; 2];
+        client.read_exact(&mut ping).await?;
+        assert_eq!(ping, [0x89, 0]);
+
+        assert!(matches!(
+            server_connection.next().await,
+            Some(Err(crate::error::Error::PongTimeout))
+        ));
+        assert!(server_connection.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_connection_stays_open() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            server_connection
+                .send_as_text(String::from("still alive"))
+                .await
+                .unwrap();
+            server_connection
+        });
+
+        let config = ClientConfig::new().keepalive(Duration::from_millis(50));
+        let mut client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        let message = client_connection.next().await.unwrap()?;
+        assert_eq!(message.as_text(), Some("still alive"));
+
+        server.await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_close_code_conversion() {
+        assert_eq!(CloseCode::Normal.as_u16(), 1000);
+        assert_eq!(CloseCode::InternalError.as_u16(), 1011);
+        assert_eq!(CloseCode::try_from_u16(1001), Some(CloseCode::GoingAway));
+        assert_eq!(CloseCode::try_from_u16(4000), Some(CloseCode::Other(4000)));
+        assert_eq!(CloseCode::try_from_u16(999), None);
+        assert_eq!(CloseCode::try_from_u16(5000), None);
+    }
+
+    #[tokio::test]
+    async fn test_close_received_from_peer() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client
+            .write_all(&masked_frame(0x88, b"\x03\xe9bye"))
+            .await?;
+
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Close {
+                code: Some(CloseCode::GoingAway),
+                reason: String::from("bye"),
+            }
+        );
+        assert!(server_connection.next().await.is_none());
+
+        // The status code is echoed back, and the write half is shut down afterward
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xe9]);
+        assert_eq!(client.read(&mut close).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_handshake() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        let close_task = tokio::spawn(async move {
+            server_connection
+                .close(CloseCode::Other(4000), Some(String::from("done")))
+                .await
+                .map(|_| server_connection)
+        });
+
+        let mut close = [0u8; 8];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 6, 0x0f, 0xa0, b'd', b'o', b'n', b'e']);
+
+        client
+            .write_all(&masked_frame(0x88, b"\x0f\xa0"))
+            .await?;
+
+        // The close returns as soon as the reply arrives, and the write half is shut down
+        let mut server_connection = tokio::time::timeout(Duration::from_secs(1), close_task)
+            .await???;
+        assert_eq!(client.read(&mut close).await?, 0);
+
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Close {
+                code: Some(CloseCode::Other(4000)),
+                reason: String::new(),
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_rejected() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            max_message_size: Some(1024),
+            ..Default::default()
+        };
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        // Only the header is sent, advertising a 2048 bytes payload, which shouldn't be buffered
+        client
+            .write_all(&[0x82, 126 | 0x80, 0x08, 0x00, 0x12, 0x34, 0x56, 0x78])
+            .await?;
+
+        let result = tokio::time::timeout(Duration::from_secs(1), server_connection.next())
+            .await?
+            .unwrap();
+        assert!(matches!(result, Err(crate::error::Error::MaxMessageSize)));
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xf1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oversized_fragmented_message_rejected() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            max_message_size: Some(1024),
+            ..Default::default()
+        };
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        client.write_all(&masked_frame(0x02, &[1u8; 600])).await?;
+        client.write_all(&masked_frame(0x80, &[1u8; 600])).await?;
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::MaxMessageSize)
+        ));
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xf1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_message_with_interleaved_ping() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x01, b"Hello")).await?;
+        client.write_all(&masked_frame(0x89, b"ping")).await?;
+        client.write_all(&masked_frame(0x00, b" World")).await?;
+        client.write_all(&masked_frame(0x80, b"!")).await?;
+
+        // The ping is answered while the message is still being reassembled
+        let mut pong = [0u8; 6];
+        client.read_exact(&mut pong).await?;
+        assert_eq!(pong, [0x8A, 4, b'p', b'i', b'n', b'g']);
+
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Text(String::from("Hello World!"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_frame_and_message_size_limits() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            max_frame_size: None,
+            max_message_size: None,
+            ..Default::default()
+        };
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        // Without any limit, the message is written in a single frame
+        server_connection.send_as_binary(vec![7u8; 300]).await?;
+        let mut frame = [0u8; 304];
+        client.read_exact(&mut frame).await?;
+        assert_eq!(&frame[..4], &[0x82, 126, 0x01, 0x2c]);
+        assert!(frame[4..].iter().all(|byte| *byte == 7));
+
+        // Neither is any received frame or message rejected
+        client.write_all(&masked_frame(0x02, &[1u8; 300])).await?;
+        client.write_all(&masked_frame(0x80, &[2u8; 300])).await?;
+        let message = server_connection.next().await.unwrap()?;
+        assert_eq!(message.len(), 600);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_continuation_frame() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x80, b"orphan")).await?;
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::InvalidContinuationFrame)
+        ));
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xea]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reserved_opcode_closed_with_protocol_error() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x83, b"reserved")).await?;
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::InvalidOpcode)
+        ));
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xea]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_data_frame_during_fragmented_message() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x01, b"Hello")).await?;
+        client.write_all(&masked_frame(0x81, b"other message")).await?;
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::InvalidFrameFragmentation)
+        ));
+
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x01, b"Hello")).await?;
+        client.write_all(&masked_frame(0x02, b"other message")).await?;
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::FragmentedInProgress)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_fragmented_message() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws_connection = accept_async(stream).await.unwrap();
+            ws_connection.next().await.unwrap().unwrap()
+        });
+
+        let mut client_connection = connect_async(&format!("ws://{}", addr)).await?;
+        let mut fragmented = client_connection.fragmented_binary();
+        fragmented.send(vec![1u8; 20000]).await?;
+        fragmented.send(vec![2u8; 20000]).await?;
+        fragmented.finish(vec![3u8; 10]).await?;
+
+        let mut expected = vec![1u8; 20000];
+        expected.extend_from_slice(&[2u8; 20000]);
+        expected.extend_from_slice(&[3u8; 10]);
+        assert_eq!(server.await?, Message::Binary(expected.into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_frames_on_wire() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        let mut fragmented = server_connection.fragmented_text();
+        fragmented.send(b"Hel".to_vec()).await?;
+        fragmented.send(b"lo".to_vec()).await?;
+        fragmented.finish(b"!".to_vec()).await?;
+
+        let mut frames = [0u8; 12];
+        client.read_exact(&mut frames).await?;
+        assert_eq!(
+            frames,
+            [0x01, 3, b'H', b'e', b'l', 0x00, 2, b'l', b'o', 0x80, 1, b'!']
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_message_holds_writer() -> Result<(), Box<dyn Error>> {
+        let (server_connection, mut client) = raw_client_connection(None).await;
+        let (_reader, mut writer) = server_connection.split();
+        let other = writer.clone();
+
+        let mut fragmented = writer.fragmented_binary();
+        fragmented.send(b"ab".to_vec()).await?;
+        // A clone sending in the meantime waits until the message is finished
+        let send = tokio::spawn(async move { other.send_as_text(String::from("hi")).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!send.is_finished());
+        fragmented.finish(b"cd".to_vec()).await?;
+        send.await??;
+
+        let mut frames = [0u8; 12];
+        client.read_exact(&mut frames).await?;
+        assert_eq!(
+            frames,
+            [0x02, 2, b'a', b'b', 0x80, 2, b'c', b'd', 0x81, 2, b'h', b'i']
+        );
+
+        // Dropped without finishing, the message is completed with an empty final fragment
+        let mut fragmented = writer.fragmented_text();
+        fragmented.send(b"ab".to_vec()).await?;
+        drop(fragmented);
+        writer.send_as_text(String::from("hi")).await?;
+
+        let mut frames = [0u8; 10];
+        client.read_exact(&mut frames).await?;
+        assert_eq!(frames, [0x01, 2, b'a', b'b', 0x80, 0, 0x81, 2, b'h', b'i']);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8_text_rejected() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x81, &[b'a', 0xff, b'b'])).await?;
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::InvalidUtf8)
+        ));
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xef]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_utf8_character_split_between_fragments() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        // "€" is encoded as E2 82 AC, and it's split between the 3 fragments
+        client.write_all(&masked_frame(0x01, &[b'a', 0xe2])).await?;
+        client.write_all(&masked_frame(0x00, &[0x82])).await?;
+        client.write_all(&masked_frame(0x80, &[0xac, b'b'])).await?;
+
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Text(String::from("a€b"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_utf8_fails_before_final_fragment() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x01, b"valid")).await?;
+        client.write_all(&masked_frame(0x00, &[0xc0, 0x80])).await?;
+
+        // The message isn't completed, although it's already known to be invalid
+        let result = tokio::time::timeout(Duration::from_secs(1), server_connection.next())
+            .await?
+            .unwrap();
+        assert!(matches!(result, Err(crate::error::Error::InvalidUtf8)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_utf8_at_message_end_rejected() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x01, b"a")).await?;
+        client.write_all(&masked_frame(0x80, &[0xe2, 0x82])).await?;
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::InvalidUtf8)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_many_headers() -> Result<(), Box<dyn Error>> {
+        let (mut client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept_async(server));
+
+        let mut request = CLIENT_HANDSHAKE_REQUEST.trim_end().to_string();
+        for i in 0..30 {
+            request.push_str(&format!("\r\nX-Custom-Header-{}: {}", i, "a".repeat(100)));
+        }
+        request.push_str("\r\n\r\n");
+        client.write_all(request.as_bytes()).await?;
+
+        let response = read_http_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols"));
+        assert!(server.await?.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_handshake_too_large() -> Result<(), Box<dyn Error>> {
+        let (mut client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept_async(server));
+
+        let mut request = CLIENT_HANDSHAKE_REQUEST.trim_end().to_string();
+        request.push_str(&format!("\r\nCookie: {}\r\n\r\n", "a".repeat(20 * 1024)));
+        client.write_all(request.as_bytes()).await?;
+
+        assert!(matches!(
+            server.await?,
+            Err(crate::error::Error::HandshakeTooLarge)
+        ));
+        Ok(())
+    }
+
+    // Replies the client handshake with the given response, which is written in small chunks,
+    // simulating a response split in multiple TCP segments
+    async fn fake_server_response(listener: TcpListener, response: String) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = split(stream);
+        let mut buf_reader = BufReader::new(read);
+
+        let req = HttpRequest::parse_http_request(&mut buf_reader)
+            .await
+            .unwrap();
+        let accept_key =
+            compute_accept_key(&req.get_header_value(SEC_WEBSOCKET_KEY).unwrap());
+
+        for chunk in response.replace("{}", &accept_key).as_bytes().chunks(10) {
+            write.write_all(chunk).await.unwrap();
+            write.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_response_split_with_extra_headers() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let response = "HTTP/1.1 101 Switching Protocols\r\n\
+                        Upgrade: websocket\r\n\
+                        Connection: Upgrade\r\n\
+                        Set-Cookie: session=123; Path=/; HttpOnly\r\n\
+                        Server: test\r\n\
+                        Sec-WebSocket-Accept: {}\r\n\r\n";
+        let server = tokio::spawn(fake_server_response(listener, response.to_string()));
+
+        assert!(connect_async(&addr).await.is_ok());
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parse_http_response() -> Result<(), Box<dyn Error>> {
+        // The bytes following the head belong to the websocket connection, so they aren't read
+        let response = b"HTTP/1.1 101 Switching Protocols\r\n\
+                         Upgrade: websocket\r\n\
+                         X-Name: caf\xc3\xa9\r\n\r\n\x81\x05hello";
+        let mut reader = BufReader::new(&response[..]);
+        let parsed = crate::request::HttpResponse::parse_http_response(&mut reader).await?;
+        assert_eq!(parsed.version, "HTTP/1.1");
+        assert_eq!(parsed.status, 101);
+        assert_eq!(parsed.reason, "Switching Protocols");
+        assert_eq!(
+            parsed.get_header_value("x-name").as_deref(),
+            Some("caf\u{e9}")
+        );
+        assert_eq!(reader.buffer(), b"\x81\x05hello");
+
+        for response in [
+            &b"HTTP/1.1 101 Switching Protocols\r\nX-Name: caf\xe9\r\n\r\n"[..],
+            b"HTTP/1.1 abc Switching Protocols\r\n\r\n",
+            b"SSH-2.0-OpenSSH_9.6\r\n\r\n",
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n",
+        ] {
+            let mut reader = BufReader::new(response);
+            assert!(matches!(
+                crate::request::HttpResponse::parse_http_response(&mut reader).await,
+                Err(crate::error::Error::HttpParseError)
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_not_switching_protocols() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        // Even though it contains a valid accept key, the connection wasn't upgraded
+        let response = "HTTP/1.1 403 Forbidden\r\n\
+                        Sec-WebSocket-Accept: {}\r\n\
+                        Content-Length: 0\r\n\r\n";
+        let server = tokio::spawn(fake_server_response(listener, response.to_string()));
+
+        match connect_async(&addr).await {
+            Err(crate::error::Error::NoUpgrade { response }) => {
+                assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+                assert!(response.ends_with("Content-Length: 0"));
+            }
+            _ => panic!("expected NoUpgrade"),
+        }
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_invalid_accept_key_response() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        // The response is carried by the error, but truncated, so a huge one isn't kept
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: invalid\r\n\
+             X-Padding: {}\r\n\r\n",
+            "a".repeat(1100)
+        );
+        let server = tokio::spawn(fake_server_response(listener, response));
+
+        match connect_async(&addr).await {
+            Err(crate::error::Error::InvalidAcceptKey { response }) => {
+                assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+                assert!(response.contains("Sec-WebSocket-Accept: invalid\r\n"));
+                assert_eq!(response.len(), 1024 + "...".len());
+                assert!(response.ends_with("aaa..."));
+            }
+            _ => panic!("expected InvalidAcceptKey"),
+        }
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_frame_sent_with_handshake() -> Result<(), Box<dyn Error>> {
+        let (mut client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept_async(server));
+
+        // Handshake and first frame written in a single write
+        let mut data = CLIENT_HANDSHAKE_REQUEST.as_bytes().to_vec();
+        data.extend_from_slice(&masked_frame(0x81, b"first message"));
+        client.write_all(&data).await?;
+
+        let response = read_http_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols"));
+
+        let mut server_connection = server.await??;
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Text(String::from("first message"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_frame_sent_with_response() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = split(stream);
+            let mut buf_reader = BufReader::new(read);
+            let req = HttpRequest::parse_http_request(&mut buf_reader)
+                .await
+                .unwrap();
+            let accept_key =
+                compute_accept_key(&req.get_header_value(SEC_WEBSOCKET_KEY).unwrap());
+
+            // Response and first frame written in a single write
+            let mut data = HTTP_ACCEPT_RESPONSE.replace("{}", &accept_key).into_bytes();
+            data.extend_from_slice(b"\r\n");
+            data.extend_from_slice(&[0x81, 13]);
+            data.extend_from_slice(b"first message");
+            write.write_all(&data).await.unwrap();
+            // Keeping the connection open until the client sends another frame
+            buf_reader.read_u8().await.unwrap();
+        });
+
+        let mut client_connection = connect_async(&addr).await?;
+        assert_eq!(
+            client_connection.next().await.unwrap()?,
+            Message::Text(String::from("first message"))
+        );
+        client_connection.send_as_text(String::from("done")).await?;
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_handshake_timeout() -> Result<(), Box<dyn Error>> {
+        let (mut client, server) = duplex(1024);
+        let accept_config = AcceptConfig::new().handshake_timeout(Duration::from_millis(100));
+        let server = tokio::spawn(accept_async_with(server, accept_config));
+
+        // Only a part of the handshake is sent
+        client.write_all(b"GET / HTTP/1.1\r\n").await?;
+
+        assert!(matches!(
+            server.await?,
+            Err(crate::error::Error::HandshakeTimeout)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_handshake_timeout() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        // The server accepts the TCP connection, but never replies the handshake
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let _ = stream.read_to_end(&mut buf).await;
+        });
+
+        let config = ClientConfig::new().handshake_timeout(Duration::from_millis(100));
+        assert!(matches!(
+            connect_async_with_config(&addr, Some(config)).await,
+            Err(crate::error::Error::HandshakeTimeout)
+        ));
+        server.await?;
+        Ok(())
+    }
+
+    const CLIENT_HANDSHAKE_REQUEST_WITH_PATH: &str = "GET /chat?room=1 HTTP/1.1\r\n\
+                                Host: 127.0.0.1\r\n\
+                                Upgrade: websocket\r\n\
+                                Connection: Upgrade\r\n\
+                                authorization: Bearer token\r\n\
+                                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                                Sec-WebSocket-Version: 13\r\n\r\n";
+
+    #[tokio::test]
+    async fn test_accept_async_with_request() -> Result<(), Box<dyn Error>> {
+        let (mut client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept_async_with_request(server, |request| {
+            assert_eq!(request.path(), "/chat");
+            assert_eq!(request.query(), Some("room=1"));
+            // Header names are case-insensitive
+            match request.get_header_value("Authorization").as_deref() {
+                Some("Bearer token") => Ok(()),
+                _ => Err(String::from("missing token")),
+            }
+        }));
+
+        client
+            .write_all(CLIENT_HANDSHAKE_REQUEST_WITH_PATH.as_bytes())
+            .await?;
+        let response = read_http_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols"));
+        assert!(server.await?.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_with_request_rejected() -> Result<(), Box<dyn Error>> {
+        let (mut client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept_async_with_request(server, |request| {
+            if request.path() == "/admin" {
+                Ok(())
+            } else {
+                Err(String::from("unknown path"))
+            }
+        }));
+
+        client
+            .write_all(CLIENT_HANDSHAKE_REQUEST_WITH_PATH.as_bytes())
+            .await?;
+
+        assert!(matches!(
+            server.await?,
+            Err(crate::error::Error::HandshakeRejected(reason)) if reason == "unknown path"
+        ));
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(response.ends_with("\r\n\r\nunknown path"));
+        Ok(())
+    }
+
+    async fn accept_with_origin(
+        accept_config: AcceptConfig,
+        origin: Option<&str>,
+    ) -> (crate::handshake::Result, String) {
+        let (mut client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept_async_with(server, accept_config));
+
+        let mut request = CLIENT_HANDSHAKE_REQUEST.trim_end().to_string();
+        if let Some(origin) = origin {
+            request.push_str(&format!("\r\nOrigin: {}", origin));
+        }
+        request.push_str("\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let response = read_http_response(&mut client).await;
+        (server.await.unwrap(), response)
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_allowed_origin() -> Result<(), Box<dyn Error>> {
+        let accept_config = AcceptConfig::new()
+            .allowed_origin("https://app.example")
+            .allowed_origin("https://admin.example");
+
+        let (result, response) =
+            accept_with_origin(accept_config.clone(), Some("https://admin.example")).await;
+        assert!(result.is_ok());
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols"));
+
+        let (result, response) =
+            accept_with_origin(accept_config.clone(), Some("https://evil.example")).await;
+        assert!(matches!(result, Err(crate::error::Error::OriginNotAllowed)));
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"));
+
+        let (result, response) = accept_with_origin(accept_config, None).await;
+        assert!(matches!(result, Err(crate::error::Error::OriginNotAllowed)));
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"));
+
+        // Without an allow-list, any origin is accepted
+        let (result, _) = accept_with_origin(AcceptConfig::new(), Some("https://evil.example")).await;
+        assert!(result.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_split_writer_cloned_between_tasks() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        // The server keeps the compression context, so messages must be written in the same
+        // order they were compressed, even if they are sent from different tasks
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let server_connection =
+                accept_async_with_config(stream, Some(compression_config(false, false)))
+                    .await
+                    .unwrap();
+            let (_reader, writer) = server_connection.split();
+            let mut senders = Vec::new();
+            for task in 0..4 {
+                let writer = writer.clone();
+                senders.push(tokio::spawn(async move {
+                    for i in 0..25 {
+                        let message = format!("{} {} {}", "message from task".repeat(20), task, i);
+                        writer.send_as_text(message).await.unwrap();
+                    }
+                }));
+            }
+            for sender in senders {
+                sender.await.unwrap();
+            }
+            writer
+        });
+
+        let config = ClientConfig::new().web_socket_config(compression_config(false, false));
+        let client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        let (mut reader, _writer) = client_connection.split();
+        let mut received = 0;
+        while received < 100 {
+            let message = reader.next().await.unwrap()?;
+            assert!(message.as_text().unwrap().starts_with("message from task"));
+            received += 1;
+        }
+
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_from_cloned_writer_ends_reader() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            // Consuming the close frame, which is automatically replied
+            while server_connection.next().await.is_some() {}
+        });
+
+        let client_connection = connect_async(&addr).await?;
+        let (mut reader, writer) = client_connection.split();
+        let closer = writer.clone();
+        tokio::spawn(async move { closer.close(CloseCode::GoingAway, None).await });
+
+        assert!(matches!(
+            reader.next().await.unwrap()?,
+            Message::Close {
+                code: Some(CloseCode::GoingAway),
+                ..
+            }
+        ));
+        assert!(reader.next().await.is_none());
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sink_and_stream() -> Result<(), Box<dyn Error>> {
+        use futures::SinkExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        // Echo server, forwarding the reader into the writer
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, writer) = accept_async(stream).await.unwrap().split();
+            reader.take(3).forward(writer).await.unwrap();
+        });
+
+        let mut client_connection = connect_async(&addr).await?;
+        let messages = vec![
+            Message::Text(String::from("first")),
+            Message::binary(vec![1, 2, 3]),
+            Message::Text(String::from("third")),
+        ];
+        for message in messages.clone() {
+            SinkExt::send(&mut client_connection, message).await?;
+        }
+        for message in messages {
+            assert_eq!(client_connection.next().await.unwrap()?, message);
+        }
+
+        // Forwarding finished, so the server closes its sink, sending a close frame
+        assert!(matches!(
+            client_connection.next().await.unwrap()?,
+            Message::Close {
+                code: Some(CloseCode::Normal),
+                ..
+            }
+        ));
+        SinkExt::close(&mut client_connection).await?;
+        server.await?;
+        Ok(())
+    }
+
+    // Accepts a single CONNECT request, replying with the given status. If successful,
+    // the connection is tunneled to the target. Returns the CONNECT request
+    async fn fake_proxy(listener: TcpListener, status: &str) -> HttpRequest {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut buf_reader = BufReader::new(stream);
+        let req = HttpRequest::parse_http_request(&mut buf_reader)
+            .await
+            .unwrap();
+
+        let mut client = buf_reader.into_inner();
+        client
+            .write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes())
+            .await
+            .unwrap();
+        if status.starts_with("200") {
+            let mut server = TcpStream::connect(&req.uri).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_through_proxy() -> Result<(), Box<dyn Error>> {
+        let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server_listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (stream, _) = server_listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            let message = server_connection.next().await.unwrap().unwrap();
+            server_connection.send_message(message).await.unwrap();
+            server_connection.close_connection().await.unwrap();
+        });
+
+        let proxy_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_url = format!("http://user:p%40ss@{}", proxy_listener.local_addr()?);
+        let proxy = tokio::spawn(fake_proxy(proxy_listener, "200 Connection Established"));
+
+        let config = ClientConfig::new().proxy(proxy_url);
+        let mut client_connection =
+            connect_async_with_config(&format!("ws://{}", server_addr), Some(config)).await?;
+        client_connection
+            .send_as_text(String::from("through proxy"))
+            .await?;
+        assert_eq!(
+            client_connection.next().await.unwrap()?,
+            Message::Text(String::from("through proxy"))
+        );
+        server.await?;
+        // Closing the client side of the tunnel
+        drop(client_connection);
+
+        let connect_request = proxy.await?;
+        assert_eq!(connect_request.method, "CONNECT");
+        assert_eq!(connect_request.uri, server_addr.to_string());
+        // base64 of "user:p@ss"
+        assert_eq!(
+            connect_request.get_header_value("Proxy-Authorization"),
+            Some(String::from("Basic dXNlcjpwQHNz"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_proxy_refused() -> Result<(), Box<dyn Error>> {
+        let proxy_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_url = format!("http://{}", proxy_listener.local_addr()?);
+        let proxy = tokio::spawn(fake_proxy(
+            proxy_listener,
+            "407 Proxy Authentication Required",
+        ));
+
+        let config = ClientConfig::new().proxy(proxy_url);
+        assert!(matches!(
+            connect_async_with_config("ws://example.com", Some(config)).await,
+            Err(crate::error::Error::ProxyConnectFailed(407))
+        ));
+
+        let connect_request = proxy.await?;
+        assert_eq!(connect_request.uri, "example.com:80");
+        assert!(connect_request
+            .get_header_value("Proxy-Authorization")
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_to_http_request_ipv6() {
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let request = construct_http_request("ws://[::1]/", key, &ClientConfig::default()).unwrap();
+        assert_eq!(request.host_with_port, "[::1]:80");
+        assert_eq!(request.host, "::1");
+        assert!(request.raw.contains("Host: [::1]\r\n"));
+
+        let request =
+            construct_http_request("wss://[2001:db8::1]:9000/path", key, &ClientConfig::default())
+                .unwrap();
+        assert_eq!(request.host_with_port, "[2001:db8::1]:9000");
+        assert_eq!(request.host, "2001:db8::1");
+        assert!(request.use_tls);
+        assert!(request.raw.starts_with("GET /path HTTP/1.1"));
+        assert!(request.raw.contains("Host: [2001:db8::1]:9000\r\n"));
+
+        let request =
+            construct_http_request("ws://127.0.0.1:9000", key, &ClientConfig::default()).unwrap();
+        assert_eq!(request.host_with_port, "127.0.0.1:9000");
+        assert_eq!(request.host, "127.0.0.1");
+        assert!(request.raw.contains("Host: 127.0.0.1:9000\r\n"));
+
+        let request =
+            construct_http_request("wss://example.com/", key, &ClientConfig::default()).unwrap();
+        assert_eq!(request.host_with_port, "example.com:443");
+        assert_eq!(request.host, "example.com");
+        assert!(request.raw.contains("Host: example.com\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_ipv6_tls() -> Result<(), Box<dyn Error>> {
+        // IPv6 may not be available in every environment
+        let Ok(listener) = TcpListener::bind("[::1]:0").await else {
+            return Ok(());
+        };
+        let port = listener.local_addr()?.port();
+        let acceptor = tls_acceptor();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let tls_stream = acceptor.accept(stream).await.unwrap();
+            accept_async(SocketFlowStream::Secure(TlsStream::from(tls_stream)))
+                .await
+                .unwrap()
+        });
+
+        // The server certificate is also valid for ::1
+        let client_config = ClientConfig::new().ca_file("certs/ca.crt");
+        assert!(
+            connect_async_with_config(&format!("wss://[::1]:{}", port), Some(client_config))
+                .await
+                .is_ok()
+        );
+        server.await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_display_and_source() {
+        use crate::error::Error as WSError;
+
+        let no_upgrade = WSError::NoUpgrade {
+            response: String::from("HTTP/1.1 403 Forbidden"),
+        };
+        assert_eq!(
+            format!("{}", no_upgrade),
+            "Server didn't upgrade the connection, responding with: HTTP/1.1 403 Forbidden"
+        );
+        assert_eq!(
+            format!("{}", WSError::CustomFragmentSizeExceeded(32, 16)),
+            "fragment_size: `32` can't be greater than max_frame_size: `16`"
+        );
+
+        let io_error = WSError::from(std::io::Error::other("connection reset"));
+        assert!(format!("{}", io_error).contains("connection reset"));
+        assert!(io_error.source().is_some());
+        assert!(no_upgrade.source().is_none());
+
+        // Can be converted to the standard boxed error, through the question mark operator
+        fn boxed() -> Result<(), Box<dyn Error + Send + Sync>> {
+            Err(WSError::InvalidAcceptKey {
+                response: String::new(),
+            })?
+        }
+        assert!(boxed().is_err());
+    }
+
+    #[test]
+    fn test_message_constructors_and_accessors() {
+        let text = Message::text("hello");
+        assert_eq!(text, Message::Text(String::from("hello")));
+        assert_eq!(text, Message::from("hello"));
+        assert_eq!(text, Message::from(String::from("hello")));
+        assert!(text.is_text());
+        assert_eq!(text.as_text(), Some("hello"));
+        assert_eq!(text.as_binary(), None);
+        assert_eq!(text.into_data(), b"hello".to_vec());
+
+        let binary = Message::binary([1u8, 2, 3]);
+        assert_eq!(binary, Message::from(vec![1u8, 2, 3]));
+        assert!(binary.is_binary());
+        assert_eq!(binary.as_binary(), Some(&[1u8, 2, 3][..]));
+        assert_eq!(binary.as_text(), None);
+
+        let ping = Message::ping(b"ping".to_vec());
+        assert!(ping.is_ping());
+        assert!(!ping.is_close());
+
+        let close = Message::close(CloseCode::Normal, "bye");
+        assert!(close.is_close());
+        assert_eq!(close.into_data(), b"bye".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_channel_capacity() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            for i in 0..10 {
+                server_connection.send_as_text(i.to_string()).await.unwrap();
+            }
+            server_connection
+        });
+
+        // A zero capacity is raised to one message, instead of panicking
+        let config = ClientConfig::new().channel_capacity(0);
+        let mut client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        for i in 0..10 {
+            let message = client_connection.next().await.unwrap()?;
+            assert_eq!(message.as_text().unwrap(), i.to_string());
+        }
+
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extended_payload_lengths() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        for size in [200, 70000] {
+            let payload: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            client.write_all(&masked_frame(0x82, &payload)).await?;
+            assert_eq!(
+                server_connection.next().await.unwrap()?,
+                Message::Binary(payload.into())
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_non_minimal_payload_length_rejected() -> Result<(), Box<dyn Error>> {
+        // A 16 bits length for a 5 bytes payload
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+        client
+            .write_all(&[0x82, 126 | 0x80, 0x00, 0x05, 0x12, 0x34, 0x56, 0x78])
+            .await?;
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::InvalidPayloadLength)
+        ));
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xea]);
+
+        // A 64 bits length for a 200 bytes payload
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+        let mut frame = vec![0x82, 127 | 0x80];
+        frame.extend_from_slice(&200u64.to_be_bytes());
+        client.write_all(&frame).await?;
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::InvalidPayloadLength)
+        ));
+
+        // A 64 bits length with the most significant bit set
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+        let mut frame = vec![0x82, 127 | 0x80];
+        frame.extend_from_slice(&(1u64 << 63 | 200).to_be_bytes());
+        client.write_all(&frame).await?;
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::InvalidPayloadLength)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_frame_written_byte_by_byte() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let writer = tokio::spawn(async move {
+            for byte in masked_frame(0x82, &payload) {
+                client.write_all(&[byte]).await.unwrap();
+                client.flush().await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            client
+        });
+
+        let message = server_connection.next().await.unwrap()?;
+        assert_eq!(
+            message.as_binary().unwrap(),
+            (0..300).map(|i| i as u8).collect::<Vec<u8>>()
+        );
+        writer.await?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "no-masking-check"))]
+    #[tokio::test]
+    async fn test_unmasked_client_frame_rejected() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client
+            .write_all(&[0x81, 5, b'h', b'e', b'l', b'l', b'o'])
+            .await?;
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::UnmaskedClientFrame)
+        ));
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xea]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_masked_server_frame_rejected() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = split(stream);
+            let mut buf_reader = BufReader::new(read);
+            let req = HttpRequest::parse_http_request(&mut buf_reader)
+                .await
+                .unwrap();
+            let accept_key =
+                compute_accept_key(&req.get_header_value(SEC_WEBSOCKET_KEY).unwrap());
+
+            let mut data = HTTP_ACCEPT_RESPONSE.replace("{}", &accept_key).into_bytes();
+            data.extend_from_slice(b"\r\n");
+            data.extend_from_slice(&masked_frame(0x81, b"hello"));
+            write.write_all(&data).await.unwrap();
+            // The client fails the connection with a close frame
+            buf_reader.read_u8().await.unwrap()
+        });
+
+        let mut client_connection = connect_async(&addr).await?;
+        assert!(matches!(
+            client_connection.next().await.unwrap(),
+            Err(crate::error::Error::MaskedServerFrame)
+        ));
+        assert_eq!(server.await?, 0x88);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_frames_masked_with_fresh_keys() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = split(stream);
+            let mut buf_reader = BufReader::new(read);
+            let req = HttpRequest::parse_http_request(&mut buf_reader)
+                .await
+                .unwrap();
+            let accept_key =
+                compute_accept_key(&req.get_header_value(SEC_WEBSOCKET_KEY).unwrap());
+            let response = HTTP_ACCEPT_RESPONSE.replace("{}", &accept_key) + "\r\n";
+            write.write_all(response.as_bytes()).await.unwrap();
+
+            // Two masked text frames, with a 5 bytes payload each
+            let mut frames = [[0u8; 11]; 2];
+            for frame in frames.iter_mut() {
+                buf_reader.read_exact(frame).await.unwrap();
+            }
+            frames
+        });
+
+        let mut client_connection = connect_async(&addr).await?;
+        for _ in 0..2 {
+            client_connection
+                .send_as_text(String::from("hello"))
+                .await?;
+        }
+
+        let frames = server.await?;
+        assert_ne!(frames[0], frames[1]);
+        for frame in frames {
+            assert_eq!(frame[..2], [0x81, 0x80 | 5]);
+            let mut payload = frame[6..].to_vec();
+            crate::masking::apply_mask(&mut payload, frame[2..6].try_into()?);
+            assert_eq!(payload, b"hello");
+        }
+        Ok(())
+    }
+
+    // Sends a malformed handshake request, asserting it's answered with a 400 Bad Request
+    async fn malformed_handshake(request: impl AsRef<[u8]>) -> crate::error::Error {
+        let (mut client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept_async(server));
+        client.write_all(request.as_ref()).await.unwrap();
+
+        let response = read_http_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        server.await.unwrap().err().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_malformed_handshake() -> Result<(), Box<dyn Error>> {
+        let request = CLIENT_HANDSHAKE_REQUEST.replacen("GET", "POST", 1);
+        assert!(matches!(
+            malformed_handshake(&request).await,
+            crate::error::Error::InvalidHTTPHandshake
+        ));
+
+        let request = CLIENT_HANDSHAKE_REQUEST.replacen("HTTP/1.1", "HTTP/1.0", 1);
+        assert!(matches!(
+            malformed_handshake(&request).await,
+            crate::error::Error::InvalidHTTPHandshake
+        ));
+
+        let request = CLIENT_HANDSHAKE_REQUEST.replacen("Host: 127.0.0.1\r\n", "", 1);
+        assert!(matches!(
+            malformed_handshake(&request).await,
+            crate::error::Error::NoHostHeaderPresent
+        ));
+
+        let request = CLIENT_HANDSHAKE_REQUEST.replacen("Sec-WebSocket-Key", "X-Key", 1);
+        assert!(matches!(
+            malformed_handshake(&request).await,
+            crate::error::Error::NoSecWebsocketKey
+        ));
+
+        assert!(matches!(
+            malformed_handshake("GET\r\n\r\n").await,
+            crate::error::Error::HttpParseError
+        ));
+
+        // The head is parsed as bytes, so a header that isn't valid UTF-8 is still answered
+        let request = CLIENT_HANDSHAKE_REQUEST.replacen("\r\n\r\n", "\r\nX-Name: caf", 1);
+        let request = [request.as_bytes(), b"\xe9\r\n\r\n"].concat();
+        assert!(matches!(
+            malformed_handshake(request).await,
+            crate::error::Error::HttpParseError
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_unsupported_version() -> Result<(), Box<dyn Error>> {
+        for version_header in ["Sec-WebSocket-Version: 8\r\n", ""] {
+            let (mut client, server) = duplex(1 << 16);
+            let server = tokio::spawn(accept_async(server));
+            let request = CLIENT_HANDSHAKE_REQUEST.replacen(
+                "Sec-WebSocket-Version: 13\r\n",
+                version_header,
+                1,
+            );
+            client.write_all(request.as_bytes()).await?;
+
+            let response = read_http_response(&mut client).await;
+            assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+            assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+            assert!(matches!(
+                server.await?,
+                Err(crate::error::Error::UnsupportedWebsocketVersion)
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_browser_connection_headers() -> Result<(), Box<dyn Error>> {
+        let request = CLIENT_HANDSHAKE_REQUEST
+            .replacen("Upgrade: websocket", "upgrade: WebSocket", 1)
+            .replacen("Connection: Upgrade", "Connection: keep-alive, Upgrade", 1);
+        let (mut client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept_async(server));
+        client.write_all(request.as_bytes()).await?;
+
+        let response = read_http_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols"));
+        assert!(server.await?.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_invalid_connection_headers() -> Result<(), Box<dyn Error>> {
+        let request = CLIENT_HANDSHAKE_REQUEST.replacen("Upgrade: websocket\r\n", "", 1);
+        assert!(matches!(
+            malformed_handshake(&request).await,
+            crate::error::Error::NoUpgradeHeaderPresent
+        ));
+
+        let request = CLIENT_HANDSHAKE_REQUEST.replacen("Upgrade: websocket", "Upgrade: h2c", 1);
+        assert!(matches!(
+            malformed_handshake(&request).await,
+            crate::error::Error::NoUpgradeHeaderPresent
+        ));
+
+        let request =
+            CLIENT_HANDSHAKE_REQUEST.replacen("Connection: Upgrade", "Connection: keep-alive", 1);
+        assert!(matches!(
+            malformed_handshake(&request).await,
+            crate::error::Error::NoConnectionHeaderPresent
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_send_and_recv_json() -> Result<(), Box<dyn Error>> {
+        #[derive(Debug, PartialEq, Serialize, serde::Deserialize)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+
+        let config = WebSocketConfig {
+            forward_pings: true,
+            ..Default::default()
+        };
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        // Pings are skipped, and invalid JSON is reported as an error
+        client.write_all(&masked_frame(0x89, b"ping")).await?;
+        client
+            .write_all(&masked_frame(0x81, br#"{"x":1,"y":2}"#))
+            .await?;
+        client.write_all(&masked_frame(0x81, b"not json")).await?;
+
+        let point: Point = server_connection.recv_json().await.unwrap()?;
+        assert_eq!(point, Point { x: 1, y: 2 });
+        assert!(matches!(
+            server_connection.recv_json::<Point>().await,
+            Some(Err(crate::error::Error::Json(_)))
+        ));
+
+        let mut pong = [0u8; 6];
+        client.read_exact(&mut pong).await?;
+        assert_eq!(pong[0], 0x8A);
+
+        server_connection.send_json(&Point { x: 3, y: 4 }).await?;
+        let mut frame = [0u8; 15];
+        client.read_exact(&mut frame).await?;
+        assert_eq!(&frame[..2], [0x81, 13]);
+        assert_eq!(&frame[2..], br#"{"x":3,"y":4}"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_addrs() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept_async(stream).await.unwrap()
+        });
+
+        let client_connection = connect_async(&format!("ws://{}", server_addr)).await?;
+        let server_connection = server.await?;
+        assert_eq!(client_connection.peer_addr(), Some(server_addr));
+        assert_eq!(server_connection.local_addr(), Some(server_addr));
+        assert!(server_connection.peer_addr().is_some());
+        assert_eq!(
+            server_connection.peer_addr(),
+            client_connection.local_addr()
+        );
+
+        // There are no addresses for in-memory streams
+        let (server_connection, _client) = raw_client_connection(None).await;
+        assert_eq!(server_connection.peer_addr(), None);
+        assert_eq!(server_connection.local_addr(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_addrs_tls() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = listener.local_addr()?;
+        let acceptor = tls_acceptor();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let tls_stream = acceptor.accept(stream).await.unwrap();
+            accept_async(SocketFlowStream::Secure(TlsStream::from(tls_stream)))
+                .await
+                .unwrap()
+        });
+
+        let client_config = ClientConfig::new().ca_file("certs/ca.crt");
+        let addr = format!("wss://localhost:{}", server_addr.port());
+        let client_connection = connect_async_with_config(&addr, Some(client_config)).await?;
+        let server_connection = server.await?;
+        assert_eq!(client_connection.peer_addr(), Some(server_addr));
+        assert_eq!(server_connection.local_addr(), Some(server_addr));
+        assert_eq!(
+            server_connection.peer_addr(),
+            client_connection.local_addr()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_task_aborted_on_drop() -> Result<(), Box<dyn Error>> {
+        let (server_connection, mut client) = raw_client_connection(None).await;
+        drop(server_connection);
+
+        // Once the read task is gone, the server side of the stream is dropped, closing it
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(1), client.read(&mut buf)).await??;
+        assert_eq!(read, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_task_kept_while_writer_alive() -> Result<(), Box<dyn Error>> {
+        let (server_connection, mut client) = raw_client_connection(None).await;
+        let (reader, writer) = server_connection.split();
+        drop(reader);
+
+        // Pings are still answered, while the writer is alive
+        client.write_all(&masked_frame(0x89, b"ping")).await?;
+        let mut pong = [0u8; 6];
+        client.read_exact(&mut pong).await?;
+        assert_eq!(pong[0], 0x8A);
+
+        drop(writer);
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(1), client.read(&mut buf)).await??;
+        assert_eq!(read, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_without_close_frame() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, client) = raw_client_connection(None).await;
+        drop(client);
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::ConnectionClosed)
+        ));
+        assert!(server_connection.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_reset_mid_frame() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+        let frame = masked_frame(0x81, b"hello");
+        client.write_all(&frame[..frame.len() - 2]).await?;
+        drop(client);
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::ConnectionReset)
+        ));
+        assert!(server_connection.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clean_close_ends_stream() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+        client.write_all(&masked_frame(0x88, &[0x03, 0xe8])).await?;
+        drop(client);
+
+        assert!(matches!(
+            server_connection.next().await.unwrap()?,
+            Message::Close {
+                code: Some(CloseCode::Normal),
+                ..
+            }
+        ));
+        assert!(server_connection.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_nodelay() -> Result<(), Box<dyn Error>> {
+        for nodelay in [true, false] {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = format!("ws://{}", listener.local_addr()?);
+            let client = tokio::spawn(async move { connect_async(&addr).await.unwrap() });
+
+            // The clone shares the same socket, so the option can be read back from it
+            let (stream, _) = listener.accept().await?;
+            let stream = stream.into_std()?;
+            let socket = stream.try_clone()?;
+            let stream = TcpStream::from_std(stream)?;
+            let accept_config = AcceptConfig::new().nodelay(nodelay);
+            let _server_connection = accept_async_with(stream, accept_config).await?;
+
+            assert_eq!(socket.nodelay()?, nodelay);
+            client.await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_connect_timeout() -> Result<(), Box<dyn Error>> {
+        // Once the backlog is full, new connections aren't accepted by the kernel,
+        // which behaves like an unreachable host
+        let socket = tokio::net::TcpSocket::new_v4()?;
+        socket.bind("127.0.0.1:0".parse()?)?;
+        let listener = socket.listen(0)?;
+        let addr = listener.local_addr()?;
+        let _queued = TcpStream::connect(addr).await?;
+
+        let config = ClientConfig::new().connect_timeout(Duration::from_millis(100));
+        let result = connect_async_with_config(&format!("ws://{}", addr), Some(config)).await;
+        assert!(matches!(result, Err(crate::error::Error::ConnectTimeout)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_to_http_request_basic_auth() {
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let config = ClientConfig::new();
+        let request = construct_http_request("ws://alice:secret@host/", key, &config).unwrap();
+        // base64 of alice:secret
+        assert!(request
+            .raw
+            .contains("Authorization: Basic YWxpY2U6c2VjcmV0\r\n"));
+        assert!(request.raw.contains("Host: host\r\n"));
+        assert!(!request.raw.contains("alice@"));
+        assert_eq!(request.host_with_port, "host:80");
+
+        // An explicit Authorization header takes precedence over the URL credentials
+        let config = ClientConfig::new().header("authorization", "Bearer token");
+        let request = construct_http_request("ws://alice:secret@host/", key, &config).unwrap();
+        assert!(!request.raw.contains("Basic"));
+        assert!(request.raw.contains("authorization: Bearer token\r\n"));
+
+        let request = construct_http_request("ws://host/", key, &ClientConfig::new()).unwrap();
+        assert!(!request.raw.contains("Authorization"));
+    }
+
+    #[tokio::test]
+    async fn test_close_without_payload() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x88, b"")).await?;
+
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Close {
+                code: Some(CloseCode::NoStatus),
+                reason: String::new(),
+            }
+        );
+        assert!(server_connection.next().await.is_none());
+
+        // 1005 is never sent, so the reply has a normal status code
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xe8]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_with_code_only() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x88, b"\x03\xe8")).await?;
+
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Close {
+                code: Some(CloseCode::Normal),
+                reason: String::new(),
+            }
+        );
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xe8]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_with_single_byte_payload() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x88, b"\x03")).await?;
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::InvalidClosePayload)
+        ));
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xea]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_status_close_sent_without_payload() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        server_connection
+            .send_message(Message::Close {
+                code: Some(CloseCode::NoStatus),
+                reason: String::new(),
+            })
+            .await?;
+
+        let mut close = [0u8; 2];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_close_code_validity() {
+        for code in [1000, 1003, 1007, 1014, 3000, 4000, 4999] {
+            assert!(CloseCode::from(code).is_valid(), "{code} should be valid");
+        }
+        for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            assert!(
+                !CloseCode::from(code).is_valid(),
+                "{code} should be invalid"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reserved_close_codes_received() -> Result<(), Box<dyn Error>> {
+        for payload in [b"\x03\xed", b"\x03\xee", b"\x03\xf7", b"\x07\xd0"] {
+            let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+            client.write_all(&masked_frame(0x88, payload)).await?;
+
+            assert!(matches!(
+                server_connection.next().await.unwrap(),
+                Err(crate::error::Error::InvalidCloseCode)
+            ));
+
+            let mut close = [0u8; 4];
+            client.read_exact(&mut close).await?;
+            assert_eq!(close, [0x88, 2, 0x03, 0xea]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_application_close_code_received() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x88, b"\x0f\xa0")).await?;
+
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Close {
+                code: Some(CloseCode::Other(4000)),
+                reason: String::new(),
+            }
+        );
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x0f, 0xa0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reserved_close_codes_not_sent() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        for code in [
+            CloseCode::NoStatus,
+            CloseCode::Abnormal,
+            CloseCode::Other(999),
+        ] {
+            assert!(matches!(
+                server_connection.close(code, None).await,
+                Err(crate::error::Error::InvalidCloseCode)
+            ));
+        }
+        assert!(matches!(
+            server_connection
+                .send_message(Message::close(CloseCode::Abnormal, ""))
+                .await,
+            Err(crate::error::Error::InvalidCloseCode)
+        ));
+
+        // Nothing was written, so the connection is still usable
+        server_connection
+            .send_as_text(String::from("still open"))
+            .await?;
+        let mut frame = [0u8; 12];
+        client.read_exact(&mut frame).await?;
+        assert_eq!(&frame[..2], &[0x81, 10]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rsv_bits_without_compression() -> Result<(), Box<dyn Error>> {
+        for first_byte in [0xC1, 0xA1, 0x91] {
+            let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+            client
+                .write_all(&masked_frame(first_byte, b"hello"))
+                .await?;
+
+            assert!(matches!(
+                server_connection.next().await.unwrap(),
+                Err(crate::error::Error::RSVNotZero)
+            ));
+
+            let mut close = [0u8; 4];
+            client.read_exact(&mut close).await?;
+            assert_eq!(close, [0x88, 2, 0x03, 0xea]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rsv_bits_with_compression() -> Result<(), Box<dyn Error>> {
+        let request = CLIENT_HANDSHAKE_REQUEST.replace(
+            "\r\n\r\n",
+            "\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+        );
+
+        // RSV1 marks a compressed data frame
+        let (mut server_connection, mut client) =
+            raw_client_connection_with(&request, Some(compression_config(true, true))).await;
+        let compressed = Encoder::new(true, None).compress(&mut BytesMut::from("hello"))?;
+        client.write_all(&masked_frame(0xC1, &compressed)).await?;
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Text(String::from("hello"))
+        );
+
+        // While RSV2 and RSV3 are still invalid, as well as RSV1 on control frames
+        for first_byte in [0xA1, 0x91, 0xC9] {
+            let (mut server_connection, mut client) =
+                raw_client_connection_with(&request, Some(compression_config(true, true))).await;
+
+            client
+                .write_all(&masked_frame(first_byte, b"hello"))
+                .await?;
+
+            assert!(matches!(
+                server_connection.next().await.unwrap(),
+                Err(crate::error::Error::RSVNotZero)
+            ));
+
+            let mut close = [0u8; 4];
+            client.read_exact(&mut close).await?;
+            assert_eq!(close, [0x88, 2, 0x03, 0xea]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oversized_ping_rejected() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x89, &[0u8; 200])).await?;
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::ControlFramePayloadSize)
+        ));
+
+        // No pong is sent, only the close frame
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xea]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_control_frame_rejected() -> Result<(), Box<dyn Error>> {
+        for first_byte in [0x09, 0x08] {
+            let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+            client.write_all(&masked_frame(first_byte, b"ping")).await?;
+
+            assert!(matches!(
+                server_connection.next().await.unwrap(),
+                Err(crate::error::Error::ControlFramesFragmented)
+            ));
+
+            let mut close = [0u8; 4];
+            client.read_exact(&mut close).await?;
+            assert_eq!(close, [0x88, 2, 0x03, 0xea]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_closes_connection() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        // Every received frame restarts the timeout
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.write_all(&masked_frame(0x81, b"hi")).await?;
+            assert_eq!(
+                server_connection.next().await.unwrap()?,
+                Message::Text(String::from("hi"))
+            );
+        }
+
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xe8]);
+        assert_eq!(client.read(&mut close).await?, 0);
+        assert!(server_connection.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_from_parts_with_prebuffered_bytes() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut reader, writer) = stream.into_split();
+
+            // Peeking at the request, like a server multiplexing HTTP and WebSockets would do
+            let mut peeked = vec![0u8; 4];
+            reader.read_exact(&mut peeked).await.unwrap();
+            assert_eq!(&peeked, b"GET ");
+
+            let mut server_connection = accept_async_from_parts(reader, writer, peeked)
+                .await
+                .unwrap();
+            let message = server_connection.next().await.unwrap().unwrap();
+            server_connection.send_message(message).await.unwrap();
+        });
+
+        let mut client_connection = connect_async(&addr).await?;
+        client_connection
+            .send_as_text(String::from("Hello World!"))
+            .await?;
+        assert_eq!(
+            client_connection.next().await.unwrap()?,
+            Message::Text(String::from("Hello World!"))
+        );
+
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_response_headers() -> Result<(), Box<dyn Error>> {
+        let (mut client, server) = duplex(1024);
+        let config = AcceptConfig::new()
+            .response_header("Set-Cookie", "session=abc")
+            .response_header("X-Server", "socket-flow");
+        let server = tokio::spawn(accept_async_with(server, config));
+
+        client
+            .write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes())
+            .await?;
+        let response = read_http_response(&mut client).await;
+
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        let accept_key = compute_accept_key("dGhlIHNhbXBsZSBub25jZQ==");
+        assert!(response.contains(&format!("Sec-WebSocket-Accept: {}\r\n", accept_key)));
+        assert!(response.contains("\r\nSet-Cookie: session=abc\r\n"));
+        assert!(response.contains("\r\nX-Server: socket-flow\r\n"));
+        assert!(server.await?.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_response_header_injection_rejected() -> Result<(), Box<dyn Error>> {
+        let (mut client, server) = duplex(1024);
+        let config = AcceptConfig::new().response_header("Set-Cookie", "a=b\r\nX-Injected: 1");
+        let server = tokio::spawn(accept_async_with(server, config));
+
+        client
+            .write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes())
+            .await?;
+
+        assert!(matches!(
+            server.await?,
+            Err(crate::error::Error::InvalidHeader)
+        ));
+        // Nothing is written, and the connection is dropped
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await?;
+        assert!(response.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_messages_not_interleaved() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            max_frame_size: Some(1000),
+            ..Default::default()
+        };
+        let (server_connection, mut client) = raw_client_connection(Some(config)).await;
+        let (_reader, writer) = server_connection.split();
+
+        let senders: Vec<_> = [b'a', b'b']
+            .into_iter()
+            .map(|byte| {
+                let writer = writer.clone();
+                tokio::spawn(async move { writer.send_as_binary(vec![byte; 10_000]).await })
+            })
+            .collect();
+        for sender in senders {
+            sender.await??;
+        }
+
+        // Each message is made of 10 fragments, which must be contiguous on the wire
+        let mut messages = Vec::new();
+        for _ in 0..2 {
+            let mut message = Vec::new();
+            loop {
+                let mut header = [0u8; 2];
+                client.read_exact(&mut header).await?;
+                assert_eq!(header[1], 126);
+                let expected_opcode = if message.is_empty() { 0x2 } else { 0x0 };
+                assert_eq!(header[0] & 0x0f, expected_opcode);
+
+                let mut payload = vec![0u8; client.read_u16().await? as usize];
+                client.read_exact(&mut payload).await?;
+                message.extend_from_slice(&payload);
+                if header[0] & 0x80 != 0 {
+                    break;
+                }
+            }
+            messages.push(message);
+        }
+
+        messages.sort();
+        assert_eq!(messages, [vec![b'a'; 10_000], vec![b'b'; 10_000]]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_messages_not_interleaved_with_fragments() -> Result<(), Box<dyn Error>>
+    {
+        let config = WebSocketConfig {
+            max_frame_size: Some(1000),
+            ..Default::default()
+        };
+        let (server_connection, mut client) = raw_client_connection(Some(config)).await;
+        let (_reader, mut writer) = server_connection.split();
+        let clones = [writer.clone(), writer.clone()];
+
+        let mut fragmented = writer.fragmented_binary();
+        fragmented.send(vec![b'f'; 500]).await?;
+        let senders: Vec<_> = clones
+            .into_iter()
+            .zip([b'a', b'b'])
+            .map(|(writer, byte)| {
+                tokio::spawn(async move { writer.send_as_binary(vec![byte; 3000]).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        fragmented.send(vec![b'f'; 500]).await?;
+        fragmented.finish(vec![b'f'; 500]).await?;
+        for sender in senders {
+            sender.await??;
+        }
+
+        // The fragmented message comes first, and each message is contiguous on the wire
+        let mut messages = Vec::new();
+        for _ in 0..3 {
+            let mut message = Vec::new();
+            loop {
+                let mut header = [0u8; 2];
+                client.read_exact(&mut header).await?;
+                let expected_opcode = if message.is_empty() { 0x2 } else { 0x0 };
+                assert_eq!(header[0] & 0x0f, expected_opcode);
+
+                let mut payload = vec![0u8; client.read_u16().await? as usize];
+                client.read_exact(&mut payload).await?;
+                message.extend_from_slice(&payload);
+                if header[0] & 0x80 != 0 {
+                    break;
+                }
+            }
+            messages.push(message);
+        }
+
+        assert_eq!(messages[0], vec![b'f'; 1500]);
+        messages[1..].sort();
+        assert_eq!(messages[1..], [vec![b'a'; 3000], vec![b'b'; 3000]]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_after_close() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+        assert!(server_connection.is_open());
+
+        let close_task = tokio::spawn(async move {
+            server_connection
+                .close_connection()
+                .await
+                .map(|_| server_connection)
+        });
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xe8]);
+        client.write_all(&masked_frame(0x88, b"\x03\xe8")).await?;
+
+        let mut server_connection = close_task.await??;
+        assert!(server_connection.is_closed());
+        assert!(matches!(
+            server_connection.send_as_text(String::from("late")).await,
+            Err(crate::error::Error::AlreadyClosed)
+        ));
+        assert!(matches!(
+            server_connection.close_connection().await,
+            Err(crate::error::Error::AlreadyClosed)
+        ));
+
+        // Nothing else is written after the close frame
+        assert_eq!(client.read(&mut close).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_after_peer_close() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client.write_all(&masked_frame(0x88, b"\x03\xe8")).await?;
+        assert!(server_connection.next().await.unwrap()?.is_close());
+        assert!(server_connection.is_closed());
+
+        assert!(matches!(
+            server_connection.send_as_binary(vec![1, 2, 3]).await,
+            Err(crate::error::Error::AlreadyClosed)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconnecting_client_recovers() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            // The first connection is dropped after receiving a message
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            server_connection.next().await.unwrap().unwrap();
+            drop(server_connection);
+
+            // While the second one echoes the messages back, checking the headers are sent again
+            let (stream, _) = listener.accept().await.unwrap();
+            let config = AcceptConfig::new().on_request(|request| {
+                match request.get_header_value("X-Client") {
+                    Some(_) => Ok(()),
+                    None => Err(String::from("missing header")),
+                }
+            });
+            let mut server_connection = accept_async_with(stream, config).await.unwrap();
+            let message = server_connection.next().await.unwrap().unwrap();
+            server_connection.send_message(message).await.unwrap();
+        });
+
+        let reconnect_config = ReconnectConfig {
+            base_delay: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut client = ReconnectingClient::connect(
+            &addr,
+            ClientConfig::new().header("X-Client", "test"),
+            reconnect_config,
+        )
+        .await?;
+        let mut reconnections = client.reconnections();
+
+        client.send_message(Message::text("first")).await?;
+        tokio::select! {
+            message = client.next() => panic!("unexpected message: {:?}", message),
+            changed = reconnections.changed() => changed?,
+        }
+        assert_eq!(*reconnections.borrow(), 1);
+
+        client.send_message(Message::text("second")).await?;
+        assert_eq!(client.next().await.unwrap()?, Message::text("second"));
+
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconnecting_client_send_only() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            // The first connection is dropped right away
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(accept_async(stream).await.unwrap());
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            server_connection.next().await.unwrap().unwrap()
+        });
+
+        let reconnect_config = ReconnectConfig {
+            base_delay: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut client =
+            ReconnectingClient::connect(&addr, ClientConfig::new(), reconnect_config).await?;
+        let reconnections = client.reconnections();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The stream is never polled, so the dropped connection is only found by the send
+        client.send_message(Message::text("after")).await?;
+        assert_eq!(server.await?, Message::text("after"));
+        assert_eq!(*reconnections.borrow(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconnecting_client_gives_up() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept_async(stream).await.unwrap()
+        });
+
+        let reconnect_config = ReconnectConfig {
+            base_delay: Duration::from_millis(10),
+            max_retries: Some(2),
+            ..Default::default()
+        };
+        let mut client =
+            ReconnectingClient::connect(&addr, ClientConfig::new(), reconnect_config).await?;
+
+        // Dropping the listener, so every reconnection attempt is refused
+        drop(server.await?);
+
+        assert!(client.next().await.unwrap().is_err());
+        assert!(client.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_stats() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+        assert_eq!(server_connection.stats(), ConnectionStats::default());
+
+        server_connection
+            .send_as_text(String::from("hello"))
+            .await?;
+        server_connection.send_as_binary(vec![0u8; 300]).await?;
+        server_connection.send_ping().await?;
+
+        // A fragmented message is counted once, and a ping is replied with a pong
+        client.write_all(&masked_frame(0x01, b"Hel")).await?;
+        client.write_all(&masked_frame(0x80, b"lo")).await?;
+        client.write_all(&masked_frame(0x89, b"ping")).await?;
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::text("Hello")
+        );
+
+        let mut frames = [0u8; 7 + 304 + 2 + 6];
+        client.read_exact(&mut frames).await?;
+        assert_eq!(
+            server_connection.stats(),
+            ConnectionStats {
+                messages_sent: 2,
+                messages_received: 1,
+                bytes_sent: 305,
+                bytes_received: 5,
+                control_frames_sent: 2,
+                control_frames_received: 1,
+            }
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_over_unix_socket() -> Result<(), Box<dyn Error>> {
+        use crate::handshake::connect_async_unix;
+        use tokio::net::UnixListener;
+
+        let path =
+            std::env::temp_dir().join(format!("socket-flow-{}.sock", rand::rng().random::<u64>()));
+        let listener = UnixListener::bind(&path)?;
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            let message = server_connection.next().await.unwrap().unwrap();
+            server_connection.send_message(message).await.unwrap();
+        });
+
+        let mut client_connection = connect_async_unix(&path, "ws://localhost/chat").await?;
+        client_connection
+            .send_as_text(String::from("Hello World!"))
+            .await?;
+        assert_eq!(
+            client_connection.next().await.unwrap()?,
+            Message::Text(String::from("Hello World!"))
+        );
+        assert_eq!(client_connection.peer_addr(), None);
+
+        server.await?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_frame_size_splits_messages() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            max_write_frame_size: Some(1000),
+            ..Default::default()
+        };
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        server_connection.send_as_binary(vec![7u8; 2500]).await?;
+
+        for (first_byte, length) in [(0x02, 1000), (0x00, 1000), (0x80, 500)] {
+            let mut header = [0u8; 4];
+            client.read_exact(&mut header).await?;
+            assert_eq!(header[0], first_byte);
+            assert_eq!(u16::from_be_bytes([header[2], header[3]]), length);
+            let mut payload = vec![0u8; length as usize];
+            client.read_exact(&mut payload).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_frame_size_round_trip() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let payload: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            server_connection.next().await.unwrap().unwrap()
+        });
+
+        let config = ClientConfig::new().max_write_frame_size(64 * 1024);
+        let mut client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        client_connection.send_as_binary(payload.clone()).await?;
+
+        assert_eq!(server.await?, Message::Binary(payload.into()));
+        Ok(())
+    }
+
+    fn ca_certificates() -> Vec<pki_types::CertificateDer<'static>> {
+        let mut ca_file = std::io::BufReader::new(std::fs::File::open("certs/ca.crt").unwrap());
+        rustls_pemfile::certs(&mut ca_file)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    // Echo server over TLS, returning the SNI name sent by the client
+    fn spawn_tls_echo_server(listener: TcpListener) -> tokio::task::JoinHandle<Option<String>> {
+        let acceptor = tls_acceptor();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let tls_stream = acceptor.accept(stream).await.ok()?;
+            let server_name = tls_stream.get_ref().1.server_name().map(String::from);
+            let mut server_connection =
+                accept_async(SocketFlowStream::Secure(TlsStream::from(tls_stream)))
+                    .await
+                    .unwrap();
+            if let Some(Ok(message)) = server_connection.next().await {
+                server_connection.send_message(message).await.unwrap();
+            }
+            server_name
+        })
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_tls_root_cert() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let server = spawn_tls_echo_server(listener);
+
+        let client_config = ClientConfig::new().tls_root_cert(ca_certificates());
+        let mut client_connection =
+            connect_async_with_config(&format!("wss://localhost:{}", port), Some(client_config))
+                .await?;
+        client_connection
+            .send_as_text(String::from("trusted"))
+            .await?;
+        let message = client_connection.next().await.unwrap()?;
+        assert_eq!(message.as_text(), Some("trusted"));
+
+        assert_eq!(server.await?.as_deref(), Some("localhost"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_tls_untrusted_cert() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let server = spawn_tls_echo_server(listener);
+
+        // Without the CA of the server, the self-signed chain isn't trusted
+        let result = connect_async(&format!("wss://localhost:{}", port)).await;
+        assert!(result.is_err());
+
+        assert_eq!(server.await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_tls_server_name_override() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let server = spawn_tls_echo_server(listener);
+
+        // Connecting by IP address, while sending and verifying the certificate name
+        let client_config = ClientConfig::new()
+            .tls_root_cert(ca_certificates())
+            .server_name("localhost");
+        let mut client_connection =
+            connect_async_with_config(&format!("wss://127.0.0.1:{}", port), Some(client_config))
+                .await?;
+        client_connection.send_as_text(String::from("sni")).await?;
+        let message = client_connection.next().await.unwrap()?;
+        assert_eq!(message.as_text(), Some("sni"));
+
+        assert_eq!(server.await?.as_deref(), Some("localhost"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_tls_danger_accept_invalid_certs() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let server = spawn_tls_echo_server(listener);
+
+        // Neither the CA is trusted, nor the name matches the certificate
+        let client_config = ClientConfig::new()
+            .danger_accept_invalid_certs(true)
+            .server_name("invalid.example");
+        let mut client_connection =
+            connect_async_with_config(&format!("wss://127.0.0.1:{}", port), Some(client_config))
+                .await?;
+        client_connection
+            .send_as_text(String::from("insecure"))
+            .await?;
+        let message = client_connection.next().await.unwrap()?;
+        assert_eq!(message.as_text(), Some("insecure"));
+
+        assert_eq!(server.await?.as_deref(), Some("invalid.example"));
+        Ok(())
+    }
+
+    // TLS acceptor requiring a client certificate signed by the test CA
+    fn mtls_acceptor() -> TlsAcceptor {
+        let mut cert_file =
+            std::io::BufReader::new(std::fs::File::open("certs/server.crt").unwrap());
+        let mut key_file =
+            std::io::BufReader::new(std::fs::File::open("certs/server.key").unwrap());
+        let certs = rustls_pemfile::certs(&mut cert_file)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = rustls_pemfile::private_key(&mut key_file).unwrap().unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in ca_certificates() {
+            roots.add(cert).unwrap();
+        }
+        let verifier = rustls::server::WebPkiClientVerifier::builder(Arc::new(roots))
+            .build()
+            .unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .unwrap();
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    async fn mtls_echo(client_config: ClientConfig) -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let acceptor = mtls_acceptor();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let tls_stream = acceptor.accept(stream).await.unwrap();
+            let client_certs = tls_stream.get_ref().1.peer_certificates().map(<[_]>::len);
+            let mut server_connection =
+                accept_async(SocketFlowStream::Secure(TlsStream::from(tls_stream)))
+                    .await
+                    .unwrap();
+            if let Some(Ok(message)) = server_connection.next().await {
+                server_connection.send_message(message).await.unwrap();
+            }
+            client_certs
+        });
+
+        let mut client_connection =
+            connect_async_with_config(&format!("wss://localhost:{}", port), Some(client_config))
+                .await?;
+        client_connection.send_as_text(String::from("mtls")).await?;
+        let message = client_connection.next().await.unwrap()?;
+        assert_eq!(message.as_text(), Some("mtls"));
+
+        assert_eq!(server.await?, Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_client_identity_pem() -> Result<(), Box<dyn Error>> {
+        let client_config = ClientConfig::new()
+            .tls_root_cert(ca_certificates())
+            .client_identity(
+                std::fs::read("certs/client.crt")?,
+                std::fs::read("certs/client.key")?,
+            );
+        mtls_echo(client_config).await
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_client_identity_der() -> Result<(), Box<dyn Error>> {
+        let cert_pem = std::fs::read("certs/client.crt")?;
+        let key_pem = std::fs::read("certs/client.key")?;
+        let cert = rustls_pemfile::certs(&mut cert_pem.as_slice())
+            .next()
+            .unwrap()?;
+        let key = rustls_pemfile::private_key(&mut key_pem.as_slice())?.unwrap();
+
+        let client_config = ClientConfig::new()
+            .tls_root_cert(ca_certificates())
+            .client_identity(cert.to_vec(), key.secret_der().to_vec());
+        mtls_echo(client_config).await
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_client_identity_invalid_key() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        let client_config = ClientConfig::new()
+            .tls_root_cert(ca_certificates())
+            .client_identity(std::fs::read("certs/client.crt")?, b"not a key".to_vec());
+        let result =
+            connect_async_with_config(&format!("wss://localhost:{}", port), Some(client_config))
+                .await;
+        assert!(matches!(result, Err(crate::error::Error::Tls(_))));
+
+        drop(listener);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_tls() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let acceptor = tls_acceptor();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async_tls(stream, &acceptor).await.unwrap();
+            let peer_addr = server_connection.peer_addr();
+            if let Some(Ok(message)) = server_connection.next().await {
+                server_connection.send_message(message).await.unwrap();
+            }
+            peer_addr
+        });
+
+        let client_config = ClientConfig::new().tls_root_cert(ca_certificates());
+        let mut client_connection =
+            connect_async_with_config(&format!("wss://localhost:{}", port), Some(client_config))
+                .await?;
+        client_connection
+            .send_as_text(String::from("served over tls"))
+            .await?;
+        let message = client_connection.next().await.unwrap()?;
+        assert_eq!(message.as_text(), Some("served over tls"));
+
+        // The TCP stream is still known under the TLS one
+        assert_eq!(server.await?, client_connection.local_addr());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_tls_rejects_plain_client() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let acceptor = tls_acceptor();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let accept_config = AcceptConfig::new().handshake_timeout(Duration::from_secs(5));
+            accept_async_tls_with(stream, &acceptor, accept_config)
+                .await
+                .err()
+        });
+
+        // A plain websocket handshake isn't a TLS client hello
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream
+            .write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes())
+            .await?;
+
+        assert!(matches!(
+            server.await?,
+            Some(crate::error::Error::IOError { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_bytes_without_copying() {
+        let data = Bytes::from(vec![7u8; 1024]);
+        let message = Message::from(data.clone());
+        assert_eq!(message, Message::binary(vec![7u8; 1024]));
+        assert_eq!(message.as_binary().map(<[u8]>::as_ptr), Some(data.as_ptr()));
+        assert_eq!(message.into_bytes().as_ptr(), data.as_ptr());
+    }
+
+    #[tokio::test]
+    async fn test_send_shared_bytes() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let config = WebSocketConfig {
+            max_write_frame_size: Some(1000),
+            ..Default::default()
+        };
+
+        // The payload is split into frames, and a slice of it is sent as a single frame
+        let payload = Bytes::from((0..=255u8).cycle().take(4096).collect::<Vec<u8>>());
+        let server_payload = payload.clone();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async_with_config(stream, Some(config))
+                .await
+                .unwrap();
+            server_connection
+                .send_as_binary(server_payload.clone())
+                .await
+                .unwrap();
+            server_connection
+                .send_as_binary(server_payload.slice(..100))
+                .await
+                .unwrap();
+        });
+
+        let mut client_connection = connect_async(&format!("ws://127.0.0.1:{}", port)).await?;
+        let message = client_connection.next().await.unwrap()?;
+        assert_eq!(message.into_bytes(), payload);
+        let message = client_connection.next().await.unwrap()?;
+        assert_eq!(message.into_bytes(), payload.slice(..100));
+
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_buffers_reused_across_messages() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        // Single frames share the read buffer, and fragmented messages the reassembly one,
+        // so messages already received must not change while the next ones are read
+        let mut frames = Vec::new();
+        for i in 0..3u8 {
+            frames.extend(masked_frame(0x82, &[i; 100]));
+            frames.extend(masked_frame(0x02, &[i; 50]));
+            frames.extend(masked_frame(0x00, &[i + 10; 50]));
+            frames.extend(masked_frame(0x80, &[i + 20; 50]));
+        }
+        client.write_all(&frames).await?;
+
+        let mut messages = Vec::new();
+        for _ in 0..6 {
+            messages.push(server_connection.next().await.unwrap()?.into_bytes());
+        }
+        for (i, chunk) in messages.chunks(2).enumerate() {
+            let i = i as u8;
+            assert_eq!(chunk[0], vec![i; 100]);
+            let fragmented = [vec![i; 50], vec![i + 10; 50], vec![i + 20; 50]].concat();
+            assert_eq!(chunk[1], fragmented);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_all() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            max_message_size: Some(100),
+            ..Default::default()
+        };
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        let messages = (0..3).map(|i| Message::text(format!("message {}", i)));
+        server_connection.send_all(messages).await?;
+
+        // The batch stops at the first message that fails
+        let result = server_connection
+            .send_all([
+                Message::text("fourth"),
+                Message::binary(vec![0u8; 101]),
+                Message::text("never sent"),
+            ])
+            .await;
+        match result {
+            Err(crate::error::Error::SendAllFailed { written, source }) => {
+                assert_eq!(written, 1);
+                assert!(matches!(*source, crate::error::Error::MaxMessageSize));
+            }
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+
+        let mut expected = Vec::new();
+        for text in ["message 0", "message 1", "message 2", "fourth"] {
+            expected.extend([0x81, text.len() as u8]);
+            expected.extend(text.as_bytes());
+        }
+        let mut received = vec![0u8; expected.len()];
+        client.read_exact(&mut received).await?;
+        assert_eq!(received, expected);
+
+        // No message can follow a close frame in the batch
+        let result = server_connection
+            .send_all([
+                Message::close(CloseCode::Normal, ""),
+                Message::text("closed"),
+            ])
+            .await;
+        match result {
+            Err(crate::error::Error::SendAllFailed { written, source }) => {
+                assert_eq!(written, 1);
+                assert!(matches!(*source, crate::error::Error::AlreadyClosed));
+            }
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recv_timeout() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        // The server delays its reply, so the first read times out, but the reply
+        // is still delivered on the next read
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            let request = server_connection.next().await.unwrap().unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            server_connection.send_message(request).await.unwrap();
+            server_connection.close_connection().await.unwrap();
+        });
+
+        let mut client_connection = connect_async(&addr).await?;
+        client_connection
+            .send_as_text(String::from("request"))
+            .await?;
+        let result = client_connection
+            .recv_timeout(Duration::from_millis(50))
+            .await;
+        assert!(matches!(result, Err(crate::error::Error::ReadTimeout)));
+
+        let reply = client_connection
+            .recv_timeout(Duration::from_secs(5))
+            .await?;
+        assert_eq!(reply, Some(Message::Text(String::from("request"))));
+
+        // Once the connection is closed, None is returned
+        let mut result = client_connection
+            .recv_timeout(Duration::from_secs(5))
+            .await?;
+        if matches!(result, Some(Message::Close { .. })) {
+            result = client_connection
+                .recv_timeout(Duration::from_secs(5))
+                .await?;
+        }
+        assert_eq!(result, None);
+
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_uncompressed() -> Result<(), Box<dyn Error>> {
+        let request = CLIENT_HANDSHAKE_REQUEST.replace(
+            "\r\n\r\n",
+            "\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+        );
+        let (mut server_connection, mut client) =
+            raw_client_connection_with(&request, Some(compression_config(true, true))).await;
+        let payload = vec![7u8; 20];
+
+        // Compressed messages have RSV1 set
+        server_connection
+            .send_message(Message::binary(payload.clone()))
+            .await?;
+        let mut header = [0u8; 2];
+        client.read_exact(&mut header).await?;
+        assert_eq!(header[0], 0xC2);
+        let mut compressed = vec![0u8; header[1] as usize];
+        client.read_exact(&mut compressed).await?;
+        assert_ne!(compressed, payload);
+
+        // While opted-out messages have RSV1 clear, and are sent as is
+        server_connection
+            .send_uncompressed(Message::binary(payload.clone()))
+            .await?;
+        let mut frame = [0u8; 22];
+        client.read_exact(&mut frame).await?;
+        assert_eq!(frame[..2], [0x82, 20]);
+        assert_eq!(frame[2..], payload[..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        // Messages are submitted from cloned writers, and through the Sink, right before
+        // the shutdown, and all of them must be written before the close frame
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let server_connection = accept_async(stream).await.unwrap();
+            let (_reader, mut writer) = server_connection.split();
+            let mut senders = Vec::new();
+            for i in 0..5 {
+                let writer = writer.clone();
+                senders.push(tokio::spawn(async move {
+                    writer.send_as_text(format!("task {}", i)).await
+                }));
+            }
+            futures::SinkExt::feed(&mut writer, Message::Text(String::from("sink")))
+                .await
+                .unwrap();
+            // Waiting for the tasks to be queued on the writer
+            tokio::task::yield_now().await;
+            writer.shutdown().await.unwrap();
+
+            // Sends submitted after the shutdown are rejected
+            assert!(matches!(
+                writer.send_as_text(String::from("late")).await,
+                Err(crate::error::Error::AlreadyClosed)
+            ));
+            for sender in senders {
+                sender.await.unwrap().unwrap();
+            }
+        });
+
+        let mut client_connection = connect_async(&addr).await?;
+        let mut received = Vec::new();
+        loop {
+            match client_connection.next().await.unwrap()? {
+                Message::Text(text) => received.push(text),
+                Message::Close { code, .. } => {
+                    assert_eq!(code, Some(CloseCode::GoingAway));
+                    break;
+                }
+                message => panic!("unexpected message: {:?}", message),
+            }
+        }
+        received.sort();
+        assert_eq!(
+            received,
+            ["sink", "task 0", "task 1", "task 2", "task 3", "task 4"]
+        );
+        assert!(client_connection.next().await.is_none());
+
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_hdr_async() -> Result<(), Box<dyn Error>> {
+        // Accepts requests with the token, and rejects the other ones with a 401 Unauthorized
+        let callback =
+            |request: &HttpRequest| match request.get_header_value("Authorization").as_deref() {
+                Some("Bearer token") => Ok(HandshakeResponse::new()
+                    .protocol("chat")
+                    .header("Set-Cookie", "session=1")),
+                _ => Err(ErrorResponse::new(401)
+                    .header("WWW-Authenticate", "Bearer")
+                    .body("missing token")),
+            };
+
+        let (mut client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept_hdr_async(server, callback));
+        let request = CLIENT_HANDSHAKE_REQUEST.replace(
+            "\r\n\r\n",
+            "\r\nAuthorization: Bearer token\r\nSec-WebSocket-Protocol: chat\r\n\r\n",
+        );
+        client.write_all(request.as_bytes()).await?;
+        let response = read_http_response(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols"));
+        assert!(response.contains("Sec-WebSocket-Protocol: chat\r\n"));
+        assert!(response.contains("Set-Cookie: session=1\r\n"));
+        assert_eq!(server.await?.unwrap().protocol(), Some("chat"));
+
+        let (mut client, server) = duplex(1 << 16);
+        let server = tokio::spawn(accept_hdr_async(server, callback));
+        client
+            .write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes())
+            .await?;
+        assert!(matches!(
+            server.await?,
+            Err(crate::error::Error::HandshakeRejected(reason)) if reason == "missing token"
+        ));
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(response.contains("WWW-Authenticate: Bearer\r\n"));
+        assert!(response.ends_with("\r\n\r\nmissing token"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_hdr_async_protocol_not_offered() -> Result<(), Box<dyn Error>> {
+        let callback = |_: &HttpRequest| Ok(HandshakeResponse::new().protocol("chat"));
+        let offered = CLIENT_HANDSHAKE_REQUEST.replace(
+            "\r\n\r\n",
+            "\r\nSec-WebSocket-Protocol: mqtt, chat-v2\r\n\r\n",
+        );
+        for request in [CLIENT_HANDSHAKE_REQUEST, &offered] {
+            let (mut client, server) = duplex(1 << 16);
+            let server = tokio::spawn(accept_hdr_async(server, callback));
+            client.write_all(request.as_bytes()).await?;
+
+            // The client would fail the handshake, so it's reported to both sides instead
+            let response = read_http_response(&mut client).await;
+            assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+            assert!(!response.contains("Sec-WebSocket-Protocol"));
+            assert!(matches!(
+                server.await?,
+                Err(crate::error::Error::InvalidSubprotocol)
+            ));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_with_payload() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            // Pings are answered automatically, until the client closes the connection
+            while let Some(Ok(_)) = server_connection.next().await {}
+        });
+
+        let config = ClientConfig::new().web_socket_config(WebSocketConfig {
+            forward_pongs: true,
+            ..Default::default()
+        });
+        let mut client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        client_connection.ping(vec![1, 2, 3]).await?;
+        assert_eq!(
+            client_connection.next().await.unwrap()?,
+            Message::Pong(vec![1, 2, 3])
+        );
+
+        // Control frames payload can't exceed 125 bytes
+        assert!(matches!(
+            client_connection.ping(vec![0; 126]).await,
+            Err(crate::error::Error::ControlFramePayloadSize)
+        ));
+
+        client_connection.close_connection().await?;
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_error_propagated_to_senders() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut client = TcpStream::connect(listener.local_addr()?).await?;
+        let (stream, _) = listener.accept().await?;
+        let server = tokio::spawn(accept_async(stream));
+        client
+            .write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes())
+            .await?;
+        read_http_response(&mut client).await;
+        let (mut reader, writer) = server.await??.split();
+
+        // A sender waiting for the reset to be detected by the read side
+        let sender = writer.clone();
+        let notify = Arc::new(tokio::sync::Notify::new());
+        let notified = notify.clone();
+        let in_flight = tokio::spawn(async move {
+            notified.notified().await;
+            sender.send_as_text(String::from("hello")).await
+        });
+
+        // The peer resets the connection, closing the socket without lingering.
+        // With a zero linger, dropping the socket doesn't block, despite the deprecation
+        #[allow(deprecated)]
+        client.set_linger(Some(Duration::ZERO))?;
+        drop(client);
+        assert!(matches!(
+            reader.next().await.unwrap(),
+            Err(crate::error::Error::ConnectionReset)
+        ));
+
+        // Senders get the reset error, instead of a generic one
+        notify.notify_one();
+        assert!(matches!(
+            in_flight.await?,
+            Err(crate::error::Error::ConnectionReset)
+        ));
+        assert!(matches!(
+            writer.send_as_binary(vec![1, 2, 3]).await,
+            Err(crate::error::Error::ConnectionReset)
+        ));
+        assert!(matches!(
+            writer.close_connection().await,
+            Err(crate::error::Error::ConnectionReset)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_flood() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, client) = raw_client_connection(None).await;
+        let (mut client_read, mut client_write) = split(client);
+
+        // Written all at once, so pings are answered faster than they arrive
+        let flood = tokio::spawn(async move {
+            let ping = masked_frame(0x89, b"");
+            let _ = client_write.write_all(&ping.repeat(10_000)).await;
+            client_write
+        });
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::PingFlood)
+        ));
+
+        // Only some of the pings are answered, before closing with a Policy Violation
+        let mut pongs = 0;
+        loop {
+            let mut header = [0u8; 2];
+            client_read.read_exact(&mut header).await?;
+            let mut payload = vec![0u8; header[1] as usize];
+            client_read.read_exact(&mut payload).await?;
+            match header[0] {
+                0x8A => pongs += 1,
+                0x88 => {
+                    assert_eq!(payload, [0x03, 0xf0]);
+                    break;
+                }
+                first_byte => panic!("unexpected frame: {:x}", first_byte),
+            }
+        }
+        assert!(pongs < 100, "{} pongs", pongs);
+        flood.await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_extension_offers() {
+        let parse = |value: &str| parse_extensions(value.to_string());
+
+        // The first acceptable offer is selected
+        let extensions = parse(
+            "permessage-deflate; client_max_window_bits=10; server_max_window_bits=12, \
+             permessage-deflate",
+        )
+        .unwrap();
+        assert_eq!(extensions.client_max_window_bits, Some(10));
+        assert_eq!(extensions.server_max_window_bits, Some(12));
+
+        // Other extensions, and offers with unknown parameters, are skipped
+        let extensions = parse(
+            "x-webkit-deflate-frame, permessage-deflate; unknown_param, \
+             permessage-deflate; client_max_window_bits; server_no_context_takeover",
+        )
+        .unwrap();
+        assert_eq!(extensions.client_max_window_bits, Some(15));
+        assert_eq!(extensions.server_no_context_takeover, Some(true));
+        assert_eq!(extensions.client_no_context_takeover, None);
+
+        // Values may be quoted, and a quoted comma doesn't split the offers
+        let extensions = parse("permessage-deflate; client_max_window_bits=\"10\"").unwrap();
+        assert_eq!(extensions.client_max_window_bits, Some(10));
+        assert!(parse("permessage-deflate; x=\"a, permessage-deflate\"").is_none());
+
+        // Repeated parameters, and invalid values, decline the offer
+        let extensions = parse(
+            "permessage-deflate; server_max_window_bits=abc, \
+             permessage-deflate; client_no_context_takeover; client_no_context_takeover, \
+             permessage-deflate; server_max_window_bits=9",
+        )
+        .unwrap();
+        assert_eq!(extensions.server_max_window_bits, Some(9));
+        assert_eq!(extensions.client_no_context_takeover, None);
+
+        assert!(parse("").is_none());
+        assert!(parse("permessage-deflate; server_max_window_bits").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_extension_offers_in_multiple_headers() -> Result<(), Box<dyn Error>> {
+        let request = CLIENT_HANDSHAKE_REQUEST.replace(
+            "\r\n\r\n",
+            "\r\nSec-WebSocket-Extensions: x-unknown\r\n\
+             Sec-WebSocket-Extensions: permessage-deflate; client_no_context_takeover\r\n\r\n",
+        );
+        let (server_connection, _client) =
+            raw_client_connection_with(&request, Some(compression_config(true, false))).await;
+        let deflate = server_connection.extensions().permessage_deflate.unwrap();
+        assert!(deflate.client_no_context_takeover);
+        Ok(())
+    }
+
+    fn window_bits_config(window_bits: u8) -> WebSocketConfig {
+        WebSocketConfig {
+            extensions: Some(Extensions {
+                permessage_deflate: true,
+                client_max_window_bits: Some(window_bits),
+                server_max_window_bits: Some(window_bits),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_window_bits_round_trip() -> Result<(), Box<dyn Error>> {
+        for window_bits in [10, 8] {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = format!("ws://{}", listener.local_addr()?);
+
+            // Echoes back every message, compressed with the negotiated window
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut server_connection =
+                    accept_async_with_config(stream, Some(window_bits_config(window_bits)))
+                        .await
+                        .unwrap();
+                let extensions = server_connection.extensions().permessage_deflate.unwrap();
+                assert_eq!(extensions.client_max_window_bits, Some(window_bits));
+                assert_eq!(extensions.server_max_window_bits, Some(window_bits));
+                while let Some(Ok(message)) = server_connection.next().await {
+                    if message.is_binary() {
+                        server_connection.send_message(message).await.unwrap();
+                    }
+                }
+            });
+
+            let config = ClientConfig::new().web_socket_config(window_bits_config(window_bits));
+            let mut client_connection = connect_async_with_config(&addr, Some(config)).await?;
+            let extensions = client_connection.extensions().permessage_deflate.unwrap();
+            assert_eq!(extensions.client_max_window_bits, Some(window_bits));
+
+            // Repeating blocks larger than the window, so matches can only be found within it
+            let block: Vec<u8> = (0..3000u32).map(|i| (i * 7919 % 251) as u8).collect();
+            for repeat in 1..4 {
+                let payload = block.repeat(repeat);
+                client_connection.send_as_binary(payload.clone()).await?;
+                let echoed = client_connection.next().await.unwrap()?;
+                assert_eq!(echoed.as_binary(), Some(&payload[..]));
+            }
+
+            client_connection.close_connection().await?;
+            server.await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_window_bits_out_of_range() -> Result<(), Box<dyn Error>> {
+        // Offers out of range are declined by the server
+        assert!(parse_extensions("permessage-deflate; server_max_window_bits=16".into()).is_none());
+        assert!(parse_extensions("permessage-deflate; client_max_window_bits=7".into()).is_none());
+
+        // The client doesn't offer invalid window sizes
+        let config = ClientConfig::new().web_socket_config(window_bits_config(16));
+        assert!(matches!(
+            connect_async_with_config("ws://127.0.0.1:1", Some(config)).await,
+            Err(crate::error::Error::InvalidMaxWindowBits)
+        ));
+
+        // A server response out of range fails the handshake
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let response = "HTTP/1.1 101 Switching Protocols\r\n\
+                        Upgrade: websocket\r\n\
+                        Connection: Upgrade\r\n\
+                        Sec-WebSocket-Accept: {}\r\n\
+                        Sec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=20\r\n\r\n";
+        let server = tokio::spawn(fake_server_response(listener, response.to_string()));
+        let config = ClientConfig::new().web_socket_config(compression_config(false, false));
+        assert!(matches!(
+            connect_async_with_config(&addr, Some(config)).await,
+            Err(crate::error::Error::InvalidMaxWindowBits)
+        ));
+        server.await?;
+
+        // As well as a server configured out of range, which is reported to the server only,
+        // since it isn't a mistake of the client
+        let (mut client, server) = duplex(1024);
+        let request = CLIENT_HANDSHAKE_REQUEST.replace(
+            "\r\n\r\n",
+            "\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+        );
+        client.write_all(request.as_bytes()).await?;
+        assert!(matches!(
+            accept_async_with_config(server, Some(window_bits_config(20))).await,
+            Err(crate::error::Error::InvalidMaxWindowBits)
+        ));
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await?;
+        assert!(response.is_empty());
+
+        let rejection = crate::handshake::upgrade_response(
+            "/",
+            [
+                ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+                ("Sec-WebSocket-Version", "13"),
+            ],
+            AcceptConfig::new().web_socket_config(window_bits_config(20)),
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            rejection.error,
+            crate::error::Error::InvalidMaxWindowBits
+        ));
+        assert!(rejection.response.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_rejects_extensions_not_offered() -> Result<(), Box<dyn Error>> {
+        let cases = [
+            // Compression accepted without being offered
+            (None, "permessage-deflate"),
+            // The client window limited, without the client offering it
+            (
+                Some(compression_config(false, false)),
+                "permessage-deflate; client_max_window_bits=10",
+            ),
+            // A window larger than the offered one
+            (
+                Some(window_bits_config(10)),
+                "permessage-deflate; server_max_window_bits=12",
+            ),
+            // Unknown extensions and parameters
+            (
+                Some(compression_config(false, false)),
+                "x-webkit-deflate-frame",
+            ),
+            (
+                Some(compression_config(false, false)),
+                "permessage-deflate; foo",
+            ),
+        ];
+        for (config, extensions) in cases {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = format!("ws://{}", listener.local_addr()?);
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {{}}\r\n\
+                 Sec-WebSocket-Extensions: {}\r\n\r\n",
+                extensions
+            );
+            let server = tokio::spawn(fake_server_response(listener, response));
+            let mut client_config = ClientConfig::new();
+            if let Some(config) = config {
+                client_config = client_config.web_socket_config(config);
+            }
+            match connect_async_with_config(&addr, Some(client_config)).await {
+                Err(crate::error::Error::InvalidExtensionResponse {
+                    extensions: accepted,
+                }) => {
+                    assert_eq!(accepted, extensions);
+                }
+                result => panic!("unexpected result for {}: {:?}", extensions, result.is_ok()),
+            }
+            server.await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_window_bits_only_when_offered() -> Result<(), Box<dyn Error>> {
+        for (offer, expected) in [
+            (
+                "permessage-deflate",
+                "Sec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=10\r\n",
+            ),
+            (
+                "permessage-deflate; client_max_window_bits",
+                "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=10; server_max_window_bits=10\r\n",
+            ),
+        ] {
+            let (mut client, server) = duplex(1024);
+            let server = tokio::spawn(accept_async_with_config(
+                server,
+                Some(window_bits_config(10)),
+            ));
+            let request = CLIENT_HANDSHAKE_REQUEST.replace(
+                "\r\n\r\n",
+                &format!("\r\nSec-WebSocket-Extensions: {}\r\n\r\n", offer),
+            );
+            client.write_all(request.as_bytes()).await?;
+
+            let response = read_http_response(&mut client).await;
+            assert!(response.contains(expected), "{}", response);
+            server.await??;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_limiter() -> Result<(), Box<dyn Error>> {
+        let limiter = ConnectionLimiter::new(1);
+        let accept_config = AcceptConfig::new().connection_limiter(limiter.clone());
+
+        let (mut first_client, server) = duplex(1024);
+        first_client
+            .write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes())
+            .await?;
+        let first_connection = accept_async_with(server, accept_config.clone()).await?;
+        assert!(read_http_response(&mut first_client)
+            .await
+            .starts_with("HTTP/1.1 101"));
+        assert_eq!(limiter.active_connections(), 1);
+
+        // The second connection is above the limit
+        let (mut second_client, server) = duplex(1024);
+        second_client
+            .write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes())
+            .await?;
+        assert!(matches!(
+            accept_async_with(server, accept_config.clone()).await,
+            Err(crate::error::Error::TooManyConnections)
+        ));
+        assert!(read_http_response(&mut second_client)
+            .await
+            .starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        // Dropping the first connection releases its permit
+        drop(first_connection);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while limiter.active_connections() > 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+
+        let (mut third_client, server) = duplex(1024);
+        third_client
+            .write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes())
+            .await?;
+        accept_async_with(server, accept_config).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_broadcast() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let mut clients = Vec::new();
+        let mut broadcaster = Broadcaster::new();
+        let mut readers = Vec::new();
+        for _ in 0..3 {
+            let (client, accepted) = tokio::join!(connect_async(&addr), async {
+                let (stream, _) = listener.accept().await.unwrap();
+                accept_async(stream).await.unwrap()
+            });
+            clients.push(client?);
+            let (reader, writer) = accepted.split();
+            broadcaster.add(writer);
+            readers.push(reader);
+        }
+
+        let sent = broadcaster
+            .broadcast(Message::Text("hello everyone".to_string()))
+            .await?;
+        assert_eq!(sent, 3);
+        for client in clients.iter_mut() {
+            assert_eq!(
+                client.next().await.unwrap()?.as_text(),
+                Some("hello everyone")
+            );
+        }
+
+        // Closed connections are removed from the broadcaster
+        let mut closed = clients.remove(0);
+        closed.close_connection().await?;
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while readers[0].next().await.is_some() {}
+        })
+        .await?;
+        let sent = broadcaster
+            .broadcast(Message::Binary(Bytes::from_static(b"still here")))
+            .await?;
+        assert_eq!(sent, 2);
+        assert_eq!(broadcaster.len(), 2);
+        for client in clients.iter_mut() {
+            assert_eq!(
+                client.next().await.unwrap()?.as_binary(),
+                Some(&b"still here"[..])
+            );
+        }
+
+        // Invalid messages aren't sent to anyone
+        assert!(matches!(
+            broadcaster.broadcast(Message::Ping(vec![0; 126])).await,
+            Err(crate::error::Error::ControlFramePayloadSize)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_frames_server() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut raw_connection = accept_raw(stream).await.unwrap();
+
+            // Frames are received as they are, with the masking key used by the client
+            let frame = raw_connection.read_frame().await.unwrap().unwrap();
+            assert!(frame.fin);
+            assert_eq!((frame.rsv, frame.opcode), (0, 0x1));
+            assert!(frame.mask.is_some());
+            assert_eq!(frame.payload, "hello"[..]);
+
+            // A fragmented message, written frame by frame
+            let first = RawFrame {
+                fin: false,
+                ..RawFrame::new(0x1, "frag")
+            };
+            raw_connection.send_frame(first).await.unwrap();
+            raw_connection
+                .send_frame(RawFrame::new(0x0, "mented"))
+                .await
+                .unwrap();
+            raw_connection
+        });
+
+        let mut client_connection = connect_async(&addr).await?;
+        client_connection.send_as_text("hello".to_string()).await?;
+        assert_eq!(
+            client_connection.next().await.unwrap()?.as_text(),
+            Some("fragmented")
+        );
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_frames_client() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            while let Some(Ok(message)) = server_connection.next().await {
+                if message.is_binary() {
+                    server_connection.send_message(message).await.unwrap();
+                }
+            }
+        });
+
+        let (mut reader, mut writer) = connect_raw(&addr, None).await?.split();
+
+        // Clients mask the frames with a fresh key, unless one is given
+        writer.send_frame(RawFrame::new(0x9, "ping")).await?;
+        let pong = reader.read_frame().await?.unwrap();
+        assert_eq!((pong.opcode, pong.mask), (0xA, None));
+        assert_eq!(pong.payload, "ping"[..]);
+
+        let payload = vec![7u8; 70_000];
+        let frame = RawFrame {
+            mask: Some([1, 2, 3, 4]),
+            ..RawFrame::new(0x2, payload.clone())
+        };
+        writer.send_frame(frame).await?;
+        let mut frames = reader.into_stream();
+        let echoed = frames.next().await.unwrap()?;
+        assert_eq!((echoed.fin, echoed.opcode), (true, 0x2));
+        assert_eq!(echoed.payload, payload);
+
+        // Reserved bits are written as they are, making the server fail the connection
+        let frame = RawFrame {
+            rsv: 0b010,
+            ..RawFrame::new(0x1, "")
+        };
+        writer.send_frame(frame).await?;
+        let close = frames.next().await.unwrap()?;
+        assert_eq!(close.opcode, 0x8);
+        assert_eq!(close.payload[..2], [0x03, 0xea]);
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_invalid_upgrade_response() -> Result<(), Box<dyn Error>> {
+        let responses = [
+            // Without the Upgrade header
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            // Upgrading to another protocol
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: h2c\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            // Without the upgrade token in the Connection header
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: keep-alive\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+        ];
+        for response in responses {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = format!("ws://{}", listener.local_addr()?);
+            let server = tokio::spawn(fake_server_response(listener, response.to_string()));
+            match connect_async(&addr).await {
+                Err(crate::error::Error::InvalidUpgradeResponse { response }) => {
+                    assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+                }
+                _ => panic!("expected InvalidUpgradeResponse"),
+            }
+            server.await?;
+        }
+
+        // Header names and values are case-insensitive
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let response = "HTTP/1.1 101 Switching Protocols\r\n\
+                        upgrade: WebSocket\r\n\
+                        connection: keep-alive, UPGRADE\r\n\
+                        Sec-WebSocket-Accept: {}\r\n\r\n";
+        let server = tokio::spawn(fake_server_response(listener, response.to_string()));
+        connect_async(&addr).await?;
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tiny_continuation_frames_rejected() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            max_message_size: Some(1024),
+            ..Default::default()
+        };
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        // Every frame is far below the limit, but the reassembled message isn't
+        let mut frames = masked_frame(0x02, &[1u8; 2]);
+        for _ in 0..1000 {
+            frames.extend(masked_frame(0x00, &[1u8; 2]));
+        }
+        client.write_all(&frames).await?;
+
+        assert!(matches!(
+            server_connection.next().await.unwrap(),
+            Err(crate::error::Error::MaxMessageSize)
+        ));
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).await?;
+        assert_eq!(close, [0x88, 2, 0x03, 0xf1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_len() {
+        assert_eq!(Message::Text("héllo".to_string()).len(), 6);
+        assert_eq!(Message::Binary(Bytes::from_static(&[1, 2, 3])).len(), 3);
+        assert_eq!(Message::Ping(vec![1]).len(), 1);
+        assert_eq!(Message::Pong(Vec::new()).len(), 0);
+        assert!(Message::Pong(Vec::new()).is_empty());
+        let close = Message::Close {
+            code: Some(CloseCode::Normal),
+            reason: "bye".to_string(),
+        };
+        assert_eq!(close.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_connect_bind_to() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            accept_async(stream).await.unwrap();
+            peer_addr
+        });
+
+        let config = ClientConfig::new().bind_to("127.0.0.1:0".parse()?);
+        let client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        let peer_addr = server.await?;
+        let local_addr = client_connection.local_addr().unwrap();
+        assert_eq!(peer_addr.ip().to_string(), "127.0.0.1");
+        assert_eq!(peer_addr, local_addr);
+
+        // An IPv6 local address can't reach an IPv4 server
+        let config = ClientConfig::new().bind_to("[::1]:0".parse()?);
+        match connect_async_with_config(&addr, Some(config)).await {
+            Err(crate::error::Error::IOError { source }) => {
+                assert_eq!(source.kind(), std::io::ErrorKind::AddrNotAvailable);
+            }
+            _ => panic!("expected IOError"),
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "no-masking-check")]
+    #[tokio::test]
+    async fn test_unmasked_client_frame_accepted() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+
+        client
+            .write_all(&[0x81, 5, b'h', b'e', b'l', b'l', b'o'])
+            .await?;
+        let message = server_connection.next().await.unwrap()?;
+        assert_eq!(message.as_text(), Some("hello"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_mask_word_at_a_time() {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        for len in [0, 1, 3, 4, 7, 8, 9, 15, 16, 17, 1000, 1027] {
+            let original: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+            let mut payload = original.clone();
+            crate::masking::apply_mask(&mut payload, mask);
+            for (i, byte) in payload.iter().enumerate() {
+                assert_eq!(*byte, original[i] ^ mask[i % 4]);
+            }
+            crate::masking::apply_mask(&mut payload, mask);
+            assert_eq!(payload, original);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_reason_after_graceful_close() -> Result<(), Box<dyn Error>> {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+        assert_eq!(server_connection.close_reason(), None);
+
+        client
+            .write_all(&masked_frame(0x88, b"\x03\xe9bye"))
+            .await?;
+        while server_connection.next().await.is_some() {}
+
+        assert_eq!(
+            server_connection.close_reason(),
+            Some(CloseFrame {
+                code: CloseCode::GoingAway,
+                reason: String::from("bye"),
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_reason_after_failure() -> Result<(), Box<dyn Error>> {
+        let (server_connection, mut client) = raw_client_connection(None).await;
+        let (mut reader, _writer) = server_connection.split();
+
+        // A frame with the RSV2 bit set is a protocol error
+        client.write_all(&masked_frame(0xa2, b"data")).await?;
+        while reader.next().await.is_some() {}
+        let close_reason = reader.close_reason().unwrap();
+        assert_eq!(close_reason.code, CloseCode::ProtocolError);
+        assert_eq!(
+            close_reason.reason,
+            crate::error::Error::RSVNotZero.to_string()
+        );
+
+        // Without any close frame, the connection is closed abnormally
+        let (mut server_connection, client) = raw_client_connection(None).await;
+        drop(client);
+        while server_connection.next().await.is_some() {}
+        assert_eq!(
+            server_connection.close_reason().unwrap().code,
+            CloseCode::Abnormal
+        );
+        Ok(())
+    }
+
+    // Writes the bytes after the handshake, closing the client afterward,
+    // and returns the error ending the stream
+    async fn stream_error(
+        request: &str,
+        config: Option<WebSocketConfig>,
+        bytes: &[u8],
+    ) -> crate::error::Error {
+        let (mut server_connection, mut client) = raw_client_connection_with(request, config).await;
+        client.write_all(bytes).await.unwrap();
+        client.shutdown().await.unwrap();
+        loop {
+            match server_connection.next().await {
+                Some(Err(error)) => return error,
+                Some(Ok(_)) => continue,
+                None => panic!("the stream finished without an error"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frames_rejected() -> Result<(), Box<dyn Error>> {
+        let request = CLIENT_HANDSHAKE_REQUEST;
+
+        for opcode in (0x3..=0x7).chain(0xB..=0xF) {
+            let frame = masked_frame(0x80 | opcode, b"data");
+            assert!(matches!(
+                stream_error(request, None, &frame).await,
+                crate::error::Error::InvalidOpcode
+            ));
+        }
+
+        // Frames truncated in their header or payload
+        let mut truncated_payload = masked_frame(0x82, b"data");
+        truncated_payload.truncate(8);
+        for frame in [
+            &[0x82][..],
+            &[0x82, 0xFE, 0x01],
+            &[0x82, 0xFF, 0x00, 0x00, 0x00],
+            &[0x82, 0x84, 0x12, 0x34],
+            &truncated_payload,
+        ] {
+            assert!(matches!(
+                stream_error(request, None, frame).await,
+                crate::error::Error::ConnectionReset
+            ));
+        }
+
+        // Absurd lengths are rejected before allocating the payload
+        let mut huge_length = vec![0x82, 0xFF];
+        huge_length.extend_from_slice(&(i64::MAX as u64).to_be_bytes());
+        huge_length.extend_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+        assert!(matches!(
+            stream_error(request, None, &huge_length).await,
+            crate::error::Error::MaxFrameSize
+        ));
+        huge_length[2] = 0x80;
+        assert!(matches!(
+            stream_error(request, None, &huge_length).await,
+            crate::error::Error::InvalidPayloadLength
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_malformed_compressed_payload_rejected() -> Result<(), Box<dyn Error>> {
+        let request = CLIENT_HANDSHAKE_REQUEST.replace(
+            "\r\n\r\n",
+            "\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+        );
+
+        // A block with the reserved block type can't be decompressed
+        let frame = masked_frame(0xC2, &[0xFF, 0xFF, 0xFF]);
+        assert!(matches!(
+            stream_error(&request, Some(compression_config(true, true)), &frame).await,
+            crate::error::Error::InvalidCompressedPayload
+        ));
+
+        // A small payload inflating over the max message size fails while decompressing it
+        let config = WebSocketConfig {
+            max_message_size: Some(1 << 20),
+            ..compression_config(true, true)
+        };
+        let compressed = Encoder::new(true, None).compress(&mut BytesMut::zeroed(16 << 20))?;
+        assert!(compressed.len() < 64 * 1024);
+        let frame = masked_frame(0xC2, &compressed);
+        assert!(matches!(
+            stream_error(&request, Some(config), &frame).await,
+            crate::error::Error::MaxMessageSize
+        ));
+        Ok(())
+    }
+
+    // Accepts a single handshake, redirecting it to the location, and returns its request
+    async fn redirect_server(listener: TcpListener, location: String) -> HttpRequest {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf_reader = BufReader::new(&mut stream);
+        let req = HttpRequest::parse_http_request(&mut buf_reader)
+            .await
+            .unwrap();
+        let response = format!(
+            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+            location
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        req
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_follows_redirect() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}/start", listener.local_addr()?);
+        let target = TcpListener::bind("127.0.0.1:0").await?;
+        let location = format!("http://{}/next?room=1", target.local_addr()?);
+        let redirect = tokio::spawn(redirect_server(listener, location));
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = target.accept().await.unwrap();
+            let (read, mut write) = split(stream);
+            let mut buf_reader = BufReader::new(read);
+            let req = HttpRequest::parse_http_request(&mut buf_reader)
+                .await
+                .unwrap();
+            let accept_key = compute_accept_key(&req.get_header_value(SEC_WEBSOCKET_KEY).unwrap());
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\n\
+                 Upgrade: websocket\r\n\
+                 Connection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key
+            );
+            write.write_all(response.as_bytes()).await.unwrap();
+            req
+        });
+
+        let config = ClientConfig::new()
+            .max_redirects(5)
+            .header("Authorization", "Bearer token")
+            .origin("http://example.com");
+        let connection = connect_async_with_config(&addr, Some(config)).await?;
+        assert!(connection.is_open());
+
+        let redirected = redirect.await?;
+        let req = server.await?;
+        // The redirected handshake uses a new key, and the headers meant for
+        // the first host aren't sent to another one
+        assert_eq!(req.uri, "/next?room=1");
+        assert_ne!(
+            req.get_header_value(SEC_WEBSOCKET_KEY),
+            redirected.get_header_value(SEC_WEBSOCKET_KEY)
+        );
+        assert_eq!(
+            req.get_header_value("Origin").as_deref(),
+            Some("http://example.com")
+        );
+        assert!(redirected.get_header_value("Authorization").is_some());
+        assert!(req.get_header_value("Authorization").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_redirect_errors() -> Result<(), Box<dyn Error>> {
+        // Redirects aren't followed by default
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let redirect = tokio::spawn(redirect_server(listener, String::from("/next")));
+        match connect_async(&addr).await {
+            Err(crate::error::Error::Redirect { location, response }) => {
+                assert_eq!(location, "/next");
+                assert!(response.starts_with("HTTP/1.1 302 Found\r\n"));
+            }
+            _ => panic!("expected Redirect"),
+        }
+        redirect.await?;
+
+        // Redirecting back to the first URL is a loop
+        let first = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", first.local_addr()?);
+        let second = TcpListener::bind("127.0.0.1:0").await?;
+        let second_addr = format!("ws://{}/", second.local_addr()?);
+        let redirects = [
+            tokio::spawn(redirect_server(first, second_addr.clone())),
+            tokio::spawn(redirect_server(second, addr.clone())),
+        ];
+        let config = ClientConfig::new().max_redirects(5);
+        assert!(matches!(
+            connect_async_with_config(&addr, Some(config)).await,
+            Err(crate::error::Error::RedirectLoop { .. })
+        ));
+        for redirect in redirects {
+            redirect.await?;
+        }
+
+        // Past the maximum number of redirects, the last location isn't followed
+        let first = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", first.local_addr()?);
+        let second = TcpListener::bind("127.0.0.1:0").await?;
+        let second_addr = format!("ws://{}/", second.local_addr()?);
+        let redirects = [
+            tokio::spawn(redirect_server(first, second_addr)),
+            tokio::spawn(redirect_server(second, String::from("/third"))),
+        ];
+        let config = ClientConfig::new().max_redirects(1);
+        match connect_async_with_config(&addr, Some(config)).await {
+            Err(crate::error::Error::TooManyRedirects { location }) => {
+                assert_eq!(location, "/third");
+            }
+            _ => panic!("expected TooManyRedirects"),
+        }
+        for redirect in redirects {
+            redirect.await?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_redirect_url() {
+        let mut config = ClientConfig::new()
+            .header("Authorization", "Bearer token")
+            .header("Cookie", "session=1");
+        assert_eq!(
+            redirect_url("ws://localhost:8080/a/b", "c?d=1", &mut config).unwrap(),
+            "ws://localhost:8080/a/c?d=1"
+        );
+        assert_eq!(
+            redirect_url("wss://localhost/a", "https://localhost/b", &mut config).unwrap(),
+            "wss://localhost/b"
+        );
+        assert_eq!(config.headers.len(), 2);
+
+        // Another host doesn't receive the credentials
+        assert_eq!(
+            redirect_url("ws://localhost/a", "http://example.com/b", &mut config).unwrap(),
+            "ws://example.com/b"
+        );
+        assert!(config.headers.is_empty());
+
+        // Downgrading from wss to ws is only allowed explicitly
+        assert!(matches!(
+            redirect_url("wss://localhost/a", "ws://localhost/b", &mut config),
+            Err(crate::error::Error::InsecureRedirect { .. })
+        ));
+        let mut config = config.allow_insecure_redirects(true);
+        assert_eq!(
+            redirect_url("wss://localhost/a", "http://localhost/b", &mut config).unwrap(),
+            "ws://localhost/b"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pong_payload_round_trip() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            forward_pongs: true,
+            ..Default::default()
+        };
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        // A timestamp embedded in the ping, is echoed back in the pong
+        let sent_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+        server_connection.ping(sent_at.to_be_bytes()).await?;
+
+        let mut ping = [0u8; 18];
+        client.read_exact(&mut ping).await?;
+        assert_eq!(ping[..2], [0x89, 16]);
+        client.write_all(&masked_frame(0x8A, &ping[2..])).await?;
+
+        let Message::Pong(payload) = server_connection.next().await.unwrap()? else {
+            panic!("expected Pong");
+        };
+        assert_eq!(u128::from_be_bytes(payload[..].try_into()?), sent_at);
+
+        // Without forward_pongs, pongs are consumed by the connection
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+        client.write_all(&masked_frame(0x8A, b"pong")).await?;
+        client.write_all(&masked_frame(0x82, b"data")).await?;
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Binary(Bytes::from_static(b"data"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_websocket_key_validation() -> Result<(), Box<dyn Error>> {
+        // Surrounding spaces and tabs are trimmed, and the accept value is computed over the key
+        for key_header in [
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+            "Sec-WebSocket-Key: \t dGhlIHNhbXBsZSBub25jZQ==  \r\n",
+        ] {
+            let request = CLIENT_HANDSHAKE_REQUEST.replacen(
+                "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+                key_header,
+                1,
+            );
+            let (mut client, server) = duplex(1 << 16);
+            let server = tokio::spawn(accept_async(server));
+            client.write_all(request.as_bytes()).await?;
+            let response = read_http_response(&mut client).await;
+            assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+            server.await??;
+        }
+
+        // Keys that aren't the base64 encoding of 16 bytes are rejected
+        for key in [
+            "SGVsbG8sIHdvcmxkIQ==",
+            "dGhlIHNhbXBsZSBub25jZQ",
+            "dGhlIHNhbXBsZSBub25jZQ==dGhl",
+            "dGhlIHNhbXBsZSBub25jZQ=!",
+            "dGhlIHNhbXBsZSBub25j ZQ==",
+            "",
+        ] {
+            let request = CLIENT_HANDSHAKE_REQUEST.replacen("dGhlIHNhbXBsZSBub25jZQ==", key, 1);
+            assert!(matches!(
+                malformed_handshake(&request).await,
+                crate::error::Error::InvalidSecWebsocketKey
+            ));
+        }
+
+        assert!(is_valid_key("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert!(!is_valid_key(" dGhlIHNhbXBsZSBub25jZQ=="));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_response_hands_off_stream() -> Result<(), Box<dyn Error>> {
+        // Headers as an HTTP framework would have parsed them, in any case
+        let headers = [
+            ("host", "127.0.0.1"),
+            ("upgrade", "websocket"),
+            ("connection", "keep-alive, Upgrade"),
+            ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ("sec-websocket-version", "13"),
+            ("sec-websocket-protocol", "chat, superchat"),
+        ];
+        let accept_config = AcceptConfig::new()
+            .select_protocol(|offered| offered.last().map(|protocol| protocol.to_string()))
+            .on_request(|request| match request.uri.as_str() {
+                "/chat" => Ok(()),
+                _ => Err("not found".to_string()),
+            });
+        let (response, upgrade) =
+            crate::handshake::upgrade_response("/chat", headers, accept_config)?;
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.contains("Sec-WebSocket-Protocol: superchat\r\n"));
+        assert_eq!(upgrade.protocol(), Some("superchat"));
+
+        // The framework writes the response, and hands off the upgraded stream
+        let (mut client, mut server) = duplex(1024);
+        server.write_all(response.as_bytes()).await?;
+        let mut connection = upgrade.into_connection(server).await?;
+        assert_eq!(connection.protocol(), Some("superchat"));
+        assert_eq!(read_http_response(&mut client).await, response);
+
+        client.write_all(&masked_frame(0x81, b"hello")).await?;
+        match connection.next().await {
+            Some(Ok(Message::Text(text))) => assert_eq!(text, "hello"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        connection
+            .send_message(Message::Text("world".into()))
+            .await?;
+        let mut frame = [0u8; 7];
+        client.read_exact(&mut frame).await?;
+        assert_eq!(&frame, b"\x81\x05world");
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_response_rejections() {
+        let headers = [
+            ("Host", "127.0.0.1"),
+            ("Upgrade", "websocket"),
+            ("Connection", "Upgrade"),
+            ("Sec-WebSocket-Version", "13"),
+        ];
+        let rejection = crate::handshake::upgrade_response("/", headers, AcceptConfig::new())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            rejection.error,
+            crate::error::Error::NoSecWebsocketKey
+        ));
+        let response = rejection.response.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let headers = [
+            ("Host", "127.0.0.1"),
+            ("Upgrade", "websocket"),
+            ("Connection", "Upgrade"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ("Sec-WebSocket-Version", "8"),
+        ];
+        let rejection = crate::handshake::upgrade_response("/", headers, AcceptConfig::new())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            rejection.error,
+            crate::error::Error::UnsupportedWebsocketVersion
+        ));
+        let response = rejection.response.unwrap();
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_client_output_with_fixed_keys() -> Result<(), Box<dyn Error>> {
+        crate::utils::set_fixed_websocket_key(Some(*b"the sample nonce"));
+        crate::masking::set_fixed_mask(Some([0x12, 0x34, 0x56, 0x78]));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request = read_http_response(&mut stream).await;
+            stream
+                .write_all(
+                    HTTP_ACCEPT_RESPONSE
+                        .replace("{}", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+                        .as_bytes(),
+                )
+                .await
+                .unwrap();
+            stream.write_all(b"\r\n").await.unwrap();
+            let mut frame = [0u8; 11];
+            stream.read_exact(&mut frame).await.unwrap();
+            (request, frame)
+        });
+
+        let mut connection = connect_async(&format!("ws://{}/chat", addr)).await?;
+        connection
+            .send_message(Message::Text("hello".into()))
+            .await?;
+        let (request, frame) = server.await?;
+
+        crate::utils::set_fixed_websocket_key(None);
+        crate::masking::set_fixed_mask(None);
+
+        assert_eq!(
+            request,
+            format!(
+                "GET /chat HTTP/1.1\r\n\
+                 Host: {}\r\n\
+                 Connection: Upgrade\r\n\
+                 Upgrade: websocket\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Version: 13\r\n\r\n",
+                addr
+            )
+        );
+        assert_eq!(
+            frame,
+            [0x81, 0x85, 0x12, 0x34, 0x56, 0x78, 0x7A, 0x51, 0x3A, 0x14, 0x7D]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_bytes_through_connection() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let mut data = vec![0u8; 8 * 1024 + 123];
+        rand::rng().fill(&mut data[..]);
+
+        // Forwarded pings must not appear in the byte stream
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let config = WebSocketConfig {
+                forward_pings: true,
+                ..Default::default()
+            };
+            let mut tunnel = accept_async_with_config(stream, Some(config))
+                .await
+                .unwrap()
+                .into_io();
+            let mut received = Vec::new();
+            tunnel.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let mut client_connection = connect_async(&addr).await?;
+        client_connection.ping(b"ping").await?;
+        let mut tunnel = client_connection.into_io();
+        for chunk in data.chunks(1000) {
+            tunnel.write_all(chunk).await?;
+        }
+        tunnel.shutdown().await?;
+
+        assert_eq!(server.await?, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_rejects_text_messages() -> Result<(), Box<dyn Error>> {
+        let (server_connection, mut client) = raw_client_connection(None).await;
+        let mut tunnel = server_connection.into_io();
+
+        client.write_all(&masked_frame(0x82, b"bytes")).await?;
+        client.write_all(&masked_frame(0x81, b"text")).await?;
+
+        let mut received = [0u8; 5];
+        tunnel.read_exact(&mut received).await?;
+        assert_eq!(&received, b"bytes");
+        let error = tunnel.read(&mut received).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    // A handshake as sent by a browser, with many more headers than the websocket ones
+    const BROWSER_HANDSHAKE_REQUEST: &str = "GET /chat HTTP/1.1\r\n\
+        Host: 127.0.0.1:8080\r\n\
+        Connection: Upgrade\r\n\
+        Pragma: no-cache\r\n\
+        Cache-Control: no-cache\r\n\
+        User-Agent: Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/129.0.0.0 Safari/537.36\r\n\
+        Upgrade: websocket\r\n\
+        Origin: http://127.0.0.1:8080\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Accept-Encoding: gzip, deflate, br, zstd\r\n\
+        Accept-Language: en-US,en;q=0.9,pt;q=0.8\r\n\
+        Cookie: session=abc123; theme=dark; consent=yes\r\n\
+        Cookie: tracking=none\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\
+        Sec-Fetch-Dest: websocket\r\n\
+        Sec-Fetch-Mode: websocket\r\n\
+        Sec-Fetch-Site: same-origin\r\n\
+        Sec-Ch-Ua: \"Chromium\";v=\"129\", \"Not=A?Brand\";v=\"8\"\r\n\
+        Sec-Ch-Ua-Mobile: ?0\r\n\
+        Sec-Ch-Ua-Platform: \"Linux\"\r\n\
+        Sec-GPC: 1\r\n\
+        DNT: 1\r\n\
+        X-Forwarded-For: 10.0.0.1\r\n\
+        X-Forwarded-Proto: http\r\n\
+        X-Request-Id: 5f2b7c1e-8d3a-4b6f-9e2d-1a7c4e9b0f3d\r\n\
+        Priority: u=0, i\r\n\r\n";
+
+    #[tokio::test]
+    async fn test_accept_async_browser_handshake() -> Result<(), Box<dyn Error>> {
+        let mut reader = BufReader::new(BROWSER_HANDSHAKE_REQUEST.as_bytes());
+        let req = HttpRequest::parse_http_request(&mut reader).await?;
+        // Both cookie headers are combined into a single one
+        assert_eq!(req.headers.len(), 25);
+        assert_eq!(
+            req.get_header_value("cookie").as_deref(),
+            Some("session=abc123; theme=dark; consent=yes, tracking=none")
+        );
+        assert_eq!(req.get_header_value("priority").as_deref(), Some("u=0, i"));
+
+        let (mut server_connection, mut client) =
+            raw_client_connection_with(BROWSER_HANDSHAKE_REQUEST, None).await;
+        client.write_all(&masked_frame(0x81, b"hello")).await?;
+        assert_eq!(
+            server_connection.next().await.unwrap()?.as_text(),
+            Some("hello")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_accept_async_too_many_headers() -> Result<(), Box<dyn Error>> {
+        let mut request = CLIENT_HANDSHAKE_REQUEST.trim_end().to_string();
+        // Up to 128 headers are accepted, the handshake request already has 5
+        for index in 0..123 {
+            request.push_str(&format!("\r\nX-Header-{}: value", index));
+        }
+        request.push_str("\r\n\r\n");
+        let mut reader = BufReader::new(request.as_bytes());
+        let req = HttpRequest::parse_http_request(&mut reader).await?;
+        assert_eq!(req.headers.len(), 128);
+
+        let request = request.replacen("\r\n\r\n", "\r\nX-Header: one too many\r\n\r\n", 1);
+        assert!(matches!(
+            malformed_handshake(&request).await,
+            crate::error::Error::TooManyHeaders
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_fails_after_write_timeout() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            write_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        // The client never reads, so the message can't fit into the buffer of the stream
+        let (mut server_connection, _client) = raw_client_connection(Some(config)).await;
+
+        let sent = server_connection
+            .send_message(Message::Binary(vec![0u8; 4 << 20].into()))
+            .await;
+        assert!(matches!(sent, Err(crate::error::Error::WriteTimeout)));
+        assert!(server_connection.is_closed());
+
+        // The writer isn't held by the stuck write, so the connection can still be used
+        let sent = tokio::time::timeout(
+            Duration::from_secs(1),
+            server_connection.send_as_text("hello".to_string()),
+        )
+        .await?;
+        assert!(matches!(sent, Err(crate::error::Error::AlreadyClosed)));
+        assert_eq!(
+            server_connection.close_reason().map(|reason| reason.code),
+            Some(CloseCode::Abnormal)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compression_both_keep_context() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let messages: Vec<String> = (0..10)
+            .map(|i| format!("{{\"event\":\"price\",\"symbol\":\"ABC\",\"value\":{}}}", i))
+            .map(|message| message.repeat(10))
+            .collect();
+
+        // Neither side resets its context, so each one inflates the messages of the other
+        // with the history of all the previous ones, and replies them back
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection =
+                accept_async_with_config(stream, Some(compression_config(false, false)))
+                    .await
+                    .unwrap();
+            while let Some(Ok(message)) = server_connection.next().await {
+                if let Message::Text(text) = message {
+                    server_connection.send_as_text(text).await.unwrap();
+                }
+            }
+        });
+
+        let config = ClientConfig::new().web_socket_config(compression_config(false, false));
+        let mut client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        let mut sizes = Vec::new();
+        for message in &messages {
+            let bytes_sent = client_connection.stats().bytes_sent;
+            client_connection.send_as_text(message.clone()).await?;
+            sizes.push(client_connection.stats().bytes_sent - bytes_sent);
+            assert_eq!(
+                client_connection.next().await.unwrap()?.as_text().unwrap(),
+                *message
+            );
+        }
+
+        // Following messages only reference the history, instead of repeating it
+        assert!(sizes[1..].iter().all(|size| *size < sizes[0] / 2));
+        client_connection.close_connection().await?;
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_ping_and_wait_pong() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+
+        // Pings are answered by the server read task, while it echoes the data messages
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            server_connection
+                .send_as_text("hello".to_string())
+                .await
+                .unwrap();
+            while let Some(Ok(message)) = server_connection.next().await {
+                if server_connection.send_message(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut client_connection = connect_async(&addr).await?;
+        let rtt = client_connection
+            .send_ping_and_wait_pong(Duration::from_secs(1))
+            .await?;
+        assert!(rtt > Duration::ZERO && rtt < Duration::from_secs(1));
+        // The message received before the pong is still delivered
+        assert_eq!(
+            client_connection.next().await.unwrap()?.as_text(),
+            Some("hello")
+        );
+
+        client_connection
+            .send_as_text("after ping".to_string())
+            .await?;
+        assert_eq!(
+            client_connection.next().await.unwrap()?.as_text(),
+            Some("after ping")
+        );
+        client_connection.close_connection().await?;
+        server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_ping_and_wait_pong_timeout() -> Result<(), Box<dyn Error>> {
+        // The raw client never replies the ping
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+        let waited = server_connection
+            .send_ping_and_wait_pong(Duration::from_millis(100))
+            .await;
+        assert!(matches!(waited, Err(crate::error::Error::PongTimeout)));
+
+        // A pong arriving after the timeout doesn't complete anything
+        let mut ping = [0u8; 10];
+        client.read_exact(&mut ping).await?;
+        assert_eq!(&ping[..2], &[0x89, 8]);
+        client.write_all(&masked_frame(0x8A, &ping[2..])).await?;
+        assert!(server_connection.is_open());
+        Ok(())
+    }
+
+    // Sends the frames of a case from the fragmentation section of the Autobahn test suite,
+    // followed by a close frame, returning the messages delivered until the stream finishes
+    async fn fragmentation_case(
+        frames: &[Vec<u8>],
+    ) -> (Vec<Result<Message, crate::error::Error>>, DuplexStream) {
+        let (mut server_connection, mut client) = raw_client_connection(None).await;
+        client.write_all(&frames.concat()).await.unwrap();
+        client
+            .write_all(&masked_frame(0x88, &[0x03, 0xE8]))
+            .await
+            .unwrap();
+
+        let mut messages = Vec::new();
+        while let Some(message) = server_connection.next().await {
+            messages.push(message);
+        }
+        (messages, client)
+    }
+
+    #[tokio::test]
+    async fn test_autobahn_fragmentation_cases() -> Result<(), Box<dyn Error>> {
+        // 5.6 and 5.7: control frames between fragments are processed right away
+        let (messages, mut client) = fragmentation_case(&[
+            masked_frame(0x01, b"fragment1"),
+            masked_frame(0x89, b"ping"),
+            masked_frame(0x00, b"fragment2"),
+            masked_frame(0x8A, b"pong"),
+            masked_frame(0x80, b"fragment3"),
+        ])
+        .await;
+        let mut pong = [0u8; 6];
+        client.read_exact(&mut pong).await?;
+        assert_eq!(&pong, b"\x8A\x04ping");
+        assert!(matches!(
+            &messages[..],
+            [Ok(Message::Text(text)), Ok(Message::Close { .. })] if text == "fragment1fragment2fragment3"
+        ));
+
+        // 6.1.3: zero-length fragments are allowed, and FIN on a continuation ends the message
+        let (messages, _) = fragmentation_case(&[
+            masked_frame(0x01, b""),
+            masked_frame(0x00, b""),
+            masked_frame(0x00, b"abc"),
+            masked_frame(0x80, b""),
+            masked_frame(0x81, b"next"),
+        ])
+        .await;
+        assert!(matches!(
+            &messages[..],
+            [Ok(Message::Text(first)), Ok(Message::Text(second)), Ok(Message::Close { .. })]
+                if first == "abc" && second == "next"
+        ));
+
+        // 5.9 and 5.15: continuations without a message in progress fail the connection
+        let (messages, _) = fragmentation_case(&[masked_frame(0x80, b"fragment")]).await;
+        assert!(matches!(
+            &messages[..],
+            [Err(crate::error::Error::InvalidContinuationFrame)]
+        ));
+        let (messages, _) = fragmentation_case(&[
+            masked_frame(0x01, b"fragment1"),
+            masked_frame(0x80, b"fragment2"),
+            masked_frame(0x00, b"fragment3"),
+            masked_frame(0x80, b"fragment4"),
+        ])
+        .await;
+        assert!(matches!(
+            &messages[..],
+            [Ok(Message::Text(text)), Err(crate::error::Error::InvalidContinuationFrame)]
+                if text == "fragment1fragment2"
+        ));
+
+        // 5.18: a new message can't start before the one in progress ends
+        let (messages, _) = fragmentation_case(&[
+            masked_frame(0x01, b"fragment1"),
+            masked_frame(0x01, b"fragment2"),
+        ])
+        .await;
+        assert!(matches!(
+            &messages[..],
+            [Err(crate::error::Error::FragmentedInProgress)]
+        ));
+
+        // 5.1 and 5.2: control frames can't be fragmented
+        let (messages, _) = fragmentation_case(&[
+            masked_frame(0x0A, b"fragment1"),
+            masked_frame(0x80, b"fragment2"),
+        ])
+        .await;
+        assert!(matches!(
+            &messages[..],
+            [Err(crate::error::Error::ControlFramesFragmented)]
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_async_host_header_override() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}/chat", listener.local_addr()?);
+
+        // The handshake is only accepted for the overridden host
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let accept_config = AcceptConfig::new().on_request(|request| {
+                match request.get_header_value("Host").as_deref() {
+                    Some("api.example.com:8443") => Ok(()),
+                    host => Err(format!("unexpected host: {:?}", host)),
+                }
+            });
+            accept_async_with(stream, accept_config).await.unwrap()
+        });
+
+        let config = ClientConfig::new().host_header_override("api.example.com:8443");
+        let client_connection = connect_async_with_config(&addr, Some(config)).await?;
+        let server_connection = server.await?;
+        assert_eq!(
+            client_connection.peer_addr(),
+            server_connection.local_addr()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_header_override() {
+        let config = ClientConfig::new().host_header_override("api.example.com:8443");
+        let request =
+            construct_http_request("wss://10.0.0.1:9000/", "dGhlIHNhbXBsZSBub25jZQ==", &config)
+                .unwrap();
+        assert!(request.raw.contains("\r\nHost: api.example.com:8443\r\n"));
+        assert_eq!(request.host_with_port, "10.0.0.1:9000");
+        assert_eq!(request.host, "api.example.com");
+
+        // An explicit server name, which takes precedence for SNI, doesn't change the header
+        let config = config.server_name("sni.example.com");
+        let request =
+            construct_http_request("wss://10.0.0.1:9000/", "dGhlIHNhbXBsZSBub25jZQ==", &config)
+                .unwrap();
+        assert!(request.raw.contains("\r\nHost: api.example.com:8443\r\n"));
+
+        for host in [
+            "api.example.com\r\nX-Injected: 1",
+            "api.example.com/path",
+            "user@api.example.com",
+            "",
+        ] {
+            let config = ClientConfig::new().host_header_override(host);
+            let result =
+                construct_http_request("ws://10.0.0.1:9000/", "dGhlIHNhbXBsZSBub25jZQ==", &config);
+            assert!(matches!(result, Err(crate::error::Error::InvalidHeader)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_try_send_busy_writer() -> Result<(), Box<dyn Error>> {
+        let (server_connection, mut client) = raw_client_connection(None).await;
+        let (_reader, writer) = server_connection.split();
+
+        // The client doesn't read yet, so the large message holds the writer
+        let large = vec![7u8; 4 << 20];
+        let busy_writer = writer.clone();
+        let large_message = Message::Binary(large.clone().into());
+        let large_send = tokio::spawn(async move { busy_writer.send_message(large_message).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        match writer.try_send(Message::Text("hello".to_string())) {
+            Err(crate::split::TrySendError::Busy(Message::Text(text))) => assert_eq!(text, "hello"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut frame = vec![0u8; 10 + large.len()];
+        client.read_exact(&mut frame).await?;
+        assert_eq!(&frame[..2], &[0x82, 127]);
+        assert_eq!(&frame[10..], &large[..]);
+        large_send.await??;
+
+        // Once the writer is free, the message is written without waiting for it
+        writer.try_send(Message::Text("hello".to_string()))?;
+        let mut frame = [0u8; 7];
+        client.read_exact(&mut frame).await?;
+        assert_eq!(&frame, b"\x81\x05hello");
+
+        assert!(matches!(
+            writer.try_send(Message::Ping(vec![0u8; 126])),
+            Err(crate::split::TrySendError::Invalid(
+                crate::error::Error::ControlFramePayloadSize
+            ))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_send_outside_runtime() -> Result<(), Box<dyn Error>> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        let (server_connection, mut client) = runtime.block_on(raw_client_connection(None));
+        let (_reader, writer) = server_connection.split();
+
+        // Written right away, since the socket takes all of it
+        writer.try_send(Message::Text("hello".to_string()))?;
+        let mut frame = [0u8; 7];
+        runtime.block_on(client.read_exact(&mut frame))?;
+        assert_eq!(&frame, b"\x81\x05hello");
+
+        // The socket can't take the whole message, and nothing can finish writing it
+        let large = Message::Binary(vec![7u8; 2 << 20].into());
+        assert!(matches!(
+            writer.try_send(large),
+            Err(crate::split::TrySendError::Failed(
+                crate::error::Error::IOError { .. }
+            ))
+        ));
+        let result = runtime.block_on(writer.send_as_text("hello".to_string()));
+        assert!(matches!(result, Err(crate::error::Error::IOError { .. })));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_try_send_failure_reported_by_next_send() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            write_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let (server_connection, _client) = raw_client_connection(Some(config)).await;
+        let (_reader, writer) = server_connection.split();
+
+        // The client doesn't read, so the rest of the message written in the background times out
+        writer.try_send(Message::Binary(vec![7u8; 2 << 20].into()))?;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let result = writer.send_as_text("hello".to_string()).await;
+        assert!(matches!(result, Err(crate::error::Error::WriteTimeout)));
+        assert!(matches!(
+            writer.try_send(Message::Text("hello".to_string())),
+            Err(crate::split::TrySendError::Closed(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_strict_mode() -> Result<(), Box<dyn Error>> {
+        // A text frame whose length is encoded in 2 bytes, although it's below 126,
+        // followed by a close frame with status code 1005, which isn't allowed on the wire
+        let mut frames = vec![0x81, 126, 0, 5];
+        frames.extend_from_slice(b"hello");
+        frames.extend_from_slice(&[0x88, 2, 0x03, 0xED]);
+
+        for strict in [None, Some(true)] {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = format!("ws://{}", listener.local_addr()?);
+            let frames = frames.clone();
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (read, mut write) = split(stream);
+                let mut buf_reader = BufReader::new(read);
+                let req = HttpRequest::parse_http_request(&mut buf_reader)
+                    .await
+                    .unwrap();
+                let accept_key =
+                    compute_accept_key(&req.get_header_value(SEC_WEBSOCKET_KEY).unwrap());
+
+                let mut data = HTTP_ACCEPT_RESPONSE.replace("{}", &accept_key).into_bytes();
+                data.extend_from_slice(b"\r\n");
+                data.extend_from_slice(&frames);
+                write.write_all(&data).await.unwrap();
+                // Either the close reply, or the close frame failing the connection
+                buf_reader.read_u8().await.unwrap()
+            });
+
+            let mut config = ClientConfig::default();
+            if let Some(strict) = strict {
+                config = config.strict(strict);
+            }
+            let mut client_connection = connect_async_with_config(&addr, Some(config)).await?;
+            if strict.is_none() {
+                assert_eq!(
+                    client_connection.next().await.unwrap()?,
+                    Message::Text("hello".to_string())
+                );
+                assert_eq!(
+                    client_connection.next().await.unwrap()?,
+                    Message::Close {
+                        code: Some(CloseCode::NoStatus),
+                        reason: String::new(),
+                    }
+                );
+            } else {
+                assert!(matches!(
+                    client_connection.next().await.unwrap(),
+                    Err(crate::error::Error::InvalidPayloadLength)
+                ));
+            }
+            assert_eq!(server.await?, 0x88);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_lenient_mode() -> Result<(), Box<dyn Error>> {
+        let config = WebSocketConfig {
+            strict: Some(false),
+            ..Default::default()
+        };
+        let (mut server_connection, mut client) = raw_client_connection(Some(config)).await;
+
+        // A binary frame whose length is encoded in 2 bytes, although it's below 126
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x82, 0b1000_0000 | 126, 0, 5];
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            b"hello"
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        client.write_all(&frame).await?;
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Binary(Bytes::from_static(b"hello"))
+        );
+
+        // A close frame with status code 1005 is accepted, and replied with 1000
+        client.write_all(&masked_frame(0x88, &[0x03, 0xED])).await?;
+        assert_eq!(
+            server_connection.next().await.unwrap()?,
+            Message::Close {
+                code: Some(CloseCode::NoStatus),
+                reason: String::new(),
+            }
+        );
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await?;
+        assert_eq!(reply, [0x88, 2, 0x03, 0xE8]);
+        Ok(())
+    }
+
+    // A stream counting the writes into it, which supports vectored writes, like TCP
+    struct CountingStream {
+        inner: DuplexStream,
+        writes: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl AsyncRead for CountingStream {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl tokio::io::AsyncWrite for CountingStream {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.writes
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            std::pin::Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_write_vectored(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            bufs: &[std::io::IoSlice<'_>],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.writes
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let buf: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+            std::pin::Pin::new(&mut self.inner).poll_write(cx, &buf)
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frame_written_with_a_single_write() -> Result<(), Box<dyn Error>> {
+        let (mut client, server) = duplex(1 << 20);
+        let writes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = tokio::spawn(accept_async(CountingStream {
+            inner: server,
+            writes: writes.clone(),
+        }));
+        client
+            .write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes())
+            .await?;
+        read_http_response(&mut client).await;
+        let mut server_connection = server.await??;
+
+        // The header is written together with the payload, whatever the length encoding is
+        for size in [5, 200, 70_000] {
+            let before = writes.load(std::sync::atomic::Ordering::SeqCst);
+            server_connection.send_as_binary(vec![0u8; size]).await?;
+            assert_eq!(writes.load(std::sync::atomic::Ordering::SeqCst) - before, 1);
+
+            let header_len = match size {
+                0..=125 => 2,
+                126..=65535 => 4,
+                _ => 10,
+            };
+            let mut frame = vec![0u8; header_len + size];
+            client.read_exact(&mut frame).await?;
+            assert_eq!(frame[0], 0x82);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_error_from_every_send_path() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut client = TcpStream::connect(listener.local_addr()?).await?;
+        let (stream, _) = listener.accept().await?;
+        let server = tokio::spawn(accept_async(stream));
+        client
+            .write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes())
+            .await?;
+        read_http_response(&mut client).await;
+        let (mut reader, mut writer) = server.await??.split();
+        let sender = writer.clone();
+
+        // The senders are already waiting for the writer, held by a fragmented message,
+        // when the read side detects the reset
+        let mut fragmented = writer.fragmented_binary();
+        fragmented.send(b"ab".to_vec()).await?;
+        let (batch, ping, uncompressed) = (sender.clone(), sender.clone(), sender);
+        let sends = [
+            tokio::spawn(async move { batch.send_all([Message::text("hello")]).await }),
+            tokio::spawn(async move { ping.send_ping().await }),
+            tokio::spawn(
+                async move { uncompressed.send_uncompressed(Message::text("hello")).await },
+            ),
+        ];
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        #[allow(deprecated)]
+        client.set_linger(Some(Duration::ZERO))?;
+        drop(client);
+        assert!(matches!(
+            reader.next().await.unwrap(),
+            Err(crate::error::Error::ConnectionReset)
+        ));
+        drop(fragmented);
+
+        for send in sends {
+            match send.await? {
+                Err(crate::error::Error::SendAllFailed { source, .. }) => {
+                    assert!(matches!(*source, crate::error::Error::ConnectionReset));
+                }
+                result => assert!(matches!(result, Err(crate::error::Error::ConnectionReset))),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_all_written_at_once() -> Result<(), Box<dyn Error>> {
+        let (mut client, server) = duplex(1 << 20);
+        let writes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = tokio::spawn(accept_async(CountingStream {
+            inner: server,
+            writes: writes.clone(),
+        }));
+        client
+            .write_all(CLIENT_HANDSHAKE_REQUEST.as_bytes())
+            .await?;
+        read_http_response(&mut client).await;
+        let mut server_connection = server.await??;
+
+        let before = writes.load(std::sync::atomic::Ordering::SeqCst);
+        let messages = (0..3).map(|i| Message::text(format!("message {}", i)));
+        server_connection.send_all(messages).await?;
+        assert_eq!(writes.load(std::sync::atomic::Ordering::SeqCst) - before, 1);
+
+        // Clients mask each frame of the batch with its own key
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            let mut messages = Vec::new();
+            for _ in 0..3 {
+                messages.push(server_connection.next().await.unwrap().unwrap());
+            }
+            messages
+        });
+        let mut client_connection = connect_async(&addr).await?;
+        client_connection
+            .send_all([
+                Message::text("hello"),
+                Message::binary(vec![7u8; 300]),
+                Message::text("bye"),
+            ])
+            .await?;
+        assert_eq!(
+            server.await?,
+            [
+                Message::text("hello"),
+                Message::binary(vec![7u8; 300]),
+                Message::text("bye"),
+            ]
+        );
+        Ok(())
+    }
+
+    // Records the names of the spans created, and the messages of the events, in order
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct RecordingSubscriber {
+        records: Arc<std::sync::Mutex<Vec<String>>>,
+        next_id: Arc<std::sync::atomic::AtomicU64>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for RecordingSubscriber {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            self.records
+                .lock()
+                .unwrap()
+                .push(format!("span {}", span.metadata().name()));
+            let id = self
+                .next_id
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tracing::span::Id::from_u64(id + 1)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            struct MessageVisitor(Option<String>);
+            impl tracing::field::Visit for MessageVisitor {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    if field.name() == "message" {
+                        self.0 = Some(format!("{:?}", value));
+                    }
+                }
+            }
+            let mut visitor = MessageVisitor(None);
+            event.record(&mut visitor);
+            if let Some(message) = visitor.0 {
+                self.records.lock().unwrap().push(message);
+            }
+        }
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_tracing_events() -> Result<(), Box<dyn Error>> {
+        // The runtime of the test is single-threaded, so the connection tasks use the
+        // subscriber set for this thread
+        let subscriber = RecordingSubscriber::default();
+        let _guard = tracing::subscriber::set_default(subscriber.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("ws://{}", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server_connection = accept_async(stream).await.unwrap();
+            server_connection.next().await.unwrap().unwrap()
+        });
+
+        let mut client_connection = connect_async(&addr).await?;
+        client_connection.send_as_text("hello".to_string()).await?;
+        assert_eq!(server.await?, Message::text("hello"));
+
+        let records = subscriber.records.lock().unwrap().clone();
+        for expected in [
+            "span connect_async",
+            "span accept_async",
+            "handshake completed",
+            "frame received",
+        ] {
+            assert!(
+                records.iter().any(|record| record == expected),
+                "{} not in {:?}",
+                expected,
+                records
+            );
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,141 @@
+use crate::config::{ClientConfig, ClientIdentity};
+use crate::error::Error;
+use pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+// Builds the rustls configuration used by clients for `wss://` connections
+pub(crate) fn client_tls_config(
+    client_config: &ClientConfig,
+) -> Result<rustls::ClientConfig, Error> {
+    // Creating a cert store, to inject the TLS certificates
+    let mut root_cert_store = RootCertStore::empty();
+
+    // In the case you are using self-signed certificates, or a private CA, on the server
+    // you are trying to connect, you must indicate the CA certificate of this server
+    // when connecting to it, either as a file, or as already loaded certificates
+    if let Some(ref file) = client_config.ca_file {
+        let mut pem = BufReader::new(File::open(file)?);
+        for cert in rustls_pemfile::certs(&mut pem) {
+            root_cert_store.add(cert?)?;
+        }
+    }
+    for cert in &client_config.tls_root_certs {
+        root_cert_store.add(cert.clone())?;
+    }
+    if root_cert_store.is_empty() {
+        // Here we are adding TLS_SERVER_ROOTS to the certificate store,
+        // which is basically a reference to a list of trusted root certificates
+        // issue by a CA.
+        // In the case, you are establishing a connection with a server
+        // that has a valid trusted certificate.
+        // You won't need a CA file
+        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+
+    let builder = rustls::ClientConfig::builder().with_root_certificates(root_cert_store);
+    let mut config = match client_config.client_identity {
+        Some(ref identity) => {
+            let (cert_chain, private_key) = parse_client_identity(identity)?;
+            builder.with_client_auth_cert(cert_chain, private_key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    if client_config.danger_accept_invalid_certs {
+        let provider = config.crypto_provider().clone();
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)));
+    }
+    Ok(config)
+}
+
+// Parses the client certificate chain and private key, which can be PEM or DER encoded
+fn parse_client_identity(
+    identity: &ClientIdentity,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), Error> {
+    let cert_chain = if is_pem(&identity.cert_chain) {
+        rustls_pemfile::certs(&mut identity.cert_chain.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid_identity("invalid PEM client certificate chain"))?
+    } else {
+        vec![CertificateDer::from(identity.cert_chain.clone())]
+    };
+    if cert_chain.is_empty() {
+        return Err(invalid_identity("empty client certificate chain"));
+    }
+
+    let private_key = if is_pem(&identity.private_key) {
+        rustls_pemfile::private_key(&mut identity.private_key.as_slice())
+            .ok()
+            .flatten()
+            .ok_or_else(|| invalid_identity("invalid PEM client private key"))?
+    } else {
+        PrivateKeyDer::try_from(identity.private_key.clone())
+            .map_err(|_| invalid_identity("invalid DER client private key"))?
+    };
+    Ok((cert_chain, private_key))
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    data.trim_ascii_start().starts_with(b"-----BEGIN")
+}
+
+fn invalid_identity(reason: &str) -> Error {
+    Error::Tls(rustls::Error::General(reason.to_string()))
+}
+
+// Accepts any server certificate, without verifying its chain or name, although the handshake
+// signatures are still verified, so the server must own the key of the certificate it presents
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
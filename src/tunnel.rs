@@ -0,0 +1,113 @@
+//! Tunneling byte streams over a websocket connection.
+//!
+//! A `WSTunnel`, created by `WSConnection::into_io`, is an `AsyncRead` and `AsyncWrite`,
+//! so it can be used wherever a socket is, like for proxying SSH or a database connection
+//! over websockets, with `tokio::io::copy_bidirectional`.
+//! The bytes written are sent as binary messages, and the bytes read are the payloads of the
+//! received binary messages, concatenated. Pings and pongs are answered as usual, and never appear
+//! in the byte stream, and the stream finishes once the connection is closed.
+//! Receiving a text message fails the read with `io::ErrorKind::InvalidData`, since a tunnel
+//! only carries binary data.
+use crate::error::Error;
+use crate::message::Message;
+use crate::split::{WSReader, WSWriter};
+use bytes::Bytes;
+use futures::{ready, Sink, Stream};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A websocket connection read and written as a byte stream, check the module documentation
+pub struct WSTunnel {
+    reader: WSReader,
+    writer: WSWriter,
+    // The part of the last received payload that wasn't read yet
+    pending: Bytes,
+}
+
+impl WSTunnel {
+    pub(crate) fn new(reader: WSReader, writer: WSWriter) -> Self {
+        Self {
+            reader,
+            writer,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+fn io_error(error: Error) -> io::Error {
+    match error {
+        Error::IOError { source } => source,
+        error => io::Error::other(error),
+    }
+}
+
+impl AsyncRead for WSTunnel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.pending.is_empty() {
+            match ready!(Pin::new(&mut this.reader).poll_next(cx)) {
+                Some(Ok(Message::Binary(payload))) => this.pending = payload,
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Text message received in a binary tunnel",
+                    )))
+                }
+                // Forwarded pings and pongs, or the close message before the stream finishes
+                Some(Ok(_)) => {}
+                Some(Err(error)) => return Poll::Ready(Err(io_error(error))),
+                // Reading nothing signals the end of the stream
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = this.pending.len().min(buf.remaining());
+        buf.put_slice(&this.pending.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Each write is sent as a binary message, once the previous one was written into the socket
+impl AsyncWrite for WSTunnel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.writer).poll_ready(cx)).map_err(io_error)?;
+        // Larger writes are partially accepted, instead of being rejected by the message limit
+        let len = buf.len().min(
+            this.writer
+                .web_socket_config
+                .max_message_size
+                .unwrap_or(usize::MAX),
+        );
+        Pin::new(&mut this.writer)
+            .start_send(Message::Binary(Bytes::copy_from_slice(&buf[..len])))
+            .map_err(io_error)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer)
+            .poll_flush(cx)
+            .map_err(io_error)
+    }
+
+    // Performs the close handshake, after the pending write
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer)
+            .poll_close(cx)
+            .map_err(io_error)
+    }
+}
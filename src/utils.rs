@@ -1,18 +1,44 @@
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
+use percent_encoding::percent_decode_str;
 use rand::random;
-use sha1::{Digest, Sha1};
+#[cfg(test)]
+use std::cell::Cell;
+use url::Url;
 
-pub(crate) const UUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-
-pub(crate) fn generate_websocket_accept_value(key: String) -> String {
-    let mut sha1 = Sha1::new();
-    sha1.update(key.as_bytes());
-    sha1.update(UUID.as_bytes());
-    BASE64_STANDARD.encode(sha1.finalize())
+#[cfg(test)]
+thread_local! {
+    // The handshake key nonce used by the clients of the current thread, when set by a test
+    static FIXED_KEY: Cell<Option<[u8; 16]>> = const { Cell::new(None) };
 }
 
 pub(crate) fn generate_websocket_key() -> String {
+    #[cfg(test)]
+    if let Some(nonce) = FIXED_KEY.get() {
+        return BASE64_STANDARD.encode(nonce);
+    }
     let random_bytes: [u8; 16] = random();
     BASE64_STANDARD.encode(random_bytes)
 }
+
+// Makes the clients of the current thread send the handshake key of the given nonce, or of
+// random ones again, for asserting the exact bytes of the handshake. Only the tests can fix it
+#[cfg(test)]
+pub(crate) fn set_fixed_websocket_key(nonce: Option<[u8; 16]>) {
+    FIXED_KEY.set(nonce);
+}
+
+// Builds the value of a Basic authorization header, from the credentials of the URL, if any.
+// The credentials are percent-decoded, since the URL keeps them encoded
+pub(crate) fn basic_auth_value(url: &Url) -> Option<String> {
+    if url.username().is_empty() && url.password().is_none() {
+        return None;
+    }
+
+    let username = percent_decode_str(url.username()).decode_utf8_lossy();
+    let password = percent_decode_str(url.password().unwrap_or_default()).decode_utf8_lossy();
+    Some(format!(
+        "Basic {}",
+        BASE64_STANDARD.encode(format!("{}:{}", username, password))
+    ))
+}
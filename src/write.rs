@@ -1,132 +1,263 @@
+use crate::encoder::Encoder;
 use crate::error::Error;
-use crate::frame::Frame;
-use crate::stream::SocketFlowStream;
-use rand::rngs::StdRng;
-use rand::{Rng, SeedableRng};
-use tokio::io::{AsyncWriteExt, WriteHalf};
+use crate::frame::{Frame, OpCode};
+use crate::masking::{apply_mask, generate_mask};
+use crate::message::{CloseCode, CloseFrame};
+use crate::state::ConnectionState;
+use crate::stream::BoxedWriter;
+use futures::task::noop_waker_ref;
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum WriterKind {
     Client,
     Server,
 }
 
 pub struct Writer {
-    write_half: WriteHalf<SocketFlowStream>,
+    write_half: BoxedWriter,
     kind: WriterKind,
+    // Per WebSockets RFC, after sending a close frame, no more frames should be sent.
+    // It's tracked in the state shared with the connection, so it can be checked without the lock
+    state: Arc<ConnectionState>,
+    // Compresses the outgoing messages, it's kept together with the socket, since messages
+    // must be written in the same order they were compressed
+    pub(crate) encoder: Encoder,
+    write_timeout: Option<Duration>,
 }
 
 impl Writer {
-    pub fn new(write_half: WriteHalf<SocketFlowStream>, kind: WriterKind) -> Self {
-        Self { write_half, kind }
+    pub fn new(
+        write_half: BoxedWriter,
+        kind: WriterKind,
+        encoder: Encoder,
+        state: Arc<ConnectionState>,
+        write_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            write_half,
+            kind,
+            state,
+            encoder,
+            write_timeout,
+        }
     }
 
-    pub async fn write_frame(&mut self, frame: Frame, set_rsv1: bool) -> Result<(), Error> {
-        match self.kind {
-            WriterKind::Client => self.write_frame_client(frame, set_rsv1).await,
-            WriterKind::Server => self.write_frame_server(frame, set_rsv1).await,
-        }
+    // Returns true if a close frame was already written into the socket
+    pub fn close_sent(&self) -> bool {
+        self.state.close_sent()
     }
 
-    pub async fn write_frame_server(&mut self, frame: Frame, set_rsv1: bool) -> Result<(), Error> {
-        // The first byte of a websockets frame contains the final fragment bit, and the OpCode
-        // in (frame.final_fragment as u8) << 7 we are doing a left bitwise shift, if final_fragment is true
-        // it will be converted from 10000000 to 1
-        // after that it will perform a bitwise OR operation with OpCode, so if Opcode is text(0x1)
-        // the final result will be 10000001, which is 129 decimal
-        let mut first_byte = (frame.final_fragment as u8) << 7 | frame.opcode.as_u8();
+    // Keeps why the connection was closed, for the tasks only holding the writer, like the keepalive
+    pub(crate) fn set_close_reason(&self, close_frame: CloseFrame) {
+        self.state.set_close_reason(close_frame);
+    }
 
-        // Set the RSV1 bit if compression is enabled for this frame
-        if set_rsv1 {
-            first_byte |= 0x40; // Set RSV1
-        }
+    // Shuts down the write half, closing the connection
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        let shutdown = async { Ok(self.write_half.shutdown().await?) };
+        with_write_timeout(self.write_timeout, &self.state, shutdown).await
+    }
 
-        let payload_len = frame.payload.len();
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        let flush = async { Ok(self.write_half.flush().await?) };
+        with_write_timeout(self.write_timeout, &self.state, flush).await
+    }
 
-        self.write_half.write_all(&[first_byte]).await?;
+    pub async fn write_frame(&mut self, frame: Frame, set_rsv1: bool) -> Result<(), Error> {
+        self.start_frame(&frame)?;
+        let (write_timeout, state) = (self.write_timeout, self.state.clone());
+        let write = self.write_frame_parts(frame, set_rsv1);
+        with_write_timeout(write_timeout, &state, write).await
+    }
 
-        // According to Websockets RFC, if the payload length is less or equal 125, it's written as a 8-bit unsigned integer
-        // if it's between 126 and 65535, it's represented by additional 8 bytes.
-        if payload_len <= 125 {
-            self.write_half.write_all(&[payload_len as u8]).await?;
-        } else if payload_len <= 65535 {
-            self.write_half
-                .write_all(&[126, (payload_len >> 8) as u8, payload_len as u8])
-                .await?;
-        } else {
-            let bytes = payload_len.to_be_bytes();
-            self.write_half
-                .write_all(&[
-                    127, bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6],
-                    bytes[7],
-                ])
-                .await?;
+    // Appends the frame into the buffer, instead of writing it, so many frames can be
+    // written into the socket at once, through write_encoded
+    pub(crate) fn encode_frame(
+        &self,
+        frame: Frame,
+        set_rsv1: bool,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        self.start_frame(&frame)?;
+        let mask = self.mask();
+        let (header, header_len) = frame_header(&frame, set_rsv1, mask);
+        buffer.extend_from_slice(&header[..header_len]);
+        let payload_start = buffer.len();
+        buffer.extend_from_slice(&frame.payload);
+        if let Some(mask) = mask {
+            apply_mask(&mut buffer[payload_start..], mask);
         }
+        Ok(())
+    }
 
-        self.write_half.write_all(&frame.payload).await?;
+    // Writes the frames encoded into the buffer by encode_frame
+    pub(crate) async fn write_encoded(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        let write = async { Ok(self.write_half.write_all(buffer).await?) };
+        with_write_timeout(self.write_timeout, &self.state, write).await
+    }
+
+    // Writes as much of the buffer as the socket takes without waiting, flushing it once it's
+    // entirely written. Returns how many bytes were written, and whether they were flushed,
+    // so the rest can be finished by a task, through write_encoded and flush
+    pub(crate) fn try_write_encoded(&mut self, buffer: &[u8]) -> Result<(usize, bool), Error> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut written = 0;
+        while written < buffer.len() {
+            match Pin::new(&mut self.write_half).poll_write(&mut cx, &buffer[written..]) {
+                Poll::Ready(Ok(0)) => {
+                    let source = io::Error::from(io::ErrorKind::WriteZero);
+                    return Err(self.state.write_error(Error::IOError { source }));
+                }
+                Poll::Ready(Ok(size)) => written += size,
+                Poll::Ready(Err(source)) => {
+                    return Err(self.state.write_error(Error::IOError { source }))
+                }
+                Poll::Pending => return Ok((written, false)),
+            }
+        }
+        match Pin::new(&mut self.write_half).poll_flush(&mut cx) {
+            Poll::Ready(Ok(())) => Ok((written, true)),
+            Poll::Ready(Err(source)) => Err(self.state.write_error(Error::IOError { source })),
+            Poll::Pending => Ok((written, false)),
+        }
+    }
 
+    // Per WebSockets RFC, no frame can follow a close frame
+    fn start_frame(&self, frame: &Frame) -> Result<(), Error> {
+        if self.close_sent() {
+            return Err(self.state.write_error(Error::AlreadyClosed));
+        }
+        if frame.opcode == OpCode::Close {
+            self.state.mark_close_sent();
+        }
+        self.state.stats.frame_sent(frame);
         Ok(())
     }
 
-    // Method used for writing frames into the socket by clients
-    pub async fn write_frame_client(&mut self, frame: Frame, set_rsv1: bool) -> Result<(), Error> {
-        let mut rng = StdRng::from_rng(&mut rand::rng());
-        // According to Websockets RFC, all frames sent from the client,
-        // needs to have the payload masked
-        let mask = [
-            rng.random::<u8>(),
-            rng.random::<u8>(),
-            rng.random::<u8>(),
-            rng.random::<u8>(),
-        ];
+    // According to Websockets RFC, all frames sent from the client,
+    // needs to have the payload masked, with a fresh masking key
+    fn mask(&self) -> Option<[u8; 4]> {
+        (self.kind == WriterKind::Client).then(generate_mask)
+    }
 
-        let mut first_byte = (frame.final_fragment as u8) << 7 | frame.opcode.as_u8();
+    // The header and the payload are written together, so a frame isn't split into multiple
+    // TCP segments, since Nagle's algorithm is disabled by default
+    async fn write_frame_parts(&mut self, frame: Frame, set_rsv1: bool) -> Result<(), Error> {
+        let mask = self.mask();
+        let (header, header_len) = frame_header(&frame, set_rsv1, mask);
 
-        // Set the RSV1 bit if compression is enabled for this frame
-        if set_rsv1 {
-            first_byte |= 0x40; // Set RSV1
+        match mask {
+            Some(mask) => {
+                // Masking needs its own copy of the payload, unless it isn't shared with anything else
+                let mut masked_payload = Vec::from(frame.payload);
+                apply_mask(&mut masked_payload, mask);
+                self.write_vectored_all(&header[..header_len], &masked_payload)
+                    .await?;
+            }
+            None => {
+                self.write_vectored_all(&header[..header_len], &frame.payload)
+                    .await?;
+            }
         }
-        let payload_len = frame.payload.len();
-
-        self.write_half.write_all(&[first_byte]).await?;
-
-        if payload_len <= 125 {
-            let length = 0b1000_0000 | payload_len as u8; // we set the MSB to 1 to signify that the payload is masked
-            self.write_half.write_all(&[length]).await?; // write the masked length
-            self.write_half.write_all(&mask).await?; // send the mask key
-        } else if payload_len <= 65535 {
-            self.write_half
-                .write_all(&[
-                    126 | 0b1000_0000,
-                    (payload_len >> 8) as u8,
-                    payload_len as u8,
-                ])
-                .await?;
-            self.write_half.write_all(&mask).await?;
-        } else {
-            let bytes = payload_len.to_be_bytes();
-            self.write_half
-                .write_all(&[
-                    127 | 0b1000_0000,
-                    bytes[0],
-                    bytes[1],
-                    bytes[2],
-                    bytes[3],
-                    bytes[4],
-                    bytes[5],
-                    bytes[6],
-                    bytes[7],
-                ])
+        Ok(())
+    }
+
+    // Writes the header and the payload with a single vectored write, when the stream supports it,
+    // like TCP, otherwise they are written one after the other, without copying the payload
+    async fn write_vectored_all(
+        &mut self,
+        mut header: &[u8],
+        mut payload: &[u8],
+    ) -> io::Result<()> {
+        while !header.is_empty() {
+            let written = self
+                .write_half
+                .write_vectored(&[IoSlice::new(header), IoSlice::new(payload)])
                 .await?;
-            self.write_half.write_all(&mask).await?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            if written < header.len() {
+                header = &header[written..];
+            } else {
+                payload = &payload[written - header.len()..];
+                header = &[];
+            }
         }
+        self.write_half.write_all(payload).await
+    }
+}
 
-        let mut masked_payload: Vec<u8> = Vec::with_capacity(frame.payload.len());
-        for (i, &byte) in frame.payload.iter().enumerate() {
-            masked_payload.push(byte ^ mask[i % 4]);
-        }
+// Encodes the header of a frame, returning it with its length, which is up to 14 bytes:
+// the first byte, the payload length, with up to 8 more bytes, and the masking key, if any
+fn frame_header(frame: &Frame, set_rsv1: bool, mask: Option<[u8; 4]>) -> ([u8; 14], usize) {
+    let mut header = [0u8; 14];
+    // The first byte of a websockets frame contains the final fragment bit, and the OpCode
+    // in (frame.final_fragment as u8) << 7 we are doing a left bitwise shift, if final_fragment is true
+    // it will be converted from 10000000 to 1
+    // after that it will perform a bitwise OR operation with OpCode, so if Opcode is text(0x1)
+    // the final result will be 10000001, which is 129 decimal
+    header[0] = (frame.final_fragment as u8) << 7 | frame.opcode.as_u8();
+
+    // Set the RSV1 bit if compression is enabled for this frame
+    if set_rsv1 {
+        header[0] |= 0x40; // Set RSV1
+    }
 
-        self.write_half.write_all(&masked_payload).await?;
+    // The MSB of the second byte is set to 1, to signify that the payload is masked
+    let mask_bit = if mask.is_some() { 0b1000_0000 } else { 0 };
+    let payload_len = frame.payload.len();
 
-        Ok(())
+    // According to Websockets RFC, if the payload length is less or equal 125, it's written as a 8-bit unsigned integer
+    // if it's between 126 and 65535, it's represented by additional 2 bytes, otherwise by additional 8 bytes
+    let mut len = 2;
+    if payload_len <= 125 {
+        header[1] = mask_bit | payload_len as u8;
+    } else if payload_len <= 65535 {
+        header[1] = mask_bit | 126;
+        header[2..4].copy_from_slice(&(payload_len as u16).to_be_bytes());
+        len += 2;
+    } else {
+        header[1] = mask_bit | 127;
+        header[2..10].copy_from_slice(&(payload_len as u64).to_be_bytes());
+        len += 8;
     }
+
+    if let Some(mask) = mask {
+        header[len..len + 4].copy_from_slice(&mask);
+        len += 4;
+    }
+    (header, len)
+}
+
+// Bounds a write into the socket by the write timeout, if any.
+// Once it expires, no more frames can be written, since the peer would take the rest of a frame
+// partially written as the next one, so the connection is closed. Returning releases the writer
+// lock, so the connection can still be torn down.
+// Every write goes through it, so the errors of all the senders are reported the same way
+async fn with_write_timeout(
+    write_timeout: Option<Duration>,
+    state: &ConnectionState,
+    write: impl Future<Output = Result<(), Error>>,
+) -> Result<(), Error> {
+    let result = match write_timeout {
+        Some(write_timeout) => timeout(write_timeout, write).await.unwrap_or_else(|_| {
+            state.mark_close_sent();
+            state.set_close_reason(CloseFrame {
+                code: CloseCode::Abnormal,
+                reason: Error::WriteTimeout.to_string(),
+            });
+            Err(Error::WriteTimeout)
+        }),
+        None => write.await,
+    };
+    result.map_err(|error| state.write_error(error))
 }